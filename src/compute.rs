@@ -1,6 +1,7 @@
 use im::HashMap;
 use std::sync::Arc;
 use crate::definitions::AddressUniqueId;
+use crate::worksheet::SimpleWorksheet;
 
 pub type ArcWorkbookInfo = Arc<WorkbookInfo>;
 
@@ -10,9 +11,17 @@ pub struct WorkbookInfo {
 }
 
 impl WorkbookInfo {
-    // based on the name of a sheet, get the sheet
+    // based on the name of a sheet, get the sheet. Sheet names
+    // are not case sensitive, so fall back to a case-insensitive match
     pub fn sheet_for_name(&self, name: String) -> Option<ArcSheetInfo> {
-        self.sheets.get(&name).map(|x| x.clone())
+        match self.sheets.get(&name) {
+            Some(sheet) => Some(sheet.clone()),
+            None => self
+                .sheets
+                .iter()
+                .find(|(k, _)| k.to_lowercase() == name.to_lowercase())
+                .map(|(_, v)| v.clone()),
+        }
     }
 
     pub fn set_sheet(&self, name: String, info: ArcSheetInfo) -> WorkbookInfo {
@@ -28,15 +37,42 @@ impl WorkbookInfo {
     }
 }
 
+impl Default for WorkbookInfo {
+    fn default() -> Self {
+        WorkbookInfo::new()
+    }
+}
+
 pub type ArcSheetInfo = Arc<SheetInfo>;
 
 #[derive(Debug, Clone)]
+#[allow(dead_code)] // FIXME populated once sheets have named rows and columns
 pub struct SheetInfo {
     names_to_rows: HashMap<String, AddressUniqueId>,
     names_to_cols: HashMap<String, AddressUniqueId>,
     row_order: Vec<AddressUniqueId>,
     col_order: Vec<AddressUniqueId>,
     col_id_to_info: HashMap<AddressUniqueId, (String, u32)>,
-    row_id_to_info: HashMap<AddressUniqueId, (String, u32)>
+    row_id_to_info: HashMap<AddressUniqueId, (String, u32)>,
+    worksheet: Arc<SimpleWorksheet>,
+}
+
+impl SheetInfo {
+    pub fn new(worksheet: Arc<SimpleWorksheet>) -> SheetInfo {
+        SheetInfo {
+            names_to_rows: HashMap::new(),
+            names_to_cols: HashMap::new(),
+            row_order: vec![],
+            col_order: vec![],
+            col_id_to_info: HashMap::new(),
+            row_id_to_info: HashMap::new(),
+            worksheet,
+        }
+    }
+
+    // the cells on the sheet
+    pub fn worksheet(&self) -> &Arc<SimpleWorksheet> {
+        &self.worksheet
+    }
 }
 
@@ -16,8 +16,10 @@
 // limitations under the License.
 
 use im::HashMap;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::SystemTime;
 use tokio_stream::Stream;
 
 /// A generic unique identifier
@@ -64,7 +66,7 @@ pub trait SheetIdentifier {
     fn get_id(&self) -> SheetUniqueIdentifier;
 }
 
-///
+/// A change to a range of cells on a sheet
 pub trait ChangeEvent {
     fn get_sheet_id(&self) -> SheetUniqueIdentifier;
     fn get_range(&self) -> SheetRange;
@@ -76,7 +78,7 @@ pub trait Workbook {
     fn get_sheet(&self, sheet_id: SheetUniqueIdentifier) -> Option<Arc<dyn Worksheet>>;
     fn listen_for_changes(
         &self,
-        to_listen: &Vec<SheetRange>,
+        to_listen: &[SheetRange],
     ) -> dyn Stream<Item = Arc<dyn ChangeEvent>>;
 }
 
@@ -87,24 +89,154 @@ pub trait Cell {
 
 pub trait Worksheet: SheetIdentifier {}
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub enum Value {
     Int(i128),
+    /// JSON has no numbers that aren't finite, so there these are the
+    /// strings `NaN`, `inf` and `-inf`
+    #[serde(serialize_with = "float_out", deserialize_with = "float_in")]
     Float(f64),
     Str(String),
-    Date(Instant),
+    /// Only dates after the Unix epoch (1970-01-01 UTC) can be serialized
+    Date(SystemTime),
     Bool(bool),
     JSON(JsonValue),
     Error((String, u32)),
     Maybe(Option<Arc<Value>>),
+    /// A 2-D array as rows of values. Every row is the same length
+    Array(Vec<Vec<Value>>),
     TypedJSON((JsonValue, Arc<JsonType>)),
     Other(Arc<OtherValue>),
 }
 
-#[derive(Debug, PartialEq, Clone)]
+/// The standard spreadsheet errors. An error is a value rather than a
+/// failure: a formula that uses a cell holding an error has the error as
+/// its result. `Value::Error` holds the text and code of one of these
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, Serialize, Deserialize)]
+pub enum CellError {
+    /// `#DIV/0!` dividing by zero
+    DivZero,
+    /// `#VALUE!` an operand or parameter of the wrong type
+    Value,
+    /// `#REF!` a reference to a cell or sheet that isn't there
+    Ref,
+    /// `#NAME?` a name or function that isn't defined
+    Name,
+    /// `#NUM!` a number that can't be represented, like an infinite result
+    Num,
+    /// `#N/A` a value that isn't available
+    NA,
+    /// `#CIRC!` a formula that depends on its own value. Nothing gives
+    /// this yet: there's no cycle detection
+    Circ,
+}
+
+impl CellError {
+    pub const ALL: [CellError; 7] = [
+        CellError::DivZero,
+        CellError::Value,
+        CellError::Ref,
+        CellError::Name,
+        CellError::Num,
+        CellError::NA,
+        CellError::Circ,
+    ];
+
+    /// The code for the error, which never changes. Where other
+    /// spreadsheets' `ERROR.TYPE` has a number for the error it's the same
+    pub fn code(&self) -> u32 {
+        match self {
+            CellError::DivZero => 2,
+            CellError::Value => 3,
+            CellError::Ref => 4,
+            CellError::Name => 5,
+            CellError::Num => 6,
+            CellError::NA => 7,
+            CellError::Circ => 20,
+        }
+    }
+
+    pub fn text(&self) -> &'static str {
+        match self {
+            CellError::DivZero => "#DIV/0!",
+            CellError::Value => "#VALUE!",
+            CellError::Ref => "#REF!",
+            CellError::Name => "#NAME?",
+            CellError::Num => "#NUM!",
+            CellError::NA => "#N/A",
+            CellError::Circ => "#CIRC!",
+        }
+    }
+
+    pub fn from_code(code: u32) -> Option<CellError> {
+        CellError::ALL.into_iter().find(|e| e.code() == code)
+    }
+
+    /// The error written as `text`, ignoring case
+    pub fn from_text(text: &str) -> Option<CellError> {
+        CellError::ALL
+            .into_iter()
+            .find(|e| e.text().eq_ignore_ascii_case(text))
+    }
+}
+
+impl std::fmt::Display for CellError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.text())
+    }
+}
+
+impl From<CellError> for Value {
+    fn from(e: CellError) -> Value {
+        Value::Error((e.text().to_string(), e.code()))
+    }
+}
+
+impl Value {
+    /// The standard error this value is, if it's one
+    pub fn cell_error(&self) -> Option<CellError> {
+        match self {
+            Value::Error((_, code)) => CellError::from_code(*code),
+            _ => None,
+        }
+    }
+}
+
+fn float_out<S: Serializer>(x: &f64, s: S) -> Result<S::Ok, S::Error> {
+    match *x {
+        x if x.is_finite() || !s.is_human_readable() => s.serialize_f64(x),
+        x if x.is_nan() => s.serialize_str("NaN"),
+        x if x > 0.0 => s.serialize_str("inf"),
+        _ => s.serialize_str("-inf"),
+    }
+}
+
+fn float_in<'de, D: Deserializer<'de>>(d: D) -> Result<f64, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Float {
+        Number(f64),
+        Text(String),
+    }
+
+    if !d.is_human_readable() {
+        return f64::deserialize(d);
+    }
+    match Float::deserialize(d)? {
+        Float::Number(x) => Ok(x),
+        Float::Text(text) => match text.as_str() {
+            "NaN" => Ok(f64::NAN),
+            "inf" => Ok(f64::INFINITY),
+            "-inf" => Ok(f64::NEG_INFINITY),
+            _ => Err(D::Error::custom(format!("'{}' isn't a number", text))),
+        },
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub enum JsonType {}
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub enum JsonValue {
     Number(f64),
     IntNumber(i128),
@@ -114,5 +246,5 @@ pub enum JsonValue {
     Array(Vec<JsonValue>),
     Json(HashMap<String, JsonValue>),
 }
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct OtherValue {}
@@ -0,0 +1,313 @@
+//! Diagnostics for formulas that can't be parsed
+//!
+
+// Copyright 2021 David Pollak
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt;
+
+/// A problem found while parsing a formula. The position is where the
+/// problem is: `offset` is the byte offset into the formula, `line` and
+/// `column` count from 1 and `column` counts characters
+#[derive(Debug, PartialEq, Eq, Clone, Hash)]
+pub struct ParseDiagnostic {
+    pub offset: usize,
+    pub line: u32,
+    pub column: usize,
+    pub expected: Vec<String>,
+    pub found: String,
+    pub message: String,
+}
+
+impl fmt::Display for ParseDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl std::error::Error for ParseDiagnostic {}
+
+/// The text used for running off the end of the formula
+pub const END_OF_FORMULA: &str = "end of formula";
+
+impl ParseDiagnostic {
+    /// A diagnostic for finding something other than what was expected
+    /// at `offset` in `formula`
+    pub fn unexpected(formula: &str, offset: usize, expected: Vec<String>) -> ParseDiagnostic {
+        let (line, column) = line_column(formula, offset);
+        let found = found_at(formula, offset);
+        let message = if expected.is_empty() {
+            format!("unexpected {}", found)
+        } else {
+            format!("expected {} but found {}", join_expected(&expected), found)
+        };
+
+        ParseDiagnostic {
+            offset,
+            line,
+            column,
+            expected,
+            found,
+            message,
+        }
+    }
+
+    /// A diagnostic for something at `offset` in `formula` that was
+    /// recognized but is wrong
+    pub fn problem(formula: &str, offset: usize, message: String) -> ParseDiagnostic {
+        let (line, column) = line_column(formula, offset);
+        ParseDiagnostic {
+            offset,
+            line,
+            column,
+            expected: vec![],
+            found: found_at(formula, offset),
+            message,
+        }
+    }
+}
+
+/// Join the expected items as `a, b or c`
+fn join_expected(expected: &[String]) -> String {
+    match expected.split_last() {
+        Some((last, [])) => last.clone(),
+        Some((last, rest)) => format!("{} or {}", rest.join(", "), last),
+        None => "".into(),
+    }
+}
+
+/// The line and column (counting from 1) of a byte offset
+pub fn line_column(formula: &str, offset: usize) -> (u32, usize) {
+    let before = &formula[..offset.min(formula.len())];
+    let line = before.matches('\n').count() as u32 + 1;
+    let column = match before.rfind('\n') {
+        Some(pos) => before[pos + 1..].chars().count() + 1,
+        None => before.chars().count() + 1,
+    };
+    (line, column)
+}
+
+/// A description of the token at a byte offset for use in messages
+fn found_at(formula: &str, offset: usize) -> String {
+    let rest = &formula[offset.min(formula.len())..];
+    match rest.chars().next() {
+        None => END_OF_FORMULA.into(),
+        Some(c) if c.is_alphanumeric() || c == '_' => {
+            let word: String = rest
+                .chars()
+                .take_while(|c| c.is_alphanumeric() || *c == '_')
+                .collect();
+            format!("'{}'", word)
+        }
+        Some(c) => format!("'{}'", c),
+    }
+}
+
+fn delimiter_name(open: char) -> &'static str {
+    match open {
+        '(' => "parenthesis",
+        '[' => "bracket",
+        _ => "brace",
+    }
+}
+
+fn closer_for(open: char) -> char {
+    match open {
+        '(' => ')',
+        '[' => ']',
+        _ => '}',
+    }
+}
+
+/// Find unbalanced parentheses, brackets and braces as well as strings
+/// and comments that are never closed. Every problem is reported rather
+/// than just the first
+pub fn check_delimiters(formula: &str) -> Vec<ParseDiagnostic> {
+    let mut ret = vec![];
+    let mut open: Vec<(char, usize)> = vec![];
+    let mut chars = formula.char_indices().peekable();
+
+    let diagnostic = |offset: usize, expected: Vec<String>, found: String, message: String| {
+        let (line, column) = line_column(formula, offset);
+        ParseDiagnostic {
+            offset,
+            line,
+            column,
+            expected,
+            found,
+            message,
+        }
+    };
+    let at = |offset: usize| {
+        let (line, column) = line_column(formula, offset);
+        format!("{}:{}", line, column)
+    };
+
+    while let Some((pos, c)) = chars.next() {
+        match c {
+            '"' => {
+                // a doubled quote ends one string and starts the next, so
+                // only a backslash escape needs to be skipped
+                let mut closed = false;
+                while let Some((_, c2)) = chars.next() {
+                    if c2 == '\\' {
+                        chars.next();
+                    } else if c2 == '"' {
+                        closed = true;
+                        break;
+                    }
+                }
+                if !closed {
+                    ret.push(diagnostic(
+                        pos,
+                        vec!["'\"'".into()],
+                        END_OF_FORMULA.into(),
+                        format!("unterminated string started at {}", at(pos)),
+                    ));
+                }
+            }
+            '\'' => {
+                // a quoted sheet name, where `''` is a quote in the name
+                while let Some((p2, c2)) = chars.next() {
+                    if c2 == '\'' && !formula[p2 + 1..].starts_with('\'') {
+                        break;
+                    }
+                    if c2 == '\'' {
+                        chars.next();
+                    }
+                }
+            }
+            '/' if formula[pos..].starts_with("/*") => {
+                chars.next();
+                let mut depth = 1;
+                while let Some((p2, c2)) = chars.next() {
+                    if c2 == '/' && formula[p2..].starts_with("/*") {
+                        chars.next();
+                        depth += 1;
+                    } else if c2 == '*' && formula[p2..].starts_with("*/") {
+                        chars.next();
+                        depth -= 1;
+                        if depth == 0 {
+                            break;
+                        }
+                    }
+                }
+                if depth > 0 {
+                    ret.push(diagnostic(
+                        pos,
+                        vec!["'*/'".into()],
+                        END_OF_FORMULA.into(),
+                        format!("unterminated comment started at {}", at(pos)),
+                    ));
+                }
+            }
+            '/' if formula[pos..].starts_with("//#") => {
+                while let Some((_, c2)) = chars.peek() {
+                    if *c2 == '\n' || *c2 == '\r' {
+                        break;
+                    }
+                    chars.next();
+                }
+            }
+            '(' | '[' | '{' => open.push((c, pos)),
+            ')' | ']' | '}' => match open.pop() {
+                Some((o, _)) if closer_for(o) == c => (),
+                Some((o, o_pos)) => ret.push(diagnostic(
+                    pos,
+                    vec![format!("'{}'", closer_for(o))],
+                    format!("'{}'", c),
+                    format!(
+                        "expected '{}' to close the {} opened at {} but found '{}'",
+                        closer_for(o),
+                        delimiter_name(o),
+                        at(o_pos),
+                        c
+                    ),
+                )),
+                None => ret.push(diagnostic(
+                    pos,
+                    vec![],
+                    format!("'{}'", c),
+                    format!("unmatched '{}'", c),
+                )),
+            },
+            _ => (),
+        }
+    }
+
+    for (o, pos) in open {
+        ret.push(diagnostic(
+            pos,
+            vec![format!("'{}'", closer_for(o))],
+            END_OF_FORMULA.into(),
+            format!("unclosed {} opened at {}", delimiter_name(o), at(pos)),
+        ));
+    }
+
+    ret.sort_by_key(|d| d.offset);
+    ret
+}
+
+#[test]
+fn test_line_column() {
+    assert_eq!(line_column("abc", 0), (1, 1));
+    assert_eq!(line_column("abc", 2), (1, 3));
+    assert_eq!(line_column("ab\ncd", 4), (2, 2));
+    assert_eq!(line_column("ab\ncd", 3), (2, 1));
+    assert_eq!(line_column("é+x", 3), (1, 3));
+}
+
+#[test]
+fn test_check_delimiters() {
+    assert_eq!(check_delimiters("sum((1), [2], \"(\") /* ( */"), vec![]);
+    assert_eq!(check_delimiters("sum('Q(1'':x'!A1, 'a)'!B2)"), vec![]);
+    assert_eq!(check_delimiters(r#"f("a\"(", "b""(")"#), vec![]);
+
+    let found = check_delimiters("(1 +\n  (2");
+    assert_eq!(found.len(), 2);
+    assert_eq!(found[0].message, "unclosed parenthesis opened at 1:1");
+    assert_eq!(found[1].message, "unclosed parenthesis opened at 2:3");
+    assert_eq!((found[1].line, found[1].column, found[1].offset), (2, 3, 7));
+
+    let found = check_delimiters("f[1)");
+    assert_eq!(
+        found[0].message,
+        "expected ']' to close the bracket opened at 1:2 but found ')'"
+    );
+
+    let found = check_delimiters("1) + \"abc");
+    assert_eq!(found.len(), 2);
+    assert_eq!(found[0].message, "unmatched ')'");
+    assert_eq!(found[1].message, "unterminated string started at 1:6");
+
+    let found = check_delimiters("1 /* /* */");
+    assert_eq!(found[0].message, "unterminated comment started at 1:3");
+}
+
+#[test]
+fn test_unexpected() {
+    let d = ParseDiagnostic::unexpected("1 2", 2, vec!["an operator".into()]);
+    assert_eq!(d.message, "expected an operator but found '2'");
+    assert_eq!(d.to_string(), "1:3: expected an operator but found '2'");
+
+    let d = ParseDiagnostic::unexpected(
+        "1 +",
+        3,
+        vec!["a number".into(), "a string".into(), "'('".into()],
+    );
+    assert_eq!(
+        d.message,
+        "expected a number, a string or '(' but found end of formula"
+    );
+}
@@ -1,73 +1,1356 @@
-use crate::definitions::Value;
-use crate::eval_stack::EvalStack;
+use crate::compute::{ArcSheetInfo, SheetInfo, WorkbookInfo};
+use crate::definitions::{CellError, Value};
+use crate::eval_stack::{EvalStack, SourceMap, Types};
+use crate::functions::{builtins, FormulaFunction, Registry, RegistryError};
+use crate::parser::{Address, PositionInfo, Range, SheetRef};
+use crate::worksheet::{
+    A1Reference, AddressError, SimpleAddress, SimpleRange, SimpleWorksheet, Worksheet,
+};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
 
-pub fn eval(instructions: &Vec<EvalStack>) -> Result<Value, String> {
+/// Where a formula is evaluated: the workbook that references are
+/// resolved against, the name of the sheet that unqualified references
+/// are on and the functions that can be called
+#[derive(Debug, Clone)]
+pub struct EvalContext {
+    pub workbook: WorkbookInfo,
+    pub sheet: Option<String>,
+    pub functions: Arc<Registry>,
+}
+
+impl Default for EvalContext {
+    /// No workbook and the built-in functions
+    fn default() -> EvalContext {
+        EvalContext {
+            workbook: WorkbookInfo::default(),
+            sheet: None,
+            functions: builtins(),
+        }
+    }
+}
+
+impl EvalContext {
+    /// Make a function callable from formulas evaluated in this context,
+    /// replacing any function with the same name. An error if the
+    /// function's name and signature don't match
+    pub fn register(
+        &mut self,
+        function: impl FormulaFunction + 'static,
+    ) -> Result<(), RegistryError> {
+        Arc::make_mut(&mut self.functions).register(Arc::new(function))
+    }
+
+    /// A workbook of `sheets`, each a name and the values in its cells by
+    /// A1 address, with the built-in functions. Unqualified references
+    /// are on the first sheet
+    pub fn with_sheets(sheets: &[(&str, &[(&str, Value)])]) -> Result<EvalContext, AddressError> {
+        let mut workbook = WorkbookInfo::new();
+        for (name, cells) in sheets {
+            let sheet = SimpleWorksheet::new();
+            for (at, value) in cells.iter() {
+                sheet.set_cell(&at.parse()?, &Arc::new(value.clone()));
+            }
+            workbook = workbook.set_sheet(name.to_string(), Arc::new(SheetInfo::new(sheet)));
+        }
+        Ok(EvalContext {
+            workbook,
+            sheet: sheets.first().map(|(name, _)| name.to_string()),
+            ..EvalContext::default()
+        })
+    }
+}
+
+/// Why evaluating instructions failed. Only instructions that couldn't
+/// have been built from a formula fail: a problem with the values, like
+/// dividing by zero, is an error value instead
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct EvalError {
+    /// The index of the instruction that failed or the number of
+    /// instructions if they all ran but didn't leave one value
+    pub instruction: usize,
+    pub kind: EvalErrorKind,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum EvalErrorKind {
+    UnknownOperator(String),
+    /// An operator or array needed more values than were on the stack.
+    /// `found` is the types of the values there were
+    StackUnderflow {
+        operator: String,
+        needed: usize,
+        found: Vec<Types>,
+    },
+    /// The instructions left other than one value. These are the types
+    /// of what was left
+    Unbalanced(Vec<Types>),
+}
+
+impl EvalError {
+    /// Where in the formula the failed instruction came from
+    pub fn position<'a>(&self, map: &'a SourceMap) -> Option<&'a PositionInfo> {
+        map.get(self.instruction).and_then(|info| info.as_deref())
+    }
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.kind {
+            EvalErrorKind::UnknownOperator(opr) => write!(
+                f,
+                "instruction {}: unknown operator '{}'",
+                self.instruction, opr
+            ),
+            EvalErrorKind::StackUnderflow {
+                operator,
+                needed,
+                found,
+            } => write!(
+                f,
+                "instruction {}: '{}' needs {} values but the stack has {:?}",
+                self.instruction, operator, needed, found
+            ),
+            EvalErrorKind::Unbalanced(left) => write!(
+                f,
+                "evaluating left {} values {:?} rather than 1",
+                left.len(),
+                left
+            ),
+        }
+    }
+}
+
+impl std::error::Error for EvalError {}
+
+/// Evaluate without a workbook. References to cells are `#REF!`
+pub fn eval(instructions: &[EvalStack]) -> Result<Value, EvalError> {
+    eval_in(instructions, &EvalContext::default())
+}
+
+/// Evaluate with references resolved against `context`
+pub fn eval_in(instructions: &[EvalStack], context: &EvalContext) -> Result<Value, EvalError> {
     let mut stack: Vec<Value> = vec![];
+    let arguments = range_arguments(instructions);
 
-    for inst in instructions {
+    for (pos, inst) in instructions.iter().enumerate() {
+        let fail = |kind| EvalError {
+            instruction: pos,
+            kind,
+        };
         match inst {
             EvalStack::PushInt(i) => stack.push(Value::Int(*i)),
             EvalStack::PushFloat(f) => stack.push(Value::Float(*f)),
             EvalStack::PushStr(s) => stack.push(Value::Str(s.clone())),
-            EvalStack::PerformOpr(opr) => match perform_opr(opr, &mut stack) {
-                Err(e) => return Err(e),
-                _ => (),
-            },
+            EvalStack::PushBool(b) => stack.push(Value::Bool(*b)),
+            EvalStack::PushError(e) => stack.push((*e).into()),
+            EvalStack::PushAddress(addr) => stack.push(cell_value(addr, context)),
+            EvalStack::PushRange(range) => stack.push(match arguments.get(&pos) {
+                Some(name)
+                    if context
+                        .functions
+                        .get(name)
+                        .is_some_and(|f| f.skips_blanks()) =>
+                {
+                    populated_value(range, context)
+                }
+                _ => range_value(range, context),
+            }),
+            EvalStack::MakeArray(rows, cols) => {
+                make_array(*rows, *cols, &mut stack).map_err(fail)?
+            }
+            EvalStack::PerformOpr(opr) => perform_opr(opr, &mut stack).map_err(fail)?,
+            EvalStack::PerformIntOpr(opr) => perform_int_opr(opr, &mut stack).map_err(fail)?,
+            EvalStack::PerformPrefix(opr) | EvalStack::PerformPostfix(opr) => {
+                perform_unary(opr, &mut stack).map_err(fail)?
+            }
+            EvalStack::CallFunction(name, count, decorators) => {
+                call_function(name, *count, decorators, context, &mut stack).map_err(fail)?
+            }
         }
     }
 
-    if stack.len() == 1 {
-        return Ok(
-            stack.pop().unwrap(), /* unwrap ok here because length test */
-        );
+    match stack.pop() {
+        Some(v) if stack.is_empty() => Ok(v),
+        last => Err(EvalError {
+            instruction: instructions.len(),
+            kind: EvalErrorKind::Unbalanced(stack.iter().chain(&last).map(Types::of).collect()),
+        }),
+    }
+}
+
+/// The value of the cell at `addr`. A cell with nothing in it is a blank
+/// and a cell that isn't there is `#REF!`
+fn cell_value(addr: &Address, context: &EvalContext) -> Value {
+    let sheet = match sheet_for(&addr.sheet, context) {
+        Ok(sheet) => sheet,
+        Err(e) => return e.into(),
     };
-    Err(format!("Could not eval... stack ended at {:?}", &stack))
+    let at: SimpleAddress = match addr.addr.parse() {
+        Ok(at) => at,
+        Err(_) => return CellError::Ref.into(),
+    };
+
+    match sheet.worksheet().get_cell_value(&at) {
+        Some(v) => (*v).clone(),
+        None => Value::Maybe(None),
+    }
 }
 
-fn perform_opr(opr: &str, stack: &mut Vec<Value>) -> Result<(), String> {
-    match opr {
-        "+" => match (stack.pop(), stack.pop()) {
-            (Some(Value::Int(i1)), Some(Value::Int(i2))) => {
-                stack.push(Value::Int(i1 + i2));
-                Ok(())
-            }
-            (Some(Value::Float(f1)), Some(Value::Float(f2))) => {
-                stack.push(Value::Float(f1 + f2));
-                Ok(())
+/// The cells in `range` that have a value, in row then column order.
+/// Whole rows and columns only visit the populated cells
+pub fn range_cells(
+    range: &Range,
+    context: &EvalContext,
+) -> Result<Vec<(SimpleAddress, Value)>, CellError> {
+    let sheet = sheet_for(&range.sheet, context)?;
+    let bounds = simple_range(range)?;
+
+    Ok(sheet
+        .worksheet()
+        .populated_cells(&bounds)
+        .into_iter()
+        .map(|(addr, v)| (addr, (*v).clone()))
+        .collect())
+}
+
+/// The most cells a range can be turned into an array of. A bigger range
+/// is `#NUM!` rather than taking all the memory there is
+pub const MAX_ARRAY_CELLS: i64 = 1 << 22;
+
+/// The cells in `range` as an array. A whole column or row stops at the
+/// last populated cell rather than the edge of the sheet. A function that
+/// skips blanks gets `populated_value` instead
+fn range_value(range: &Range, context: &EvalContext) -> Value {
+    let (bounds, cells) = match (simple_range(range), range_cells(range, context)) {
+        (Ok(bounds), Ok(cells)) => (bounds, cells),
+        (Err(e), _) | (_, Err(e)) => return e.into(),
+    };
+
+    let last_row = bounds.last_row.unwrap_or_else(|| {
+        cells
+            .iter()
+            .map(|(a, _)| a.row)
+            .fold(bounds.first_row, i32::max)
+    });
+    let last_col = bounds.last_col.unwrap_or_else(|| {
+        cells
+            .iter()
+            .map(|(a, _)| a.col)
+            .fold(bounds.first_col, i32::max)
+    });
+
+    let height = last_row as i64 - bounds.first_row as i64 + 1;
+    let width = last_col as i64 - bounds.first_col as i64 + 1;
+    if height * width > MAX_ARRAY_CELLS {
+        return CellError::Num.into();
+    }
+
+    let mut rows = vec![
+        vec![Value::Maybe(None); (last_col - bounds.first_col + 1) as usize];
+        (last_row - bounds.first_row + 1) as usize
+    ];
+    for (addr, v) in cells {
+        rows[(addr.row - bounds.first_row) as usize][(addr.col - bounds.first_col) as usize] = v;
+    }
+    Value::Array(rows)
+}
+
+/// The populated cells in `range` as a one row array, for a function
+/// that skips blanks. A whole column costs what's in it, not its length
+fn populated_value(range: &Range, context: &EvalContext) -> Value {
+    match range_cells(range, context) {
+        Ok(cells) => Value::Array(vec![cells.into_iter().map(|(_, v)| v).collect()]),
+        Err(e) => e.into(),
+    }
+}
+
+/// The `PushRange` instructions whose range is passed straight to a
+/// function, by position, with the name of the function
+fn range_arguments(instructions: &[EvalStack]) -> HashMap<usize, &str> {
+    // the instruction that pushed each value on the stack
+    let mut pushed_by: Vec<usize> = vec![];
+    let mut ret = HashMap::new();
+    for (pos, inst) in instructions.iter().enumerate() {
+        let pops = match inst {
+            EvalStack::MakeArray(rows, cols) => rows * cols,
+            EvalStack::PerformOpr(_) | EvalStack::PerformIntOpr(_) => 2,
+            EvalStack::PerformPrefix(_) | EvalStack::PerformPostfix(_) => 1,
+            EvalStack::CallFunction(name, count, _) => {
+                for p in pushed_by.iter().rev().take(*count) {
+                    if matches!(instructions[*p], EvalStack::PushRange(_)) {
+                        ret.insert(*p, name.as_str());
+                    }
+                }
+                *count
             }
-            (i1, i2) => Err(format!(
-                "Failed operator '+' for stack items {:?} and {:?}",
-                i1, i2
-            )),
-        },
-        "/" => match (stack.pop(), stack.pop()) {
-            (Some(Value::Int(i1)), Some(Value::Int(i2))) => {
-                stack.push(Value::Int(i1 / i2));
-                Ok(())
+            _ => 0,
+        };
+        pushed_by.truncate(pushed_by.len().saturating_sub(pops));
+        pushed_by.push(pos);
+    }
+    ret
+}
+
+/// Pop the items of an array constant, which were pushed row by row. An
+/// array can't hold arrays so one that would is `#VALUE!`
+fn make_array(rows: usize, cols: usize, stack: &mut Vec<Value>) -> Result<(), EvalErrorKind> {
+    if stack.len() < rows * cols {
+        return Err(EvalErrorKind::StackUnderflow {
+            operator: "{}".into(),
+            needed: rows * cols,
+            found: stack.iter().map(Types::of).collect(),
+        });
+    }
+    let items = stack.split_off(stack.len() - rows * cols);
+    if items.iter().any(|v| matches!(v, Value::Array(_))) {
+        stack.push(CellError::Value.into());
+        return Ok(());
+    }
+
+    stack.push(Value::Array(
+        items.chunks(cols.max(1)).map(|row| row.to_vec()).collect(),
+    ));
+    Ok(())
+}
+
+/// The number of rows and columns in a value. A single value is 1x1.
+/// An array with rows of different lengths, which a custom function or
+/// a deserialized value could hold, has no dimensions
+pub(crate) fn dimensions(v: &Value) -> Option<(usize, usize)> {
+    match v {
+        Value::Array(rows) => {
+            let cols = rows.first().map(|r| r.len()).unwrap_or(0);
+            match rows.iter().all(|r| r.len() == cols) {
+                true => Some((rows.len(), cols)),
+                false => None,
             }
-            (Some(Value::Float(f1)), Some(Value::Float(f2))) => {
-                stack.push(Value::Float(f1 / f2));
-                Ok(())
+        }
+        _ => Some((1, 1)),
+    }
+}
+
+/// The item of `v` at a row and column. A single value is at every
+/// position and an array with one row or column repeats it
+fn item_at(v: &Value, row: usize, col: usize) -> Value {
+    match v {
+        Value::Array(rows) => {
+            let items = &rows[if rows.len() == 1 { 0 } else { row }];
+            items[if items.len() == 1 { 0 } else { col }].clone()
+        }
+        other => other.clone(),
+    }
+}
+
+/// Perform a binary operator item by item when either operand is an
+/// array. The operands are broadcast against each other: a single value
+/// goes with every item and a one row or one column array is repeated
+/// to match the other operand. Arrays that don't fit together or have
+/// rows of different lengths are `#VALUE!`
+fn broadcast(opr: &str, left: &Value, right: &Value) -> Result<Value, EvalErrorKind> {
+    let ((l_rows, l_cols), (r_rows, r_cols)) = match (dimensions(left), dimensions(right)) {
+        (Some(left), Some(right)) => (left, right),
+        _ => return Ok(CellError::Value.into()),
+    };
+    let size = |a: usize, b: usize| match (a, b) {
+        (a, b) if a == b || b == 1 => Some(a),
+        (1, b) => Some(b),
+        _ => None,
+    };
+    let (rows, cols) = match (size(l_rows, r_rows), size(l_cols, r_cols)) {
+        (Some(rows), Some(cols)) => (rows, cols),
+        _ => return Ok(CellError::Value.into()),
+    };
+
+    let mut ret = Vec::with_capacity(rows);
+    for row in 0..rows {
+        let mut items = Vec::with_capacity(cols);
+        for col in 0..cols {
+            let mut operands = vec![item_at(left, row, col), item_at(right, row, col)];
+            perform_opr(opr, &mut operands)?;
+            items.push(operands.pop().unwrap_or(Value::Maybe(None)));
+        }
+        ret.push(items);
+    }
+    Ok(Value::Array(ret))
+}
+
+/// The sheet a reference is on: the qualified sheet or else the sheet
+/// the formula is evaluated on. A sheet that isn't there is `#REF!` and
+/// a reference to several sheets can't be a single value
+fn sheet_for(sheet: &Option<SheetRef>, context: &EvalContext) -> Result<ArcSheetInfo, CellError> {
+    let name = match (sheet, &context.sheet) {
+        (Some(SheetRef { last: Some(_), .. }), _) => return Err(CellError::Value),
+        (Some(SheetRef { first, .. }), _) => first,
+        (None, Some(current)) => current,
+        (None, None) => return Err(CellError::Ref),
+    };
+    context
+        .workbook
+        .sheet_for_name(name.clone())
+        .ok_or(CellError::Ref)
+}
+
+/// Convert a range to the rectangle it covers. A missing row or column
+/// in a corner leaves that side open. A range off the sheet is `#REF!`
+fn simple_range(range: &Range) -> Result<SimpleRange, CellError> {
+    let corner = |addr: &Address| addr.addr.parse::<A1Reference>();
+    corner(&range.upper_left)
+        .and_then(|ul| SimpleRange::from_corners(&ul, &corner(&range.lower_right)?))
+        .map_err(|_| CellError::Ref)
+}
+
+fn perform_opr(opr: &str, stack: &mut Vec<Value>) -> Result<(), EvalErrorKind> {
+    if stack
+        .iter()
+        .rev()
+        .take(2)
+        .any(|v| matches!(v, Value::Array(_)))
+    {
+        let (left, right) = pop_operands(opr, stack)?;
+        stack.push(broadcast(opr, &left, &right)?);
+        return Ok(());
+    }
+
+    match opr {
+        "+" | "-" | "*" | "/" | "%" | "^" => {
+            let (left, right) = pop_operands(opr, stack)?;
+            let res = match propagate_error(&left, &right) {
+                Some(err) => err,
+                None => arithmetic(opr, &left, &right)?,
+            };
+            stack.push(res);
+            Ok(())
+        }
+        "==" | "!=" | "<" | "<=" | ">" | ">=" => {
+            let (left, right) = pop_operands(opr, stack)?;
+            let res = match propagate_error(&left, &right) {
+                Some(err) => err,
+                None => compare(opr, &left, &right),
+            };
+            stack.push(res);
+            Ok(())
+        }
+        "&&" | "||" => {
+            let (left, right) = pop_operands(opr, stack)?;
+            let res = match propagate_error(&left, &right) {
+                Some(err) => err,
+                None => match (as_bool(&left), as_bool(&right)) {
+                    (Some(l), Some(r)) if opr == "&&" => Value::Bool(l && r),
+                    (Some(l), Some(r)) => Value::Bool(l || r),
+                    _ => CellError::Value.into(),
+                },
+            };
+            stack.push(res);
+            Ok(())
+        }
+        "&" => {
+            let (left, right) = pop_operands(opr, stack)?;
+            let res = match propagate_error(&left, &right) {
+                Some(err) => err,
+                None => match (as_text(&left), as_text(&right)) {
+                    (Some(l), Some(r)) => Value::Str(l + &r),
+                    _ => CellError::Value.into(),
+                },
+            };
+            stack.push(res);
+            Ok(())
+        }
+        _ => Err(EvalErrorKind::UnknownOperator(opr.into())),
+    }
+}
+
+/// Perform an arithmetic operator on two `Int`s without the checks for
+/// arrays, errors and blanks, or fall back to `perform_opr`
+fn perform_int_opr(opr: &str, stack: &mut Vec<Value>) -> Result<(), EvalErrorKind> {
+    if let [.., Value::Int(a), Value::Int(b)] = stack[..] {
+        stack.truncate(stack.len() - 2);
+        stack.push(int_arithmetic(opr, a, b)?);
+        return Ok(());
+    }
+    perform_opr(opr, stack)
+}
+
+/// Perform a prefix (`-`, `+`, `!`) or postfix (`%`) operator on the top
+/// of the stack
+fn perform_unary(opr: &str, stack: &mut Vec<Value>) -> Result<(), EvalErrorKind> {
+    if let Some(Value::Array(rows)) = stack.last() {
+        let mut ret = Vec::with_capacity(rows.len());
+        for row in rows {
+            let mut items = Vec::with_capacity(row.len());
+            for item in row {
+                let mut operand = vec![item.clone()];
+                perform_unary(opr, &mut operand)?;
+                items.push(operand.pop().unwrap_or(Value::Maybe(None)));
             }
-            (i1, i2) => Err(format!(
-                "Failed operator '/' for stack items {:?} and {:?}",
-                i1, i2
-            )),
+            ret.push(items);
+        }
+        stack.pop();
+        stack.push(Value::Array(ret));
+        return Ok(());
+    }
+
+    let operand = match stack.pop() {
+        Some(v) => unwrap_maybe(&v),
+        None => {
+            return Err(EvalErrorKind::StackUnderflow {
+                operator: opr.into(),
+                needed: 1,
+                found: vec![],
+            })
+        }
+    };
+
+    if !matches!(opr, "+" | "-" | "!" | "%") {
+        return Err(EvalErrorKind::UnknownOperator(opr.into()));
+    }
+    let res = match (opr, &operand, as_number(&operand)) {
+        (_, Value::Error(_), _) => operand.clone(),
+        ("+", _, _) => operand.clone(),
+        ("-", _, Some(Number::Int(i))) => int_arithmetic("-", 0, i)?,
+        ("-", _, Some(Number::Float(f))) => Value::Float(-f),
+        ("!", v, _) if as_bool(v).is_some() => Value::Bool(!as_bool(v).unwrap_or_default()),
+        ("%", v, _) if as_float(v).is_some() => {
+            Value::Float(as_float(v).unwrap_or_default() / 100.0)
+        }
+        _ => CellError::Value.into(),
+    };
+
+    stack.push(res);
+    Ok(())
+}
+
+/// Pop the parameters of a function and push its result
+fn call_function(
+    name: &str,
+    count: usize,
+    decorators: &[String],
+    context: &EvalContext,
+    stack: &mut Vec<Value>,
+) -> Result<(), EvalErrorKind> {
+    if stack.len() < count {
+        return Err(EvalErrorKind::StackUnderflow {
+            operator: name.into(),
+            needed: count,
+            found: stack.iter().map(Types::of).collect(),
+        });
+    }
+    let params = stack.split_off(stack.len() - count);
+    stack.push(context.functions.call(name, &params, decorators));
+    Ok(())
+}
+
+/// Pop the operands of a binary operator. The right operand is on the
+/// top of the stack, so the result is `(left, right)`
+fn pop_operands(opr: &str, stack: &mut Vec<Value>) -> Result<(Value, Value), EvalErrorKind> {
+    match (stack.pop(), stack.pop()) {
+        (Some(right), Some(left)) => Ok((left, right)),
+        (right, _) => Err(EvalErrorKind::StackUnderflow {
+            operator: opr.into(),
+            needed: 2,
+            found: right.iter().map(Types::of).collect(),
+        }),
+    }
+}
+
+/// If either operand is an error, the result of the operator is that error
+fn propagate_error(left: &Value, right: &Value) -> Option<Value> {
+    match (unwrap_maybe(left), unwrap_maybe(right)) {
+        (err @ Value::Error(_), _) => Some(err),
+        (_, err @ Value::Error(_)) => Some(err),
+        _ => None,
+    }
+}
+
+/// A `Maybe` that has a value is treated as the value. An empty `Maybe`
+/// is a blank: `0`, `""` or `false` depending on how it's used
+pub(crate) fn unwrap_maybe(v: &Value) -> Value {
+    match v {
+        Value::Maybe(Some(inner)) => unwrap_maybe(inner),
+        other => other.clone(),
+    }
+}
+
+/// A number on the numeric tower. Arithmetic on two `Int`s gives an
+/// `Int` when the result is a whole number that fits. Otherwise both
+/// operands are promoted to `Float`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Number {
+    Int(i128),
+    Float(f64),
+}
+
+impl Number {
+    pub(crate) fn to_f64(self) -> f64 {
+        match self {
+            Number::Int(i) => i as f64,
+            Number::Float(f) => f,
+        }
+    }
+
+    pub(crate) fn value(self) -> Value {
+        match self {
+            Number::Int(i) => Value::Int(i),
+            Number::Float(f) => Value::Float(f),
+        }
+    }
+}
+
+/// The number an operand of an arithmetic operator stands for. `true`
+/// and `false` are `1` and `0` and a blank is `0`. Text isn't a number
+pub(crate) fn as_number(v: &Value) -> Option<Number> {
+    match unwrap_maybe(v) {
+        Value::Int(i) => Some(Number::Int(i)),
+        Value::Float(f) => Some(Number::Float(f)),
+        Value::Bool(b) => Some(Number::Int(b as i128)),
+        Value::Maybe(None) => Some(Number::Int(0)),
+        _ => None,
+    }
+}
+
+/// Perform `+`, `-`, `*`, `/`, `%` or `^` with `left` as the left
+/// operand. An `Int` with a `Float` is promoted to a `Float`. An operand
+/// that isn't a number is `#VALUE!`
+pub(crate) fn arithmetic(opr: &str, left: &Value, right: &Value) -> Result<Value, EvalErrorKind> {
+    match (as_number(left), as_number(right)) {
+        (Some(Number::Int(a)), Some(Number::Int(b))) => int_arithmetic(opr, a, b),
+        (Some(a), Some(b)) => float_arithmetic(opr, a.to_f64(), b.to_f64()),
+        _ => Ok(CellError::Value.into()),
+    }
+}
+
+/// Arithmetic on two integers. A result that isn't a whole number or
+/// doesn't fit in an `Int`, like `7 / 2` or `10 ^ 40`, is worked out
+/// with floats instead
+fn int_arithmetic(opr: &str, a: i128, b: i128) -> Result<Value, EvalErrorKind> {
+    let exact = match opr {
+        "+" => a.checked_add(b),
+        "-" => a.checked_sub(b),
+        "*" => a.checked_mul(b),
+        "/" | "%" if b == 0 => return float_arithmetic(opr, a as f64, 0.0),
+        "^" if a == 0 && b <= 0 => return float_arithmetic(opr, 0.0, b as f64),
+        "/" => match a.checked_rem(b) {
+            Some(0) => a.checked_div(b),
+            _ => None,
         },
-        "*" => match (stack.pop(), stack.pop()) {
-            (Some(Value::Int(i1)), Some(Value::Int(i2))) => {
-                stack.push(Value::Int(i1 * i2));
-                Ok(())
-            }
-            (Some(Value::Float(f1)), Some(Value::Float(f2))) => {
-                stack.push(Value::Float(f1 * f2));
-                Ok(())
+        // the result takes the sign of the divisor
+        "%" => a.checked_rem(b).map(|r| {
+            if r != 0 && (r < 0) != (b < 0) {
+                r + b
+            } else {
+                r
             }
-            (i1, i2) => Err(format!(
-                "Failed operator '*' for stack items {:?} and {:?}",
-                i1, i2
-            )),
+        }),
+        "^" if (0..=u32::MAX as i128).contains(&b) => a.checked_pow(b as u32),
+        "^" => None,
+        _ => return Err(EvalErrorKind::UnknownOperator(opr.into())),
+    };
+    match exact {
+        Some(n) => Ok(Value::Int(n)),
+        None => float_arithmetic(opr, a as f64, b as f64),
+    }
+}
+
+/// Arithmetic on two floats. Dividing by zero or raising zero to a
+/// negative power is `#DIV/0!`. `0 ^ 0` and a result that's infinite or
+/// not a number are `#NUM!`
+fn float_arithmetic(opr: &str, a: f64, b: f64) -> Result<Value, EvalErrorKind> {
+    let res = match opr {
+        "+" => a + b,
+        "-" => a - b,
+        "*" => a * b,
+        "/" | "%" if b == 0.0 => return Ok(CellError::DivZero.into()),
+        "^" if a == 0.0 && b < 0.0 => return Ok(CellError::DivZero.into()),
+        "^" if a == 0.0 && b == 0.0 => return Ok(CellError::Num.into()),
+        "/" => a / b,
+        "%" => a - b * (a / b).floor(),
+        "^" => a.powf(b),
+        _ => return Err(EvalErrorKind::UnknownOperator(opr.into())),
+    };
+    Ok(match res.is_finite() {
+        true => Value::Float(res),
+        false => CellError::Num.into(),
+    })
+}
+
+fn as_float(v: &Value) -> Option<f64> {
+    match unwrap_maybe(v) {
+        Value::Int(i) => Some(i as f64),
+        Value::Float(f) => Some(f),
+        Value::Maybe(None) => Some(0.0),
+        _ => None,
+    }
+}
+
+/// Logical operators accept booleans and numbers (non-zero is `true`)
+pub(crate) fn as_bool(v: &Value) -> Option<bool> {
+    match unwrap_maybe(v) {
+        Value::Bool(b) => Some(b),
+        Value::Int(i) => Some(i != 0),
+        Value::Float(f) => Some(f != 0.0),
+        Value::Maybe(None) => Some(false),
+        _ => None,
+    }
+}
+
+/// The text of a value when used with the `&` operator
+fn as_text(v: &Value) -> Option<String> {
+    match unwrap_maybe(v) {
+        Value::Str(s) => Some(s),
+        Value::Int(i) => Some(i.to_string()),
+        Value::Float(f) => Some(f.to_string()),
+        Value::Bool(true) => Some("TRUE".into()),
+        Value::Bool(false) => Some("FALSE".into()),
+        Value::Maybe(None) => Some("".into()),
+        _ => None,
+    }
+}
+
+/// The rank of a type when comparing values of different types:
+/// numbers sort before strings which sort before booleans
+fn type_rank(v: &Value) -> Option<u8> {
+    match v {
+        Value::Int(_) | Value::Float(_) => Some(0),
+        Value::Str(_) => Some(1),
+        Value::Bool(_) => Some(2),
+        _ => None,
+    }
+}
+
+/// Compare two values. Numbers compare numerically (`1 == 1.0`), strings
+/// compare without regard to case, and blanks compare as the blank
+/// value of the other operand's type. Values that have no ordering can
+/// only be compared with `==` and `!=` and are otherwise `#VALUE!`
+fn compare(opr: &str, left: &Value, right: &Value) -> Value {
+    use std::cmp::Ordering;
+
+    let blank_like = |v: &Value| match v {
+        Value::Int(_) | Value::Float(_) => Value::Int(0),
+        Value::Str(_) => Value::Str("".into()),
+        Value::Bool(_) => Value::Bool(false),
+        other => other.clone(),
+    };
+
+    let (l, r) = match (unwrap_maybe(left), unwrap_maybe(right)) {
+        (Value::Maybe(None), Value::Maybe(None)) => (Value::Int(0), Value::Int(0)),
+        (Value::Maybe(None), r) => (blank_like(&r), r),
+        (l, Value::Maybe(None)) => (l.clone(), blank_like(&l)),
+        (l, r) => (l, r),
+    };
+
+    let ordering: Option<Ordering> = match (&l, &r) {
+        (Value::Int(a), Value::Int(b)) => Some(a.cmp(b)),
+        (Value::Str(a), Value::Str(b)) => Some(a.to_lowercase().cmp(&b.to_lowercase())),
+        (Value::Bool(a), Value::Bool(b)) => Some(a.cmp(b)),
+        (Value::Date(a), Value::Date(b)) => Some(a.cmp(b)),
+        (a, b) => match (as_float(a), as_float(b)) {
+            (Some(x), Some(y)) => x.partial_cmp(&y),
+            _ => match (type_rank(a), type_rank(b)) {
+                (Some(x), Some(y)) => Some(x.cmp(&y)),
+                _ => None,
+            },
         },
-        _ => Err(format!("Could not find operator {}", opr)),
+    };
+
+    let res = match (opr, ordering) {
+        ("==", Some(o)) => o == Ordering::Equal,
+        ("!=", Some(o)) => o != Ordering::Equal,
+        ("<", Some(o)) => o == Ordering::Less,
+        ("<=", Some(o)) => o != Ordering::Greater,
+        (">", Some(o)) => o == Ordering::Greater,
+        (">=", Some(o)) => o != Ordering::Less,
+        ("==", None) => l == r,
+        ("!=", None) => l != r,
+        _ => return CellError::Value.into(),
+    };
+
+    Value::Bool(res)
+}
+
+#[test]
+fn test_operators() {
+    use crate::eval_stack::create_eval_stack;
+    use crate::parser::whole_expr_str;
+    use std::collections::HashMap;
+
+    let arr = |rows: Vec<Vec<i128>>| {
+        Value::Array(
+            rows.into_iter()
+                .map(|r| r.into_iter().map(Value::Int).collect())
+                .collect(),
+        )
+    };
+
+    let tests: Vec<(&str, Value)> = vec![
+        ("1 < 2", Value::Bool(true)),
+        ("2 <= 2", Value::Bool(true)),
+        ("2.5 >= 3", Value::Bool(false)),
+        ("3 > 2.5", Value::Bool(true)),
+        ("1 == 1.0", Value::Bool(true)),
+        ("1 != 2", Value::Bool(true)),
+        ("1 <> 1", Value::Bool(false)),
+        (r#""abc" == "ABC""#, Value::Bool(true)),
+        (r#""abc" < "abd""#, Value::Bool(true)),
+        (r#"1 < "1""#, Value::Bool(true)),
+        (r#""zzz" < true"#, Value::Bool(true)),
+        (r#"1 == "1""#, Value::Bool(false)),
+        ("true && false", Value::Bool(false)),
+        ("true || false", Value::Bool(true)),
+        ("1 && 0", Value::Bool(false)),
+        ("false || 1 > 0", Value::Bool(true)),
+        ("false && true || true", Value::Bool(true)),
+        (r#""a" & "b" & 3"#, Value::Str("ab3".into())),
+        (r#""x" & 1.5 & true"#, Value::Str("x1.5TRUE".into())),
+        (r#""n" & 1 + 2"#, Value::Str("n3".into())),
+        ("10 / 2", Value::Int(5)),
+        ("10 / 4", Value::Float(2.5)),
+        ("10 - 2 - 3", Value::Int(5)),
+        ("1 + 2.5", Value::Float(3.5)),
+        ("2.5 * 2", Value::Float(5.0)),
+        ("1 - true", Value::Int(0)),
+        ("7 % 3", Value::Int(1)),
+        ("-7 % 3", Value::Int(2)),
+        ("7 % (-3)", Value::Int(-2)),
+        ("7.5 % 2", Value::Float(1.5)),
+        ("2 ^ 10", Value::Int(1024)),
+        ("2 ^ 3 ^ 2", Value::Int(64)),
+        ("2 ^ -1", Value::Float(0.5)),
+        ("4 ^ 0.5", Value::Float(2.0)),
+        ("2 * 3 ^ 2", Value::Int(18)),
+        ("10 ^ 40", Value::Float(1e40)),
+        ("-(2 + 3)", Value::Int(-5)),
+        ("- 2 ^ 2", Value::Int(4)),
+        ("-(2 ^ 2)", Value::Int(-4)),
+        ("--3", Value::Int(3)),
+        ("+(1.5)", Value::Float(1.5)),
+        ("-true", Value::Int(-1)),
+        ("!true", Value::Bool(false)),
+        ("!(1 > 2) && true", Value::Bool(true)),
+        ("50%", Value::Float(0.5)),
+        ("50% * 4.0", Value::Float(2.0)),
+        ("50%%", Value::Float(0.005)),
+        ("200%>1.5", Value::Bool(true)),
+        ("7% 4", Value::Int(3)),
+        ("7%-3", Value::Float(0.07 - 3.0)),
+        ("50% + 10", Value::Float(10.5)),
+        ("50%+10", Value::Float(10.5)),
+        ("(50%) - 3", Value::Float(-2.5)),
+        ("{1, 2, 3}", arr(vec![vec![1, 2, 3]])),
+        ("{1, 2; 3, 4} * 10", arr(vec![vec![10, 20], vec![30, 40]])),
+        ("2 ^ {1; 2}", arr(vec![vec![2], vec![4]])),
+        (
+            "{1, 2, 3} + {10; 20}",
+            arr(vec![vec![11, 12, 13], vec![21, 22, 23]]),
+        ),
+        ("{1, 2} * {3, 4}", arr(vec![vec![3, 8]])),
+        ("-{1, -2}", arr(vec![vec![-1, 2]])),
+        (
+            "{1, 2} > 1",
+            Value::Array(vec![vec![Value::Bool(false), Value::Bool(true)]]),
+        ),
+        (
+            r#"{"a"; "b"} & 1"#,
+            Value::Array(vec![
+                vec![Value::Str("a1".into())],
+                vec![Value::Str("b1".into())],
+            ]),
+        ),
+        (
+            "{50, 25}%",
+            Value::Array(vec![vec![Value::Float(0.5), Value::Float(0.25)]]),
+        ),
+    ];
+
+    for (formula, expected) in tests {
+        let ex = whole_expr_str(formula).unwrap();
+        let stack = create_eval_stack(&ex, &HashMap::new()).unwrap();
+        assert_eq!(eval(&stack), Ok(expected), "Evaluating {}", formula);
+    }
+
+    let error = |e: CellError| -> Value { e.into() };
+    for (formula, expected) in [
+        ("{1, 2} + {1, 2, 3}", error(CellError::Value)),
+        ("{1; 2} * {1; 2; 3}", error(CellError::Value)),
+        ("{1, {2}}", error(CellError::Value)),
+        (
+            r#"{1, "a"} ^ 2"#,
+            Value::Array(vec![vec![Value::Int(1), error(CellError::Value)]]),
+        ),
+        (r#""a" && true"#, error(CellError::Value)),
+        ("7 % 0", error(CellError::DivZero)),
+        ("7.5 % 0.0", error(CellError::DivZero)),
+        ("1 / 0", error(CellError::DivZero)),
+        ("1 / (1 - 1) + 2", error(CellError::DivZero)),
+        (r#"1 - "a""#, error(CellError::Value)),
+        (r#""a" ^ 2"#, error(CellError::Value)),
+        (r#"-"a""#, error(CellError::Value)),
+        (r#"!"a""#, error(CellError::Value)),
+        ("10.0 ^ 400", error(CellError::Num)),
+        ("(-8) ^ 0.5", error(CellError::Num)),
+        ("0 ^ -1", error(CellError::DivZero)),
+        ("0.0 ^ -0.5", error(CellError::DivZero)),
+        ("0 ^ 0", error(CellError::Num)),
+        ("0.0 ^ 0", error(CellError::Num)),
+        ("0 ^ 2", Value::Int(0)),
+        ("rate * 2", error(CellError::Name)),
+        // the first error wins
+        ("1 / 0 + missing", error(CellError::DivZero)),
+        (r#"1 / 0 < "a""#, error(CellError::DivZero)),
+        (r#"1 / 0 & "a""#, error(CellError::DivZero)),
+    ] {
+        let ex = whole_expr_str(formula).unwrap();
+        let stack = create_eval_stack(&ex, &HashMap::new()).unwrap();
+        assert_eq!(eval(&stack), Ok(expected), "Evaluating {}", formula);
+    }
+}
+
+#[test]
+fn test_arithmetic() {
+    use std::sync::Arc;
+
+    let run = |opr: &str, left: &Value, right: &Value| {
+        let mut stack = vec![left.clone(), right.clone()];
+        perform_opr(opr, &mut stack).map(|_| stack.pop().unwrap_or(Value::Maybe(None)))
+    };
+
+    let int = |i: i128| Some(Value::Int(i));
+    let float = |f: f64| Some(Value::Float(f));
+    let div0: Option<Value> = Some(CellError::DivZero.into());
+    let num: Option<Value> = Some(CellError::Num.into());
+    let six = Value::Int(6);
+    let half = Value::Float(1.5);
+    let yes = Value::Bool(true);
+    let blank = Value::Maybe(None);
+    let minus = Value::Int(-1);
+    let zero = Value::Float(0.0);
+
+    // the results of + - * / % ^ for every pairing of numeric values
+    let operators = ["+", "-", "*", "/", "%", "^"];
+    let table: Vec<(&Value, &Value, [Option<Value>; 6])> = vec![
+        (
+            &six,
+            &six,
+            [int(12), int(0), int(36), int(1), int(0), int(46656)],
+        ),
+        (
+            &six,
+            &half,
+            [
+                float(7.5),
+                float(4.5),
+                float(9.0),
+                float(4.0),
+                float(0.0),
+                float(6f64.powf(1.5)),
+            ],
+        ),
+        (&six, &yes, [int(7), int(5), int(6), int(6), int(0), int(6)]),
+        (
+            &six,
+            &blank,
+            [int(6), int(6), int(0), div0.clone(), div0.clone(), int(1)],
+        ),
+        (
+            &half,
+            &six,
+            [
+                float(7.5),
+                float(-4.5),
+                float(9.0),
+                float(0.25),
+                float(1.5),
+                float(11.390625),
+            ],
+        ),
+        (
+            &half,
+            &half,
+            [
+                float(3.0),
+                float(0.0),
+                float(2.25),
+                float(1.0),
+                float(0.0),
+                float(1.5f64.powf(1.5)),
+            ],
+        ),
+        (
+            &half,
+            &yes,
+            [
+                float(2.5),
+                float(0.5),
+                float(1.5),
+                float(1.5),
+                float(0.5),
+                float(1.5),
+            ],
+        ),
+        (
+            &half,
+            &blank,
+            [
+                float(1.5),
+                float(1.5),
+                float(0.0),
+                div0.clone(),
+                div0.clone(),
+                float(1.0),
+            ],
+        ),
+        (
+            &yes,
+            &six,
+            [int(7), int(-5), int(6), float(1.0 / 6.0), int(1), int(1)],
+        ),
+        (
+            &yes,
+            &half,
+            [
+                float(2.5),
+                float(-0.5),
+                float(1.5),
+                float(1.0 / 1.5),
+                float(1.0),
+                float(1.0),
+            ],
+        ),
+        (&yes, &yes, [int(2), int(0), int(1), int(1), int(0), int(1)]),
+        (
+            &yes,
+            &blank,
+            [int(1), int(1), int(0), div0.clone(), div0.clone(), int(1)],
+        ),
+        (
+            &blank,
+            &six,
+            [int(6), int(-6), int(0), int(0), int(0), int(0)],
+        ),
+        (
+            &blank,
+            &half,
+            [
+                float(1.5),
+                float(-1.5),
+                float(0.0),
+                float(0.0),
+                float(0.0),
+                float(0.0),
+            ],
+        ),
+        (
+            &blank,
+            &yes,
+            [int(1), int(-1), int(0), int(0), int(0), int(0)],
+        ),
+        (
+            &blank,
+            &blank,
+            [
+                int(0),
+                int(0),
+                int(0),
+                div0.clone(),
+                div0.clone(),
+                num.clone(),
+            ],
+        ),
+        (
+            &blank,
+            &minus,
+            [int(-1), int(1), int(0), int(0), int(0), div0.clone()],
+        ),
+        (
+            &zero,
+            &zero,
+            [
+                float(0.0),
+                float(0.0),
+                float(0.0),
+                div0.clone(),
+                div0.clone(),
+                num.clone(),
+            ],
+        ),
+    ];
+    for (left, right, results) in &table {
+        for (opr, expected) in operators.iter().zip(results) {
+            assert_eq!(
+                run(opr, left, right).ok(),
+                *expected,
+                "{:?} {} {:?}",
+                left,
+                opr,
+                right
+            );
+            // a cell holding a value works the same as the value
+            let cell = Value::Maybe(Some(Arc::new((*left).clone())));
+            assert_eq!(run(opr, &cell, right).ok(), *expected);
+        }
+    }
+
+    let text = Value::Str("6".into());
+    let error = Value::Error(("#N/A".into(), 0));
+    for opr in operators {
+        for v in [&six, &half, &yes, &blank, &text] {
+            // text isn't a number
+            assert_eq!(run(opr, v, &text), Ok(CellError::Value.into()));
+            assert_eq!(run(opr, &text, v), Ok(CellError::Value.into()));
+            // an error operand is the result
+            assert_eq!(run(opr, v, &error), Ok(error.clone()));
+            assert_eq!(run(opr, &error, v), Ok(error.clone()));
+        }
+        // arrays with rows of different lengths can't be lined up
+        let ragged = Value::Array(vec![vec![six.clone(), half.clone()], vec![six.clone()]]);
+        assert_eq!(run(opr, &ragged, &yes), Ok(CellError::Value.into()));
+        assert_eq!(run(opr, &six, &ragged), Ok(CellError::Value.into()));
+        // arrays are done item by item
+        let arr = Value::Array(vec![vec![six.clone(), half.clone()]]);
+        assert_eq!(
+            run(opr, &arr, &yes),
+            Ok(Value::Array(vec![vec![
+                run(opr, &six, &yes).unwrap(),
+                run(opr, &half, &yes).unwrap()
+            ]]))
+        );
     }
+
+    // integers that don't give an exact integer are promoted
+    assert_eq!(
+        run("/", &Value::Int(7), &Value::Int(2)),
+        Ok(Value::Float(3.5))
+    );
+    assert_eq!(
+        run("*", &Value::Int(i128::MAX), &Value::Int(2)),
+        Ok(Value::Float(i128::MAX as f64 * 2.0))
+    );
+    assert_eq!(
+        run("-", &Value::Int(i128::MIN), &Value::Int(1)),
+        Ok(Value::Float(i128::MIN as f64 - 1.0))
+    );
+}
+
+#[test]
+fn test_cell_errors() {
+    use crate::eval_stack::create_eval_stack;
+    use crate::parser::whole_expr_str;
+    use crate::rewrite::copy_formula;
+    use std::collections::HashMap;
+
+    for e in CellError::ALL {
+        assert_eq!(CellError::from_code(e.code()), Some(e));
+        assert_eq!(CellError::from_text(e.text()), Some(e));
+        assert_eq!(Value::from(e).cell_error(), Some(e));
+    }
+    assert_eq!(CellError::from_text("#div/0!"), Some(CellError::DivZero));
+    assert_eq!(CellError::from_text("#OOPS!"), None);
+    assert_eq!(
+        Value::Error(("#REF!".into(), 4)).cell_error(),
+        Some(CellError::Ref)
+    );
+
+    // a reference copied off the sheet is `#REF!` and so is the formula
+    let copied = copy_formula(
+        &whole_expr_str("A1 + 1").unwrap(),
+        &"B2".parse().unwrap(),
+        &"A1".parse().unwrap(),
+    );
+    let stack = create_eval_stack(&copied, &HashMap::new()).unwrap();
+    assert_eq!(stack[0], EvalStack::PushError(CellError::Ref));
+    assert_eq!(eval(&stack), Ok(CellError::Ref.into()));
+}
+
+#[test]
+fn test_eval_errors() {
+    use crate::eval_stack::create_eval_stack_mapped;
+    use crate::parser::whole_expr_str;
+    use std::collections::HashMap;
+
+    assert_eq!(
+        eval(&[EvalStack::PushInt(1), EvalStack::PerformOpr("+".into())]),
+        Err(EvalError {
+            instruction: 1,
+            kind: EvalErrorKind::StackUnderflow {
+                operator: "+".into(),
+                needed: 2,
+                found: vec![Types::Int],
+            },
+        })
+    );
+    assert_eq!(
+        eval(&[EvalStack::PerformPrefix("-".into())]).map_err(|e| e.kind),
+        Err(EvalErrorKind::StackUnderflow {
+            operator: "-".into(),
+            needed: 1,
+            found: vec![],
+        })
+    );
+    assert_eq!(
+        eval(&[EvalStack::PushStr("a".into()), EvalStack::MakeArray(1, 2)]).map_err(|e| e.kind),
+        Err(EvalErrorKind::StackUnderflow {
+            operator: "{}".into(),
+            needed: 2,
+            found: vec![Types::Str],
+        })
+    );
+    let err = eval(&[
+        EvalStack::PushInt(1),
+        EvalStack::PushFloat(2.0),
+        EvalStack::PerformOpr("<=>".into()),
+    ])
+    .unwrap_err();
+    assert_eq!(err.kind, EvalErrorKind::UnknownOperator("<=>".into()));
+    assert_eq!(err.to_string(), "instruction 2: unknown operator '<=>'");
+    assert_eq!(
+        eval(&[EvalStack::PushInt(1), EvalStack::PushBool(true)]),
+        Err(EvalError {
+            instruction: 2,
+            kind: EvalErrorKind::Unbalanced(vec![Types::Int, Types::Bool]),
+        })
+    );
+    assert_eq!(
+        eval(&[]).map_err(|e| e.kind),
+        Err(EvalErrorKind::Unbalanced(vec![]))
+    );
+
+    // a failed instruction can be traced back to the formula
+    let formula = "1 - (2 * 3)";
+    let (mut stack, map) =
+        create_eval_stack_mapped(&whole_expr_str(formula).unwrap(), &HashMap::new()).unwrap();
+    stack[3] = EvalStack::PerformOpr("?".into());
+    let err = eval(&stack).unwrap_err();
+    let (start, end) = err.position(&map).unwrap().span();
+    assert_eq!(&formula[start..end], "2 * 3");
+}
+
+#[test]
+fn test_sheet_references() {
+    use crate::eval_stack::create_eval_stack;
+    use crate::parser::whole_expr_str;
+
+    let context = EvalContext::with_sheets(&[
+        ("Sheet1", &[("A1", Value::Int(2))]),
+        (
+            "Q3 Sales",
+            &[("B4", Value::Int(40)), ("AA10", Value::Float(0.5))],
+        ),
+    ])
+    .unwrap();
+
+    let run = |formula: &str| {
+        let ex = whole_expr_str(formula).unwrap();
+        let stack = create_eval_stack(&ex, &HashMap::new()).unwrap();
+        eval_in(&stack, &context)
+    };
+
+    assert_eq!(run("A1"), Ok(Value::Int(2)));
+    assert_eq!(run("sheet1!A1 + 1"), Ok(Value::Int(3)));
+    assert_eq!(run("'Q3 Sales'!B4 * A1"), Ok(Value::Int(80)));
+    assert_eq!(run("'q3 sales'!$AA$10"), Ok(Value::Float(0.5)));
+    assert_eq!(run("B7"), Ok(Value::Maybe(None)));
+    assert_eq!(run("Sheet9!A1"), Ok(CellError::Ref.into()));
+    assert_eq!(run("Sheet9!A1:B2 + 1"), Ok(CellError::Ref.into()));
+    assert_eq!(run("Sheet1:Sheet2!A1"), Ok(CellError::Value.into()));
+    assert_eq!(run("A0"), Ok(CellError::Ref.into()));
+    assert_eq!(
+        run("A1:A2"),
+        Ok(Value::Array(vec![
+            vec![Value::Int(2)],
+            vec![Value::Maybe(None)]
+        ]))
+    );
+    assert_eq!(
+        run("{1, 2} * Sheet1!A1:A1"),
+        Ok(Value::Array(vec![vec![Value::Int(2), Value::Int(4)]]))
+    );
+    assert_eq!(
+        eval(&create_eval_stack(&whole_expr_str("A1").unwrap(), &HashMap::new()).unwrap()),
+        Ok(CellError::Ref.into())
+    );
+}
+
+#[test]
+fn test_range_cells() {
+    use crate::eval_stack::create_eval_stack;
+    use crate::parser::{whole_expr_str, Expression};
+
+    let context = EvalContext::with_sheets(&[(
+        "Sheet1",
+        &[
+            ("A1", Value::Int(1)),
+            ("A2", Value::Int(2)),
+            ("A1048576", Value::Int(1_048_576)),
+            ("C3", Value::Int(3)),
+            ("B7", Value::Int(7)),
+        ],
+    )])
+    .unwrap();
+
+    let cells = |formula: &str| -> Vec<String> {
+        match whole_expr_str(formula) {
+            Ok(Expression::Range(range, _)) => range_cells(&range, &context)
+                .unwrap()
+                .iter()
+                .map(|(addr, _)| addr.to_string())
+                .collect(),
+            other => panic!("{} parsed to {:?}", formula, other),
+        }
+    };
+
+    assert_eq!(cells("A:A"), vec!["A1", "A2", "A1048576"]);
+    assert_eq!(cells("A2:A"), vec!["A2", "A1048576"]);
+    assert_eq!(cells("B:C"), vec!["C3", "B7"]);
+    assert_eq!(cells("2:3"), vec!["A2", "C3"]);
+    assert_eq!(cells("C3:A1"), vec!["A1", "A2", "C3"]);
+    assert_eq!(cells("Sheet1!$A$1:$B$7"), vec!["A1", "A2", "B7"]);
+
+    // a whole column stops at its last populated cell
+    match whole_expr_str("B:C") {
+        Ok(Expression::Range(range, _)) => {
+            let blank = || Value::Maybe(None);
+            let mut expected = vec![vec![blank(), blank()]; 7];
+            expected[2][1] = Value::Int(3);
+            expected[6][0] = Value::Int(7);
+            assert_eq!(range_value(&range, &context), Value::Array(expected));
+        }
+        other => panic!("B:C parsed to {:?}", other),
+    }
+
+    // a far away cell makes a big array but not one too big to make
+    let value = |formula: &str| match whole_expr_str(formula) {
+        Ok(Expression::Range(range, _)) => range_value(&range, &context),
+        other => panic!("{} parsed to {:?}", formula, other),
+    };
+    match value("A:A") {
+        Value::Array(rows) => {
+            assert_eq!(rows.len(), 1_048_576);
+            assert_eq!(rows[1_048_575], vec![Value::Int(1_048_576)]);
+        }
+        other => panic!("A:A is {:?}", other),
+    }
+    assert!(value("A:E") == CellError::Num.into());
+    assert!(value("A1:XFD1048576") == CellError::Num.into());
+
+    // a function that skips blanks only gets the populated cells, so
+    // ranges too big to be an array can still be added up
+    let eval = |formula: &str| {
+        let stack = create_eval_stack(&whole_expr_str(formula).unwrap(), &HashMap::new()).unwrap();
+        eval_in(&stack, &context)
+    };
+    assert_eq!(eval("SUM(A:E)"), Ok(Value::Int(1_048_576 + 13)));
+    assert_eq!(eval("COUNT(A1:XFD1048576, 1)"), Ok(Value::Int(6)));
+    assert_eq!(eval("MAX(B:C) + AVERAGE(2:3)"), Ok(Value::Float(9.5)));
+    assert_eq!(eval("SUM(A:E + 1)"), Ok(CellError::Num.into()));
+    assert!(eval("INDEX(A:E, 1, 1)") == Ok(CellError::Num.into()));
+    assert_eq!(
+        range_arguments(
+            &create_eval_stack(
+                &whole_expr_str("SUM(A:A, {1, 2}, -B:B) + C:C").unwrap(),
+                &HashMap::new()
+            )
+            .unwrap()
+        ),
+        HashMap::from([(0, "SUM")])
+    );
 }
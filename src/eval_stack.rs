@@ -1,12 +1,33 @@
-use crate::parser::Expression;
+use crate::definitions::{CellError, Value};
+use crate::diagnostic::ParseDiagnostic;
+use crate::parser::{Address, Expression, Notation, ParseInfo, Range};
+use crate::printer::print;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::fmt;
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub enum EvalStack {
     PushInt(i128),
     PushFloat(f64),
     PushStr(String),
+    PushBool(bool),
+    PushAddress(Address),
+    PushRange(Range),
+    /// Pop `rows * cols` values, pushed row by row, and push them as an
+    /// array
+    MakeArray(usize, usize),
     PerformOpr(String),
+    /// `PerformOpr` for an arithmetic operator whose operands were both
+    /// inferred to be `Int`s. Operands that turn out not to be, like an
+    /// `Int` that overflowed to a `Float`, take the general path
+    PerformIntOpr(String),
+    PerformPrefix(String),
+    PerformPostfix(String),
+    PushError(CellError),
+    /// Pop the given number of parameters, pushed in order, and push the
+    /// result of calling the function with them and the decorators
+    CallFunction(String, usize, Vec<String>),
 }
 
 pub enum BuilderParams {
@@ -15,69 +36,374 @@ pub enum BuilderParams {
     OtherMap(HashMap<String, String>), // an "Other" expressed as a map
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Hash)]
+/// The type of a value as far as it can be known before evaluation
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
 pub enum Types {
     Int,
     Float,
     Str,
+    Bool,
+    /// An `Int` or a `Float`
+    Number,
+    /// An array of values, including the values in a range
+    Array,
+    /// An error value, like `#REF!`, which operators pass on
+    Error,
+    /// Not known until evaluation, like the value in a cell
+    Any,
 }
 
+impl Types {
+    /// The types arithmetic can be done on
+    pub const NUMERIC: &'static [Types] = &[Types::Int, Types::Float, Types::Number, Types::Bool];
+    /// The types that can be `true` or `false`
+    pub const LOGICAL: &'static [Types] = &[Types::Bool, Types::Int, Types::Float, Types::Number];
+    /// Any single value
+    pub const SCALAR: &'static [Types] = &[
+        Types::Int,
+        Types::Float,
+        Types::Number,
+        Types::Bool,
+        Types::Str,
+    ];
+    /// Numbers and arrays of them
+    pub const NUMBERS: &'static [Types] = &[
+        Types::Int,
+        Types::Float,
+        Types::Number,
+        Types::Bool,
+        Types::Array,
+    ];
+    pub const ANYTHING: &'static [Types] = &[
+        Types::Int,
+        Types::Float,
+        Types::Number,
+        Types::Bool,
+        Types::Str,
+        Types::Array,
+    ];
+
+    /// The type of a value. A value that doesn't have one of these types,
+    /// like a blank, is `Any`
+    pub fn of(v: &Value) -> Types {
+        match v {
+            Value::Int(_) => Types::Int,
+            Value::Float(_) => Types::Float,
+            Value::Str(_) => Types::Str,
+            Value::Bool(_) => Types::Bool,
+            Value::Array(_) => Types::Array,
+            Value::Error(_) => Types::Error,
+            Value::Maybe(Some(inner)) => Types::of(inner),
+            _ => Types::Any,
+        }
+    }
+}
+
+/// How the type of the result of an operator or function follows from
+/// the types of its parameters
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum ResultType {
+    /// Always this type
+    Is(Types),
+    /// `Float` if any parameter is a `Float`, `Int` if every parameter
+    /// is an `Int` or `Bool`, otherwise `Number`. An `Int` result too big
+    /// for an `Int` is a `Float` when evaluated
+    Arithmetic,
+    /// `Float` if any parameter is a `Float`, otherwise `Number`: a
+    /// result from integers isn't always an integer
+    Division,
+    /// The type the parameters from this one on have in common, or
+    /// `Any` if they're different
+    Common(u16),
+}
+
+/// The signature of an operator or function
 #[derive(Debug, PartialEq, Eq, Clone)]
-struct OperatorInfo {
-    operator: String,
-    num_type_param: u16,
-    type_param_type: HashMap<u16, Types>,
-    num_params: u16,
-    param_type: HashMap<u16, Vec<Types>>,
+pub struct OperatorInfo {
+    pub operator: String,
+    pub num_type_param: u16,
+    pub type_param_type: HashMap<u16, Types>,
+    /// The number of parameters that must be given
+    pub num_params: u16,
+    /// The most parameters that can be given or `None` for any number
+    pub max_params: Option<u16>,
+    /// The types each parameter can have. A parameter past the last one
+    /// listed can have the same types as the last one. A parameter of
+    /// type `Any` is always allowed
+    pub param_type: HashMap<u16, Vec<Types>>,
+    pub result: ResultType,
+}
+
+impl OperatorInfo {
+    /// A signature without type parameters. `params` are the types of
+    /// each parameter, the last of which goes for any after it
+    pub fn new(
+        operator: &str,
+        num_params: u16,
+        max_params: Option<u16>,
+        params: &[&[Types]],
+        result: ResultType,
+    ) -> OperatorInfo {
+        OperatorInfo {
+            operator: operator.to_string(),
+            num_type_param: 0,
+            type_param_type: HashMap::new(),
+            num_params,
+            max_params,
+            param_type: params
+                .iter()
+                .enumerate()
+                .map(|(i, types)| (i as u16, types.to_vec()))
+                .collect(),
+            result,
+        }
+    }
+
+    /// Whether `count` parameters is the right number
+    pub fn takes(&self, count: usize) -> bool {
+        count >= self.num_params as usize && self.max_params.is_none_or(|max| count <= max as usize)
+    }
+
+    /// The types parameter `pos` (counting from 0) can have
+    pub fn param_types(&self, pos: u16) -> &[Types] {
+        (0..=pos)
+            .rev()
+            .find_map(|p| self.param_type.get(&p))
+            .map(|v| v.as_slice())
+            .unwrap_or(&[])
+    }
+}
+
+/// Why a formula couldn't be turned into instructions
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum BuildError {
+    /// An expression the evaluator can't do yet. `what` says what it is,
+    /// like `let` or the name of a function
+    Unsupported { what: String, position: ParseInfo },
+    /// Text in a partial parse that couldn't be parsed
+    Unparsed { text: String, position: ParseInfo },
+    /// A part of the formula that's missing from a partial parse and a
+    /// description of what was expected
+    Incomplete {
+        expected: String,
+        position: ParseInfo,
+    },
+}
+
+impl BuildError {
+    /// The expression that couldn't be built
+    pub fn position(&self) -> &ParseInfo {
+        match self {
+            BuildError::Unsupported { position, .. }
+            | BuildError::Unparsed { position, .. }
+            | BuildError::Incomplete { position, .. } => position,
+        }
+    }
+
+    /// The error as a diagnostic with a line and column in `formula`
+    pub fn diagnostic(&self, formula: &str) -> ParseDiagnostic {
+        let start = self
+            .position()
+            .as_ref()
+            .map(|info| info.span().0)
+            .unwrap_or(0);
+        ParseDiagnostic::problem(formula, start, self.to_string())
+    }
 }
 
-type BuildResult = Result<Vec<EvalStack>, String>;
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BuildError::Unsupported { what, .. } => write!(f, "{} can't be evaluated yet", what),
+            BuildError::Unparsed { text, .. } => write!(f, "'{}' couldn't be parsed", text),
+            BuildError::Incomplete { expected, .. } => {
+                write!(f, "the formula is incomplete: expected {}", expected)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+type BuildResult = Result<Vec<EvalStack>, BuildError>;
 
+/// Where each instruction came from: the position of the expression it
+/// was built for, by instruction index
+pub type SourceMap = Vec<ParseInfo>;
+
+/// Set `specialize` to `BuilderParams::Choice(false)` to build only
+/// general instructions rather than ones picked for the inferred types
 pub fn create_eval_stack(
     expr: &Expression,
     params: &HashMap<String, BuilderParams>,
 ) -> BuildResult {
-    let mut to_populate: Vec<EvalStack> = vec![];
-
-    match do_create_eval_stack(expr, params, &mut to_populate) {
-        Ok(_) => Ok(to_populate),
-        Err(bad) => Err(bad),
-    }
+    let mut to_populate = Built::new(expr, params);
+    do_create_eval_stack(expr, &mut to_populate)?;
+    Ok(to_populate.instructions)
 }
 
-fn do_create_eval_stack(
+/// Build the instructions along with where each came from, so a failed
+/// evaluation can be traced back to the formula
+pub fn create_eval_stack_mapped(
     expr: &Expression,
     params: &HashMap<String, BuilderParams>,
-    to_populate: &mut Vec<EvalStack>,
-) -> Result<(), String> {
+) -> Result<(Vec<EvalStack>, SourceMap), BuildError> {
+    let mut to_populate = Built {
+        positions: Some(vec![]),
+        ..Built::new(expr, params)
+    };
+    do_create_eval_stack(expr, &mut to_populate)?;
+    Ok((
+        to_populate.instructions,
+        to_populate.positions.unwrap_or_default(),
+    ))
+}
+
+/// The instructions built so far and, when they're wanted, the positions
+/// of the expressions they're for
+struct Built {
+    instructions: Vec<EvalStack>,
+    positions: Option<SourceMap>,
+    /// The inferred type of each expression, parents before children,
+    /// and the number of expressions reached so far
+    types: Option<Vec<Types>>,
+    next: usize,
+}
+
+impl Built {
+    fn new(expr: &Expression, params: &HashMap<String, BuilderParams>) -> Built {
+        let specialize = !matches!(params.get("specialize"), Some(BuilderParams::Choice(false)));
+        Built {
+            instructions: vec![],
+            positions: None,
+            types: specialize.then(|| crate::typecheck::check(expr).types),
+            next: 0,
+        }
+    }
+
+    /// Number the next expression, in the order `typecheck` does
+    fn reach(&mut self) {
+        self.next += 1;
+    }
+
+    /// Skip over an expression that isn't built, and everything in it
+    fn skip(&mut self, expr: &Expression) {
+        self.reach();
+        for e in expr.children() {
+            self.skip(e);
+        }
+    }
+
+    fn type_of(&self, index: usize) -> Types {
+        match &self.types {
+            Some(types) => types.get(index).copied().unwrap_or(Types::Any),
+            None => Types::Any,
+        }
+    }
+
+    fn push(&mut self, instruction: EvalStack, expr: &Expression) {
+        self.instructions.push(instruction);
+        if let Some(positions) = &mut self.positions {
+            positions.push(expr.parse_info().clone());
+        }
+    }
+}
+
+fn do_create_eval_stack(expr: &Expression, to_populate: &mut Built) -> Result<(), BuildError> {
+    to_populate.reach();
+    let unsupported = |what: &str| BuildError::Unsupported {
+        what: what.to_string(),
+        position: expr.parse_info().clone(),
+    };
+
     match expr {
-        Expression::Int(i, _) => to_populate.push(EvalStack::PushInt(*i)),
-        Expression::Float(f, _) => to_populate.push(EvalStack::PushFloat(*f)),
-        Expression::Str(string, _) => to_populate.push(EvalStack::PushStr(string.clone())),
-
-        // DottedIdentifier(Vec<String>, ParseInfo),
-        // Identifier(String, ParseInfo),
-        Expression::Paren(expr, _) => match do_create_eval_stack(expr, params, to_populate) {
-            Ok(_) => (),
-            Err(e) => return Err(e),
-        },
-        // Address(Address, ParseInfo),
-        // Range(Range, ParseInfo),
-        // Function(String, Vec<Expression>, Vec<Expression>, ParseInfo),
-        Expression::Infix(opr, left, right, _) => {
-            match do_create_eval_stack(left, params, to_populate)
-                .and_then(|_| do_create_eval_stack(right, params, to_populate))
-                .and_then(|_| {
-                    to_populate.push(EvalStack::PerformOpr(opr.clone()));
-                    Ok(())
-                }) {
-                Ok(_) => (),
-                Err(e) => return Err(e),
-            }
+        Expression::Int(i, _) => to_populate.push(EvalStack::PushInt(*i), expr),
+        Expression::Float(f, _) => to_populate.push(EvalStack::PushFloat(*f), expr),
+        Expression::Str(string, _) => to_populate.push(EvalStack::PushStr(string.clone()), expr),
+
+        Expression::Identifier(id, _) if id == "TRUE" => {
+            to_populate.push(EvalStack::PushBool(true), expr)
+        }
+        Expression::Identifier(id, _) if id == "FALSE" => {
+            to_populate.push(EvalStack::PushBool(false), expr)
         }
 
-        // Let(String, Box<Expression>, Box<Expression>, ParseInfo),
-        _ => return Err(format!("Failed {:?}", expr)),
+        // names aren't defined until `let` is evaluated
+        Expression::Identifier(..) | Expression::DottedIdentifier(..) => {
+            to_populate.push(EvalStack::PushError(CellError::Name), expr)
+        }
+        // an error written in the formula, like a `#REF!` from a rewrite
+        Expression::ErrorValue(e, _) => to_populate.push(EvalStack::PushError(*e), expr),
+        Expression::Error(text, info) => {
+            return Err(BuildError::Unparsed {
+                text: text.clone(),
+                position: info.clone(),
+            })
+        }
+        Expression::Missing(expected, info) => {
+            return Err(BuildError::Incomplete {
+                expected: expected.clone(),
+                position: info.clone(),
+            })
+        }
+        Expression::Paren(inner, _) => do_create_eval_stack(inner, to_populate)?,
+        // an R1C1 address is relative to the cell the formula is in, which
+        // isn't known here, so it has to be converted with `r1c1::to_a1`
+        Expression::Address(addr, _) if addr.notation == Notation::R1C1 => {
+            return Err(unsupported(&format!("the R1C1 reference {}", addr.addr)))
+        }
+        Expression::Range(range, _) if range.upper_left.notation == Notation::R1C1 => {
+            return Err(unsupported(&format!(
+                "the R1C1 reference {}:{}",
+                range.upper_left.addr, range.lower_right.addr
+            )))
+        }
+        Expression::Address(addr, _) => {
+            to_populate.push(EvalStack::PushAddress(addr.clone()), expr)
+        }
+        Expression::Range(range, _) => to_populate.push(EvalStack::PushRange(range.clone()), expr),
+        Expression::Function(name, decorators, args, _) => {
+            for e in decorators {
+                to_populate.skip(e);
+            }
+            for e in args {
+                do_create_eval_stack(e, to_populate)?;
+            }
+            let decorators = decorators.iter().map(print).collect();
+            to_populate.push(
+                EvalStack::CallFunction(name.clone(), args.len(), decorators),
+                expr,
+            );
+        }
+        Expression::Array(rows, _) => {
+            for e in rows.iter().flatten() {
+                do_create_eval_stack(e, to_populate)?;
+            }
+            let cols = rows.first().map(|r| r.len()).unwrap_or(0);
+            to_populate.push(EvalStack::MakeArray(rows.len(), cols), expr);
+        }
+        Expression::Infix(opr, left, right, _) => {
+            let left_type = to_populate.type_of(to_populate.next);
+            do_create_eval_stack(left, to_populate)?;
+            let right_type = to_populate.type_of(to_populate.next);
+            do_create_eval_stack(right, to_populate)?;
+            let instruction = match (opr.as_str(), left_type, right_type) {
+                ("+" | "-" | "*" | "/" | "%" | "^", Types::Int, Types::Int) => {
+                    EvalStack::PerformIntOpr(opr.clone())
+                }
+                _ => EvalStack::PerformOpr(opr.clone()),
+            };
+            to_populate.push(instruction, expr);
+        }
+        Expression::Prefix(opr, operand, _) => {
+            do_create_eval_stack(operand, to_populate)?;
+            to_populate.push(EvalStack::PerformPrefix(opr.clone()), expr);
+        }
+        Expression::Postfix(opr, operand, _) => {
+            do_create_eval_stack(operand, to_populate)?;
+            to_populate.push(EvalStack::PerformPostfix(opr.clone()), expr);
+        }
+        Expression::Let(..) => return Err(unsupported("let")),
     }
 
     Ok(())
@@ -94,3 +420,105 @@ fn test_create_stack() {
     let computed = eval(&res.unwrap());
     assert_eq!(computed, Ok(Value::Int(42)))
 }
+
+#[test]
+fn test_build_errors() {
+    use crate::parser::{partial_expr_str, whole_expr_str, whole_r1c1_expr_str};
+
+    let build =
+        |formula: &str| create_eval_stack(&whole_expr_str(formula).unwrap(), &HashMap::new());
+
+    let formula = "1 + (let x = 2; x)";
+    let err = build(formula).unwrap_err();
+    assert!(matches!(&err, BuildError::Unsupported { what, .. } if what == "let"));
+    assert_eq!(err.to_string(), "let can't be evaluated yet");
+    assert_eq!(
+        err.diagnostic(formula).to_string(),
+        "1:6: let can't be evaluated yet"
+    );
+
+    let err = build("2 * (let x = 1; x)").unwrap_err();
+    assert_eq!(
+        err.position().as_ref().map(|info| info.span()),
+        Some((5, 17))
+    );
+
+    assert_eq!(
+        build("sum(1, A1) + nope[fast, Q]()"),
+        Ok(vec![
+            EvalStack::PushInt(1),
+            EvalStack::PushAddress(Address {
+                sheet: None,
+                addr: "A1".into(),
+                notation: Notation::A1,
+            }),
+            EvalStack::CallFunction("SUM".into(), 2, vec![]),
+            EvalStack::CallFunction("NOPE".into(), 0, vec!["FAST".into(), "Q".into()]),
+            EvalStack::PerformOpr("+".into()),
+        ])
+    );
+
+    let r1c1 = whole_r1c1_expr_str("R[-1]C + SUM(R1C1:RC[2])").unwrap();
+    assert_eq!(
+        create_eval_stack(&r1c1, &HashMap::new())
+            .unwrap_err()
+            .to_string(),
+        "the R1C1 reference R[-1]C can't be evaluated yet"
+    );
+    let r1c1 = whole_r1c1_expr_str("SUM(R1C1:RC[2])").unwrap();
+    assert_eq!(
+        create_eval_stack(&r1c1, &HashMap::new())
+            .unwrap_err()
+            .to_string(),
+        "the R1C1 reference R1C1:RC[2] can't be evaluated yet"
+    );
+
+    // operands inferred to be integers get the integer instructions,
+    // unless that's turned off
+    let formula = whole_expr_str("1 + 2 * A1 - (3 ^ 4 + {1}) + 2 * -TRUE").unwrap();
+    let oprs = |params: &HashMap<String, BuilderParams>| {
+        create_eval_stack(&formula, params)
+            .unwrap()
+            .into_iter()
+            .filter_map(|i| match i {
+                EvalStack::PerformOpr(opr) => Some(opr),
+                EvalStack::PerformIntOpr(opr) => Some(format!("int {}", opr)),
+                _ => None,
+            })
+            .collect::<Vec<String>>()
+    };
+    assert_eq!(
+        oprs(&HashMap::new()),
+        vec!["*", "+", "int ^", "+", "-", "int *", "+"]
+    );
+    let general = HashMap::from([("specialize".to_string(), BuilderParams::Choice(false))]);
+    assert_eq!(oprs(&general), vec!["*", "+", "^", "+", "-", "*", "+"]);
+    let ex =
+        whole_expr_str("SUM[FAST](1 + 2) + 170141183460469231731687303715884105727 * 2").unwrap();
+    let stack = create_eval_stack(&ex, &HashMap::new()).unwrap();
+    assert!(stack.contains(&EvalStack::PerformIntOpr("+".into())));
+    assert_eq!(
+        crate::eval::eval(&stack[stack.len() - 4..stack.len() - 1]),
+        Ok(Value::Float(
+            2.0 * 170141183460469231731687303715884105727.0
+        ))
+    );
+
+    let partial = partial_expr_str("1 +");
+    let err = create_eval_stack(&partial.expr, &HashMap::new()).unwrap_err();
+    assert!(matches!(err, BuildError::Incomplete { .. }));
+
+    // each instruction knows the expression it's for
+    let formula = "1 + {2, 3}";
+    let (stack, map) =
+        create_eval_stack_mapped(&whole_expr_str(formula).unwrap(), &HashMap::new()).unwrap();
+    assert_eq!(stack.len(), map.len());
+    let spans: Vec<&str> = map
+        .iter()
+        .map(|info| {
+            let (start, end) = info.as_ref().unwrap().span();
+            &formula[start..end]
+        })
+        .collect();
+    assert_eq!(spans, vec!["1", "2", "3", "{2, 3}", "1 + {2, 3}"]);
+}
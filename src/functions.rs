@@ -0,0 +1,933 @@
+//! The functions that can be called from formulas
+//!
+
+// Copyright 2021 David Pollak
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::definitions::{CellError, Value};
+use crate::eval::{arithmetic, as_bool, as_number, dimensions, unwrap_maybe, Number};
+use crate::eval_stack::{OperatorInfo, ResultType, Types};
+use crate::parser::{Expression, ParseInfo};
+use crate::visit::Visitor;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::fmt;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::Arc;
+
+/// A function that can be called from a formula. Applications add their
+/// own with `Registry::register` or `EvalContext::register`
+pub trait FormulaFunction: Send + Sync {
+    /// The name formulas call it by. Names don't depend on case
+    fn name(&self) -> &str;
+
+    /// How many parameters it takes, of what types and the type of its
+    /// result. Type checking uses this and the number of parameters is
+    /// checked against it before `call`. The operator is the name, in
+    /// any case. It's read once, when the function is registered
+    fn signature(&self) -> OperatorInfo;
+
+    /// Work out the result from the values of the parameters and the
+    /// decorators it was called with, like `FAST` in `LOOKUP[FAST](...)`.
+    /// Problems with the parameters are error values. A panic is caught
+    /// and the result is `#VALUE!`
+    fn call(&self, params: &[Value], decorators: &[String]) -> Value;
+
+    /// Whether the result can change when nothing it's given changes,
+    /// like the current time, so it has to be worked out every time
+    fn volatile(&self) -> bool {
+        false
+    }
+
+    /// Whether blanks in an array parameter are skipped. A range given
+    /// straight to such a function is passed as a one row array of just
+    /// its populated cells, so a whole column doesn't fill memory
+    fn skips_blanks(&self) -> bool {
+        false
+    }
+
+    /// The decorators it can be called with
+    fn decorators(&self) -> Vec<String> {
+        vec![]
+    }
+
+    /// What the function does, for help and autocomplete
+    fn doc(&self) -> String {
+        String::new()
+    }
+}
+
+/// The built-in functions that look at the numbers or logicals in an
+/// array and skip everything else
+const SKIPS_BLANKS: &[&str] = &["SUM", "AVERAGE", "MIN", "MAX", "COUNT", "AND", "OR"];
+
+/// A function that comes with Mesa X
+#[derive(Clone, Debug)]
+pub struct BuiltIn {
+    pub signature: OperatorInfo,
+    pub doc: &'static str,
+    pub call: fn(&[Value]) -> Value,
+}
+
+impl FormulaFunction for BuiltIn {
+    fn name(&self) -> &str {
+        &self.signature.operator
+    }
+
+    fn signature(&self) -> OperatorInfo {
+        self.signature.clone()
+    }
+
+    fn call(&self, params: &[Value], _decorators: &[String]) -> Value {
+        (self.call)(params)
+    }
+
+    fn skips_blanks(&self) -> bool {
+        SKIPS_BLANKS.contains(&self.signature.operator.as_str())
+    }
+
+    fn doc(&self) -> String {
+        self.doc.to_string()
+    }
+}
+
+/// What an editor needs to offer a function while a formula is typed
+#[derive(Debug, PartialEq, Eq, Clone, Hash)]
+pub struct FunctionInfo {
+    pub name: String,
+    /// How it's called, like `ROUND(number, [number])`
+    pub usage: String,
+    pub doc: String,
+    pub decorators: Vec<String>,
+    pub volatile: bool,
+}
+
+/// The functions that can be called, by upper case name, along with
+/// their signatures
+#[derive(Clone, Default)]
+pub struct Registry {
+    functions: HashMap<String, (Arc<dyn FormulaFunction>, OperatorInfo)>,
+}
+
+/// Why a function can't be registered
+#[derive(Debug, PartialEq, Eq, Clone, Hash)]
+pub enum RegistryError {
+    /// The function's name isn't the operator in its signature
+    NameMismatch { name: String, operator: String },
+}
+
+impl fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RegistryError::NameMismatch { name, operator } => {
+                write!(f, "the function {} has the signature of {}", name, operator)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RegistryError {}
+
+impl fmt::Debug for Registry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Registry {:?}", self.names())
+    }
+}
+
+impl Registry {
+    /// A registry with the built-in functions
+    pub fn with_builtins() -> Registry {
+        let mut ret = Registry::default();
+        let mut add = |name: &str, doc, params: (u16, Option<u16>, &[&[Types]]), result, call| {
+            ret.register(Arc::new(BuiltIn {
+                signature: OperatorInfo::new(name, params.0, params.1, params.2, result),
+                doc,
+                call,
+            }))
+            .expect("a built-in function's name matches its signature")
+        };
+        let logicals: &[Types] = &[
+            Types::Bool,
+            Types::Int,
+            Types::Float,
+            Types::Number,
+            Types::Array,
+        ];
+
+        add(
+            "SUM",
+            "The total of the numbers",
+            (1, None, &[Types::NUMBERS]),
+            ResultType::Arithmetic,
+            sum,
+        );
+        add(
+            "AVERAGE",
+            "The mean of the numbers",
+            (1, None, &[Types::NUMBERS]),
+            ResultType::Is(Types::Float),
+            average,
+        );
+        add(
+            "MIN",
+            "The smallest of the numbers",
+            (1, None, &[Types::NUMBERS]),
+            ResultType::Arithmetic,
+            min,
+        );
+        add(
+            "MAX",
+            "The largest of the numbers",
+            (1, None, &[Types::NUMBERS]),
+            ResultType::Arithmetic,
+            max,
+        );
+        add(
+            "COUNT",
+            "How many of the values are numbers",
+            (1, None, &[Types::ANYTHING]),
+            ResultType::Is(Types::Int),
+            count,
+        );
+        add(
+            "ROUND",
+            "Round a number to a number of decimal places",
+            (1, Some(2), &[Types::NUMERIC]),
+            ResultType::Is(Types::Float),
+            round,
+        );
+        add(
+            "ABS",
+            "A number without its sign",
+            (1, Some(1), &[Types::NUMERIC]),
+            ResultType::Arithmetic,
+            abs,
+        );
+        add(
+            "INDEX",
+            "The value at a row and column of an array",
+            (2, Some(3), &[Types::ANYTHING, Types::NUMERIC]),
+            ResultType::Is(Types::Any),
+            index,
+        );
+        add(
+            "IF",
+            "The second value if the first is true, otherwise the third",
+            (2, Some(3), &[Types::LOGICAL, Types::ANYTHING]),
+            ResultType::Common(1),
+            if_,
+        );
+        add(
+            "AND",
+            "Whether all the values are true",
+            (1, None, &[logicals]),
+            ResultType::Is(Types::Bool),
+            and,
+        );
+        add(
+            "OR",
+            "Whether any of the values are true",
+            (1, None, &[logicals]),
+            ResultType::Is(Types::Bool),
+            or,
+        );
+        add(
+            "NOT",
+            "True if the value is false and false if it's true",
+            (1, Some(1), &[Types::LOGICAL]),
+            ResultType::Is(Types::Bool),
+            not,
+        );
+
+        ret
+    }
+
+    /// Add a function or replace the one with the same name. An error if
+    /// the name and the operator in the signature are different
+    pub fn register(&mut self, function: Arc<dyn FormulaFunction>) -> Result<(), RegistryError> {
+        let name = function.name().to_uppercase();
+        let signature = function.signature();
+        if name != signature.operator.to_uppercase() {
+            return Err(RegistryError::NameMismatch {
+                name,
+                operator: signature.operator,
+            });
+        }
+        self.functions.insert(name, (function, signature));
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Arc<dyn FormulaFunction>> {
+        self.functions.get(&name.to_uppercase()).map(|(f, _)| f)
+    }
+
+    /// The names of the functions in alphabetical order
+    pub fn names(&self) -> Vec<&str> {
+        let mut ret: Vec<&str> = self.functions.keys().map(|k| k.as_str()).collect();
+        ret.sort_unstable();
+        ret
+    }
+
+    /// Call a function. A function that isn't there or a decorator it
+    /// doesn't have is `#NAME?`. The wrong number of parameters or a
+    /// function that panics is `#VALUE!`
+    pub fn call(&self, name: &str, params: &[Value], decorators: &[String]) -> Value {
+        match self.functions.get(&name.to_uppercase()) {
+            None => CellError::Name.into(),
+            Some((_, signature)) if !signature.takes(params.len()) => CellError::Value.into(),
+            Some((f, _)) if !has_decorators(f.as_ref(), decorators) => CellError::Name.into(),
+            Some((f, _)) => catch_unwind(AssertUnwindSafe(|| f.call(params, decorators)))
+                .unwrap_or_else(|_| CellError::Value.into()),
+        }
+    }
+
+    pub fn info(&self, name: &str) -> Option<FunctionInfo> {
+        let (f, signature) = self.functions.get(&name.to_uppercase())?;
+        Some(FunctionInfo {
+            name: f.name().to_uppercase(),
+            usage: usage(&f.name().to_uppercase(), signature),
+            doc: f.doc(),
+            decorators: f.decorators(),
+            volatile: f.volatile(),
+        })
+    }
+
+    /// The functions whose names start with `prefix`, ignoring case, in
+    /// alphabetical order
+    pub fn complete(&self, prefix: &str) -> Vec<FunctionInfo> {
+        let prefix = prefix.to_uppercase();
+        self.names()
+            .into_iter()
+            .filter(|name| name.starts_with(&prefix))
+            .filter_map(|name| self.info(name))
+            .collect()
+    }
+
+    /// Whether `expr` calls a volatile function, so its value has to be
+    /// worked out again whenever anything is
+    pub fn is_volatile(&self, expr: &Expression) -> bool {
+        struct Finder<'a>(&'a Registry, bool);
+
+        impl Visitor for Finder<'_> {
+            fn visit_function(
+                &mut self,
+                name: &str,
+                type_params: &[Expression],
+                params: &[Expression],
+                _info: &ParseInfo,
+            ) {
+                self.1 |= self.0.get(name).is_some_and(|f| f.volatile());
+                for e in type_params.iter().chain(params) {
+                    self.visit_expr(e)
+                }
+            }
+        }
+
+        let mut finder = Finder(self, false);
+        finder.visit_expr(expr);
+        finder.1
+    }
+}
+
+/// Whether a function has all of the decorators, ignoring case
+pub(crate) fn has_decorators(function: &dyn FormulaFunction, decorators: &[String]) -> bool {
+    if decorators.is_empty() {
+        return true;
+    }
+    let known = function.decorators();
+    decorators
+        .iter()
+        .all(|d| known.iter().any(|k| k.eq_ignore_ascii_case(d)))
+}
+
+/// How a function is called, from its signature. Optional parameters are
+/// in brackets and any number of parameters ends with `...`
+fn usage(name: &str, signature: &OperatorInfo) -> String {
+    let describe = |pos: u16| {
+        let types = signature.param_types(pos);
+        match types.first() {
+            _ if types.contains(&Types::Str) => "value",
+            Some(Types::Bool) => "logical",
+            _ => "number",
+        }
+    };
+
+    let last = signature.max_params.unwrap_or(signature.num_params.max(1));
+    let mut params: Vec<String> = (0..last)
+        .map(|pos| match pos < signature.num_params {
+            true => describe(pos).to_string(),
+            false => format!("[{}]", describe(pos)),
+        })
+        .collect();
+    if signature.max_params.is_none() {
+        params.push("...".into());
+    }
+    format!("{}({})", name, params.join(", "))
+}
+
+lazy_static! {
+    static ref BUILTINS: Arc<Registry> = Arc::new(Registry::with_builtins());
+}
+
+/// The functions that come with Mesa X
+pub fn builtins() -> Arc<Registry> {
+    BUILTINS.clone()
+}
+
+/// Each parameter's value with arrays and ranges flattened, along with
+/// whether it came from an array. Functions skip values in an array that
+/// they'd reject if given directly
+fn flatten(params: &[Value]) -> Vec<(Value, bool)> {
+    let mut ret = vec![];
+    for p in params {
+        match unwrap_maybe(p) {
+            Value::Array(rows) => {
+                ret.extend(rows.iter().flatten().map(|v| (unwrap_maybe(v), true)))
+            }
+            v => ret.push((v, false)),
+        }
+    }
+    ret
+}
+
+/// The numbers in the parameters. Anything else in an array is skipped
+/// but a parameter given directly has to be a number, a boolean or a
+/// blank. The first error is the result
+fn numbers(params: &[Value]) -> Result<Vec<Number>, Value> {
+    let mut ret = vec![];
+    for (v, in_array) in flatten(params) {
+        match (&v, in_array, as_number(&v)) {
+            (Value::Error(_), _, _) => return Err(v),
+            (Value::Int(_) | Value::Float(_), _, Some(n)) | (_, false, Some(n)) => ret.push(n),
+            (_, true, _) => (),
+            (_, false, None) => return Err(CellError::Value.into()),
+        }
+    }
+    Ok(ret)
+}
+
+/// A parameter that has to be a single value. An error is passed on
+fn scalar(v: &Value) -> Result<Value, Value> {
+    match unwrap_maybe(v) {
+        e @ Value::Error(_) => Err(e),
+        Value::Array(_) => Err(CellError::Value.into()),
+        v => Ok(v),
+    }
+}
+
+fn sum(params: &[Value]) -> Value {
+    match numbers(params) {
+        Ok(numbers) => numbers.into_iter().fold(Value::Int(0), |total, n| {
+            arithmetic("+", &total, &n.value()).unwrap_or_else(|_| CellError::Value.into())
+        }),
+        Err(e) => e,
+    }
+}
+
+fn average(params: &[Value]) -> Value {
+    match numbers(params) {
+        Ok(numbers) if numbers.is_empty() => CellError::DivZero.into(),
+        Ok(numbers) => {
+            let mean = numbers.iter().map(|n| n.to_f64()).sum::<f64>() / numbers.len() as f64;
+            match mean.is_finite() {
+                true => Value::Float(mean),
+                false => CellError::Num.into(),
+            }
+        }
+        Err(e) => e,
+    }
+}
+
+/// The smallest or largest number, or `0` if there aren't any
+fn extreme(params: &[Value], largest: bool) -> Value {
+    let numbers = match numbers(params) {
+        Ok(numbers) => numbers,
+        Err(e) => return e,
+    };
+    let better = |a: Number, b: Number| match largest {
+        true => b.to_f64() > a.to_f64(),
+        false => b.to_f64() < a.to_f64(),
+    };
+    numbers
+        .into_iter()
+        .reduce(|best, n| if better(best, n) { n } else { best })
+        .map(|n| n.value())
+        .unwrap_or(Value::Int(0))
+}
+
+fn min(params: &[Value]) -> Value {
+    extreme(params, false)
+}
+
+fn max(params: &[Value]) -> Value {
+    extreme(params, true)
+}
+
+/// How many numbers there are. A boolean given directly counts too but
+/// errors and text don't
+fn count(params: &[Value]) -> Value {
+    let counted = flatten(params)
+        .iter()
+        .filter(|(v, in_array)| match v {
+            Value::Int(_) | Value::Float(_) => true,
+            Value::Bool(_) => !in_array,
+            _ => false,
+        })
+        .count();
+    Value::Int(counted as i128)
+}
+
+/// Round to a number of decimal places, `0` if not given. Halves round
+/// away from zero and negative places round to the left of the point
+fn round(params: &[Value]) -> Value {
+    let number = |v: &Value| match scalar(v) {
+        Ok(v) => as_number(&v).ok_or_else(|| CellError::Value.into()),
+        Err(e) => Err(e),
+    };
+    let places = match params.get(1).map(number).unwrap_or(Ok(Number::Int(0))) {
+        Ok(places) => places.to_f64().trunc().clamp(-400.0, 400.0) as i32,
+        Err(e) => return e,
+    };
+    let x = match number(&params[0]) {
+        Ok(x) => x.to_f64(),
+        Err(e) => return e,
+    };
+
+    // a float has about 15 significant digits, so rounding past them
+    // leaves the number as it is, and a number well short of the place
+    // being rounded to is 0
+    let digits = match x == 0.0 {
+        true => 0,
+        false => x.abs().log10().floor() as i32,
+    };
+    if x == 0.0 || places + digits >= 15 {
+        return Value::Float(x);
+    }
+    if digits + 1 < -places {
+        return Value::Float(0.0);
+    }
+
+    // 10 ^ places can overflow, so a big scale is applied in two steps.
+    // powi drifts for big powers, but parsing gives the nearest float
+    let ten_to = |n: i32| format!("1e{}", n).parse::<f64>().unwrap_or(f64::NAN);
+    let (scale, more) = match places.abs() > 300 {
+        true => (ten_to(places.abs() - 300), 1e300),
+        false => (ten_to(places.abs()), 1.0),
+    };
+    let rounded = match places >= 0 {
+        true => (x * scale * more).round() / more / scale,
+        false => (x / scale / more).round() * more * scale,
+    };
+    match rounded.is_finite() {
+        true => Value::Float(rounded),
+        false => CellError::Num.into(),
+    }
+}
+
+fn abs(params: &[Value]) -> Value {
+    let v = match scalar(&params[0]) {
+        Ok(v) => v,
+        Err(e) => return e,
+    };
+    match as_number(&v) {
+        Some(Number::Int(i)) => i
+            .checked_abs()
+            .map(Value::Int)
+            .unwrap_or(Value::Float((i as f64).abs())),
+        Some(Number::Float(f)) => Value::Float(f.abs()),
+        None => CellError::Value.into(),
+    }
+}
+
+/// The item of an array at a row and a column counting from 1. With just
+/// one position, an array that's a single row is indexed by column. A
+/// position of `0` is the whole row or column and one past the end of
+/// the array is `#REF!`
+fn index(params: &[Value]) -> Value {
+    let rows = match unwrap_maybe(&params[0]) {
+        v @ Value::Array(_) if dimensions(&v).is_none() => return CellError::Value.into(),
+        Value::Array(rows) => rows,
+        e @ Value::Error(_) => return e,
+        v => vec![vec![v]],
+    };
+    let position = |v: &Value| match scalar(v).map(|v| as_number(&v)) {
+        Ok(Some(n)) if n.to_f64() >= 0.0 => Ok(n.to_f64() as usize),
+        Ok(_) => Err(CellError::Value.into()),
+        Err(e) => Err(e),
+    };
+    let positions = match params[1..]
+        .iter()
+        .map(position)
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(positions) => positions,
+        Err(e) => return e,
+    };
+    let (row, col) = match positions[..] {
+        [col] if rows.len() == 1 => (1, col),
+        [row] => (row, 0),
+        [row, col, ..] => (row, col),
+        [] => (0, 0),
+    };
+    if row > rows.len() || col > rows.first().map_or(0, |r| r.len()) {
+        return CellError::Ref.into();
+    }
+
+    let picked: Vec<Vec<Value>> = rows
+        .into_iter()
+        .enumerate()
+        .filter(|(r, _)| row == 0 || r + 1 == row)
+        .map(|(_, items)| {
+            items
+                .into_iter()
+                .enumerate()
+                .filter(|(c, _)| col == 0 || c + 1 == col)
+                .map(|(_, v)| v)
+                .collect()
+        })
+        .collect();
+    match &picked[..] {
+        [only] if only.len() == 1 => only[0].clone(),
+        _ => Value::Array(picked),
+    }
+}
+
+/// The second parameter if the first is true, otherwise the third or
+/// `FALSE` if there isn't one. An error in the branch not taken doesn't
+/// matter
+fn if_(params: &[Value]) -> Value {
+    let condition = match scalar(&params[0]) {
+        Ok(v) => as_bool(&v),
+        Err(e) => return e,
+    };
+    match condition {
+        Some(true) => params[1].clone(),
+        Some(false) => params.get(2).cloned().unwrap_or(Value::Bool(false)),
+        None => CellError::Value.into(),
+    }
+}
+
+/// The logical values in the parameters. Text and blanks in an array are
+/// skipped. With no logical values at all the result is `#VALUE!`
+fn logicals(params: &[Value]) -> Result<Vec<bool>, Value> {
+    let mut ret = vec![];
+    for (v, in_array) in flatten(params) {
+        match (&v, in_array) {
+            (Value::Error(_), _) => return Err(v),
+            (Value::Str(_), false) => return Err(CellError::Value.into()),
+            (Value::Str(_) | Value::Maybe(None), _) => (),
+            (v, _) => match as_bool(v) {
+                Some(b) => ret.push(b),
+                None if in_array => (),
+                None => return Err(CellError::Value.into()),
+            },
+        }
+    }
+    match ret.is_empty() {
+        true => Err(CellError::Value.into()),
+        false => Ok(ret),
+    }
+}
+
+fn and(params: &[Value]) -> Value {
+    match logicals(params) {
+        Ok(all) => Value::Bool(all.iter().all(|b| *b)),
+        Err(e) => e,
+    }
+}
+
+fn or(params: &[Value]) -> Value {
+    match logicals(params) {
+        Ok(all) => Value::Bool(all.iter().any(|b| *b)),
+        Err(e) => e,
+    }
+}
+
+fn not(params: &[Value]) -> Value {
+    match scalar(&params[0]).map(|v| as_bool(&v)) {
+        Ok(Some(b)) => Value::Bool(!b),
+        Ok(None) => CellError::Value.into(),
+        Err(e) => e,
+    }
+}
+
+#[test]
+fn test_functions() {
+    use crate::eval::{eval_in, EvalContext};
+    use crate::eval_stack::create_eval_stack;
+    use crate::parser::whole_expr_str;
+
+    // A1:A5 is 1, 2.5, "x", TRUE and a blank. B1 is #DIV/0!
+    let context = EvalContext::with_sheets(&[(
+        "Sheet1",
+        &[
+            ("A1", Value::Int(1)),
+            ("A2", Value::Float(2.5)),
+            ("A3", Value::Str("x".into())),
+            ("A4", Value::Bool(true)),
+            ("B1", CellError::DivZero.into()),
+        ],
+    )])
+    .unwrap();
+
+    let error = |e: CellError| -> Value { e.into() };
+    let tests: Vec<(&str, Value)> = vec![
+        ("SUM(1, 2, 3)", Value::Int(6)),
+        ("sum(A1:A5)", Value::Float(3.5)),
+        ("SUM(A1:A5, TRUE, A5)", Value::Float(4.5)),
+        ("SUM({1, 2; 3, 4}) - 1", Value::Int(9)),
+        ("SUM(A1:B1)", error(CellError::DivZero)),
+        (r#"SUM(1, "2")"#, error(CellError::Value)),
+        ("AVERAGE(A1:A5)", Value::Float(1.75)),
+        ("AVERAGE(2, 3)", Value::Float(2.5)),
+        ("AVERAGE(1E308, 1E308)", error(CellError::Num)),
+        ("AVERAGE(A3:A5)", error(CellError::DivZero)),
+        ("MIN(A1:A5, 3)", Value::Int(1)),
+        ("MAX(A1:A5, -3)", Value::Float(2.5)),
+        ("MAX(A3:A5)", Value::Int(0)),
+        ("COUNT(A1:B5)", Value::Int(2)),
+        (r#"COUNT(1, "a", TRUE, 1 / 0)"#, Value::Int(2)),
+        ("ROUND(2.5)", Value::Float(3.0)),
+        ("ROUND(-2.5)", Value::Float(-3.0)),
+        ("ROUND(2.71828, 2)", Value::Float(2.72)),
+        ("ROUND(1234, -2)", Value::Float(1200.0)),
+        ("ROUND(0, 400)", Value::Float(0.0)),
+        ("ROUND(2.5, 400)", Value::Float(2.5)),
+        ("ROUND(1E300, 10)", Value::Float(1e300)),
+        ("ROUND(1E300, -300)", Value::Float(1e300)),
+        ("ROUND(1E300, -400)", Value::Float(0.0)),
+        ("ROUND(-4.4, -1)", Value::Float(0.0)),
+        ("ROUND(1.5E-300, 300)", Value::Float(2e-300)),
+        (
+            "ROUND(0.123456789012345678, 15)",
+            Value::Float(0.123456789012346),
+        ),
+        ("ROUND(A3)", error(CellError::Value)),
+        ("ABS(-3)", Value::Int(3)),
+        ("ABS(-2.5) + ABS(TRUE)", Value::Float(3.5)),
+        ("ABS(A1:A2)", error(CellError::Value)),
+        (r#"INDEX({"a","b"}, 2)"#, Value::Str("b".into())),
+        ("INDEX({1, 2; 3, 4}, 2, 1)", Value::Int(3)),
+        (
+            "INDEX({1, 2; 3, 4}, 2)",
+            Value::Array(vec![vec![Value::Int(3), Value::Int(4)]]),
+        ),
+        (
+            "INDEX({1, 2; 3, 4}, 0, 2)",
+            Value::Array(vec![vec![Value::Int(2)], vec![Value::Int(4)]]),
+        ),
+        ("INDEX(A1:A5, 2.9)", Value::Float(2.5)),
+        ("INDEX(7, 1, 1)", Value::Int(7)),
+        ("INDEX({1, 2}, 3)", error(CellError::Ref)),
+        ("INDEX({1, 2}, -1)", error(CellError::Value)),
+        ("INDEX(B1, 1)", error(CellError::DivZero)),
+        ("IF(A1 > 0, \"yes\", \"no\")", Value::Str("yes".into())),
+        ("IF(A5, 1, 2)", Value::Int(2)),
+        ("IF(FALSE, 1)", Value::Bool(false)),
+        ("IF(TRUE, 1, 1 / 0)", Value::Int(1)),
+        ("IF(B1, 1, 2)", error(CellError::DivZero)),
+        ("IF(\"x\", 1, 2)", error(CellError::Value)),
+        ("AND(TRUE, 1, A1:A5)", Value::Bool(true)),
+        ("AND(TRUE, 0)", Value::Bool(false)),
+        ("OR(FALSE, A4)", Value::Bool(true)),
+        ("OR(A3:A3)", error(CellError::Value)),
+        (r#"AND(TRUE, "x")"#, error(CellError::Value)),
+        ("NOT(A5)", Value::Bool(true)),
+        ("NOT(1 / 0)", error(CellError::DivZero)),
+        ("SUM(ABS(-2), MAX(1, 4)) * 2", Value::Int(12)),
+        ("NOPE(1)", error(CellError::Name)),
+        ("ABS(1, 2)", error(CellError::Value)),
+        ("IF(TRUE)", error(CellError::Value)),
+    ];
+    for (formula, expected) in tests {
+        let stack =
+            create_eval_stack(&whole_expr_str(formula).unwrap(), &Default::default()).unwrap();
+        assert_eq!(
+            eval_in(&stack, &context),
+            Ok(expected),
+            "Evaluating {}",
+            formula
+        );
+    }
+
+    assert_eq!(builtins().names()[..3], ["ABS", "AND", "AVERAGE"]);
+    assert_eq!(
+        builtins().get("sum").map(|f| f.signature().max_params),
+        Some(None)
+    );
+}
+
+#[test]
+fn test_custom_functions() {
+    use crate::eval::{eval_in, EvalContext};
+    use crate::eval_stack::create_eval_stack;
+    use crate::lint::lint_with;
+    use crate::parser::whole_expr_str;
+    use crate::typecheck::check_with;
+
+    /// A random number, or the same number every time with `FIXED`
+    struct Rand;
+
+    impl FormulaFunction for Rand {
+        fn name(&self) -> &str {
+            "rand"
+        }
+
+        fn signature(&self) -> OperatorInfo {
+            OperatorInfo::new("RAND", 0, Some(0), &[], ResultType::Is(Types::Float))
+        }
+
+        fn call(&self, _params: &[Value], decorators: &[String]) -> Value {
+            match decorators.iter().any(|d| d.eq_ignore_ascii_case("fixed")) {
+                true => Value::Float(0.5),
+                false => Value::Float(0.25),
+            }
+        }
+
+        fn volatile(&self) -> bool {
+            true
+        }
+
+        fn decorators(&self) -> Vec<String> {
+            vec!["FIXED".into()]
+        }
+
+        fn doc(&self) -> String {
+            "A number from 0 up to 1".into()
+        }
+    }
+
+    let mut context = EvalContext::default();
+    context.register(Rand).unwrap();
+
+    let eval = |formula: &str, context: &EvalContext| {
+        let stack =
+            create_eval_stack(&whole_expr_str(formula).unwrap(), &Default::default()).unwrap();
+        eval_in(&stack, context)
+    };
+    assert_eq!(eval("RAND() * 2", &context), Ok(Value::Float(0.5)));
+    assert_eq!(eval("Rand[fixed]()", &context), Ok(Value::Float(0.5)));
+    assert_eq!(eval("RAND[FAST]()", &context), Ok(CellError::Name.into()));
+    assert_eq!(eval("RAND(1)", &context), Ok(CellError::Value.into()));
+    assert_eq!(eval("SUM(1, 2)", &context), Ok(Value::Int(3)));
+
+    // registering on a context leaves the built-ins alone
+    assert_eq!(
+        eval("RAND()", &EvalContext::default()),
+        Ok(CellError::Name.into())
+    );
+    assert!(builtins().get("RAND").is_none());
+
+    let functions = &context.functions;
+    assert_eq!(
+        functions.info("rand"),
+        Some(FunctionInfo {
+            name: "RAND".into(),
+            usage: "RAND()".into(),
+            doc: "A number from 0 up to 1".into(),
+            decorators: vec!["FIXED".into()],
+            volatile: true,
+        })
+    );
+    let usages: Vec<String> = functions
+        .complete("r")
+        .into_iter()
+        .map(|f| f.usage)
+        .collect();
+    assert_eq!(usages, vec!["RAND()", "ROUND(number, [number])"]);
+    assert_eq!(
+        builtins().info("SUM").map(|f| f.usage),
+        Some("SUM(number, ...)".into())
+    );
+
+    let expr = whole_expr_str("1 + IF(A1, RAND[FIXED](), 2)").unwrap();
+    assert!(functions.is_volatile(&expr));
+    assert!(!functions.is_volatile(&whole_expr_str("SUM(A1:B2)").unwrap()));
+
+    assert_eq!(check_with(&expr, functions).errors, vec![]);
+    assert_eq!(check_with(&expr, functions).result, Types::Number);
+    assert_eq!(lint_with(&expr, functions), vec![]);
+    assert_eq!(lint_with(&expr, &builtins()).len(), 1);
+
+    let expr = whole_expr_str("RAND[FAST]()").unwrap();
+    assert_eq!(check_with(&expr, functions).errors.len(), 1);
+    assert_eq!(
+        lint_with(&expr, functions)[0].message,
+        "RAND doesn't have the decorator FAST"
+    );
+
+    /// Panics when it's called, or claims another name in its signature
+    struct Broken(&'static str);
+
+    impl FormulaFunction for Broken {
+        fn name(&self) -> &str {
+            "broken"
+        }
+
+        fn signature(&self) -> OperatorInfo {
+            OperatorInfo::new(self.0, 0, None, &[], ResultType::Is(Types::Any))
+        }
+
+        fn call(&self, params: &[Value], _decorators: &[String]) -> Value {
+            params[7].clone()
+        }
+    }
+
+    // a panic is an error value rather than the end of the evaluator
+    context.register(Broken("Broken")).unwrap();
+    assert_eq!(eval("BROKEN(1) + 1", &context), Ok(CellError::Value.into()));
+    assert_eq!(eval("RAND()", &context), Ok(Value::Float(0.25)));
+
+    /// An array with rows of different lengths
+    struct Ragged;
+
+    impl FormulaFunction for Ragged {
+        fn name(&self) -> &str {
+            "RAGGED"
+        }
+
+        fn signature(&self) -> OperatorInfo {
+            OperatorInfo::new("RAGGED", 0, Some(0), &[], ResultType::Is(Types::Array))
+        }
+
+        fn call(&self, _params: &[Value], _decorators: &[String]) -> Value {
+            Value::Array(vec![
+                vec![Value::Int(1), Value::Int(2)],
+                vec![Value::Int(3)],
+            ])
+        }
+    }
+
+    context.register(Ragged).unwrap();
+    assert_eq!(eval("RAGGED() + 1", &context), Ok(CellError::Value.into()));
+    assert_eq!(
+        eval("{1; 2} * RAGGED()", &context),
+        Ok(CellError::Value.into())
+    );
+    assert_eq!(
+        eval("INDEX(RAGGED(), 2, 2)", &context),
+        Ok(CellError::Value.into())
+    );
+    assert_eq!(eval("SUM(RAGGED())", &context), Ok(Value::Int(6)));
+
+    // a function whose signature is for another name isn't added
+    assert_eq!(
+        context.register(Broken("FIXED")).map_err(|e| e.to_string()),
+        Err("the function BROKEN has the signature of FIXED".to_string())
+    );
+    assert_eq!(eval("BROKEN(1) + 1", &context), Ok(CellError::Value.into()));
+    let mut registry = Registry::default();
+    assert!(registry.register(Arc::new(Broken("FIXED"))).is_err());
+    assert!(registry.get("BROKEN").is_none());
+}
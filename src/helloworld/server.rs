@@ -11,7 +11,7 @@ use rand::{thread_rng, Rng};
 use std::pin::Pin;
 use tokio_stream::Stream;
 
-///
+/// The generated gRPC types
 pub mod hello_world {
     tonic::include_proto!("helloworld");
 }
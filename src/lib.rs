@@ -1,5 +1,25 @@
 pub mod parser;
 
+pub mod diagnostic;
+
+pub mod printer;
+
+pub mod visit;
+
+pub mod precedents;
+
+pub mod serial;
+
+pub mod typecheck;
+
+pub mod lint;
+
+pub mod trivia;
+
+pub mod r1c1;
+
+pub mod rewrite;
+
 pub mod util;
 
 pub mod parser_util;
@@ -8,6 +28,8 @@ pub mod eval_stack;
 
 pub mod eval;
 
+pub mod functions;
+
 pub mod definitions;
 
 pub mod workbook;
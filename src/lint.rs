@@ -0,0 +1,333 @@
+//! Warnings about formulas that parse but probably don't do what was
+//! meant
+//!
+
+// Copyright 2021 David Pollak
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::diagnostic::ParseDiagnostic;
+use crate::functions::{builtins, has_decorators, Registry};
+use crate::parser::{whole_expr_str, Expression, Notation, ParseInfo, Range};
+use crate::printer::print;
+use crate::visit::Visitor;
+use crate::worksheet::A1Reference;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum LintKind {
+    /// A `let` name that the body never uses
+    UnusedLet,
+    /// A `let` name that hides a name bound by an outer `let`
+    ShadowedName,
+    /// An `IF` whose condition doesn't depend on anything
+    ConstantCondition,
+    /// Dividing by a literal `0`
+    DivisionByZero,
+    /// A range with one corner anchored by `$` and the other not, so the
+    /// corners move apart when the formula is copied
+    MixedAnchors,
+    UnknownFunction,
+    /// A `[...]` decorator the function doesn't have
+    UnknownDecorator,
+}
+
+/// A warning about part of a formula. `start` and `end` are the byte
+/// offsets of the expression it's about
+#[derive(Debug, PartialEq, Eq, Clone, Hash)]
+pub struct LintWarning {
+    pub kind: LintKind,
+    pub start: usize,
+    pub end: usize,
+    pub message: String,
+}
+
+impl LintWarning {
+    /// The warning as a diagnostic with a line and column in `formula`
+    pub fn diagnostic(&self, formula: &str) -> ParseDiagnostic {
+        ParseDiagnostic::problem(formula, self.start, self.message.clone())
+    }
+}
+
+/// The warnings for `expr` in the order their expressions are visited
+pub fn lint(expr: &Expression) -> Vec<LintWarning> {
+    lint_with(expr, &builtins())
+}
+
+/// Lint `expr` knowing the functions in `registry` rather than just the
+/// built-in ones
+pub fn lint_with(expr: &Expression, registry: &Registry) -> Vec<LintWarning> {
+    let mut linter = Linter {
+        registry,
+        warnings: vec![],
+        scope: vec![],
+    };
+    linter.visit_expr(expr);
+    linter.warnings
+}
+
+/// Parse and lint a formula. The warnings are diagnostics so they can be
+/// shown the same way as parse errors, which are the `Err`
+pub fn lint_str(formula: &str) -> Result<Vec<ParseDiagnostic>, Vec<ParseDiagnostic>> {
+    let expr = whole_expr_str(formula)?;
+    Ok(lint(&expr).iter().map(|w| w.diagnostic(formula)).collect())
+}
+
+/// A name bound by a `let` and whether anything has used it yet
+struct Binding {
+    name: String,
+    used: bool,
+}
+
+struct Linter<'a> {
+    registry: &'a Registry,
+    warnings: Vec<LintWarning>,
+    scope: Vec<Binding>,
+}
+
+impl Linter<'_> {
+    fn warn(&mut self, kind: LintKind, info: &ParseInfo, message: String) {
+        let (start, end) = info.as_ref().map(|info| info.span()).unwrap_or((0, 0));
+        self.warnings.push(LintWarning {
+            kind,
+            start,
+            end,
+            message,
+        })
+    }
+
+    fn used(&mut self, name: &str) {
+        if let Some(binding) = self.scope.iter_mut().rev().find(|b| b.name == name) {
+            binding.used = true;
+        }
+    }
+}
+
+/// Whether an expression has the same value wherever it's evaluated:
+/// literals and operators on them without names, references or
+/// function calls
+fn is_constant(expr: &Expression) -> bool {
+    match expr {
+        Expression::Int(..) | Expression::Float(..) | Expression::Str(..) => true,
+        Expression::Identifier(name, _) => name == "TRUE" || name == "FALSE",
+        Expression::Paren(..)
+        | Expression::Prefix(..)
+        | Expression::Postfix(..)
+        | Expression::Infix(..)
+        | Expression::Array(..) => expr.children().into_iter().all(is_constant),
+        _ => false,
+    }
+}
+
+/// Whether an expression is a literal zero, allowing for parentheses
+/// and a sign
+fn is_zero(expr: &Expression) -> bool {
+    match expr {
+        Expression::Int(i, _) => *i == 0,
+        Expression::Float(f, _) => *f == 0.0,
+        Expression::Paren(inner, _) => is_zero(inner),
+        Expression::Prefix(opr, inner, _) if opr == "-" || opr == "+" => is_zero(inner),
+        _ => false,
+    }
+}
+
+/// Whether the corners of an A1 range are anchored differently. Only the
+/// parts both corners have are compared, so `$A:$A` and `A$1:C` are fine.
+/// A first corner anchored in both parts is the usual way of writing a
+/// range that grows as it's filled down, like `$A$1:A5` for a running
+/// total, so that isn't a warning either
+fn mixed_anchors(range: &Range) -> bool {
+    // R1C1 ranges say where each corner is explicitly
+    if range.upper_left.notation == Notation::R1C1 {
+        return false;
+    }
+    let corners = (
+        range.upper_left.addr.parse::<A1Reference>(),
+        range.lower_right.addr.parse::<A1Reference>(),
+    );
+    match corners {
+        (Ok(first), Ok(_)) if first.absolute_col && first.absolute_row => false,
+        (Ok(first), Ok(last)) => {
+            (first.col.is_some() && last.col.is_some() && first.absolute_col != last.absolute_col)
+                || (first.row.is_some()
+                    && last.row.is_some()
+                    && first.absolute_row != last.absolute_row)
+        }
+        _ => false,
+    }
+}
+
+impl Visitor for Linter<'_> {
+    fn visit_identifier(&mut self, name: &str, _info: &ParseInfo) {
+        self.used(name)
+    }
+
+    fn visit_dotted_identifier(&mut self, names: &[String], _info: &ParseInfo) {
+        if let Some(first) = names.first() {
+            self.used(first)
+        }
+    }
+
+    fn visit_range(&mut self, range: &Range, info: &ParseInfo) {
+        if mixed_anchors(range) {
+            self.warn(
+                LintKind::MixedAnchors,
+                info,
+                format!(
+                    "the corners of {}:{} are anchored differently so they'll move apart when the formula is copied",
+                    range.upper_left.addr, range.lower_right.addr
+                ),
+            );
+        }
+    }
+
+    fn visit_infix(&mut self, opr: &str, left: &Expression, right: &Expression, info: &ParseInfo) {
+        if (opr == "/" || opr == "%") && is_zero(right) {
+            self.warn(
+                LintKind::DivisionByZero,
+                info,
+                format!("'{}' by zero is always an error", opr),
+            );
+        }
+        self.visit_expr(left);
+        self.visit_expr(right);
+    }
+
+    fn visit_function(
+        &mut self,
+        name: &str,
+        type_params: &[Expression],
+        params: &[Expression],
+        info: &ParseInfo,
+    ) {
+        match self.registry.get(name) {
+            None => self.warn(
+                LintKind::UnknownFunction,
+                info,
+                format!("unknown function {}", name),
+            ),
+            Some(f) => {
+                for decorator in type_params
+                    .iter()
+                    .filter(|d| !has_decorators(f.as_ref(), &[print(d)]))
+                {
+                    self.warn(
+                        LintKind::UnknownDecorator,
+                        decorator.parse_info(),
+                        format!("{} doesn't have the decorator {}", name, print(decorator)),
+                    );
+                }
+            }
+        }
+
+        if name == "IF" {
+            if let Some(condition) = params.first().filter(|c| is_constant(c)) {
+                self.warn(
+                    LintKind::ConstantCondition,
+                    condition.parse_info(),
+                    "the condition of IF is a constant so it always takes the same branch".into(),
+                );
+            }
+        }
+
+        for e in type_params.iter().chain(params) {
+            self.visit_expr(e)
+        }
+    }
+
+    fn visit_let(&mut self, name: &str, value: &Expression, body: &Expression, info: &ParseInfo) {
+        if self.scope.iter().any(|b| b.name == name) {
+            self.warn(
+                LintKind::ShadowedName,
+                info,
+                format!("{} hides the {} bound by an outer let", name, name),
+            );
+        }
+
+        // the name isn't bound in its own value
+        self.visit_expr(value);
+        self.scope.push(Binding {
+            name: name.to_string(),
+            used: false,
+        });
+        self.visit_expr(body);
+
+        if let Some(Binding { used: false, .. }) = self.scope.pop() {
+            self.warn(
+                LintKind::UnusedLet,
+                info,
+                format!("{} is bound but never used", name),
+            );
+        }
+    }
+}
+
+#[test]
+fn test_lint() {
+    let warnings = |formula: &str| -> Vec<String> {
+        lint_str(formula)
+            .unwrap()
+            .iter()
+            .map(|d| d.to_string())
+            .collect()
+    };
+    let kinds = |formula: &str| -> Vec<LintKind> {
+        lint(&whole_expr_str(formula).unwrap())
+            .iter()
+            .map(|w| w.kind)
+            .collect()
+    };
+
+    assert_eq!(
+        warnings("let x = 1; let y = x * 2; SUM(A1:B2, y) / MAX(1, $C$1:$D9)"),
+        Vec::<String>::new()
+    );
+    assert_eq!(warnings("IF(A1 > 0, 1 / (1 - 1), 0)"), Vec::<String>::new());
+
+    assert_eq!(
+        warnings("let x = 1;\nlet y = 2; x"),
+        vec!["2:1: Y is bound but never used"]
+    );
+    assert_eq!(
+        kinds("let x = 1; let x = x + 1; x"),
+        vec![LintKind::ShadowedName]
+    );
+    assert_eq!(warnings("let t = 1; t.total"), Vec::<String>::new());
+    assert_eq!(
+        warnings("IF(TRUE, 1, 2) + IF(-(2 > 1), 3, 4) + IF(A1, 5)"),
+        vec![
+            "1:4: the condition of IF is a constant so it always takes the same branch",
+            "1:21: the condition of IF is a constant so it always takes the same branch",
+        ]
+    );
+    assert_eq!(
+        warnings("A1 / 0 + B2 % (-(0.0))"),
+        vec![
+            "1:1: '/' by zero is always an error",
+            "1:10: '%' by zero is always an error",
+        ]
+    );
+    assert_eq!(
+        warnings("SUM($A1:B2, A$1:$B2, $A:$A, A$1:C, $A1:$B9, $A$1:A9)"),
+        vec![
+            "1:5: the corners of $A1:B2 are anchored differently so they'll move apart when the formula is copied",
+            "1:13: the corners of A$1:$B2 are anchored differently so they'll move apart when the formula is copied",
+        ]
+    );
+    assert_eq!(
+        warnings("SUMM(1) + ABS[FAST](2)"),
+        vec![
+            "1:1: unknown function SUMM",
+            "1:15: ABS doesn't have the decorator FAST",
+        ]
+    );
+}
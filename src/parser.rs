@@ -1,72 +1,337 @@
 use nom::{
     branch::alt,
-    bytes::complete::{is_a, is_not, tag, take, take_till},
-    character::complete::{alpha1, char, digit1},
-    combinator::opt,
+    bytes::complete::{is_a, is_not, tag, take, take_till, take_while_m_n},
+    character::complete::{alpha1, char, digit1, hex_digit1, one_of, satisfy},
+    combinator::{map_opt, not, opt, recognize, verify},
     error::ErrorKind,
     error::ParseError,
-    multi::{many0, many1, separated_list0},
-    sequence::{delimited, tuple}, // sequence::tuple
+    multi::{many0, many1, separated_list0, separated_list1},
+    sequence::{delimited, preceded, terminated, tuple}, // sequence::tuple
     AsChar,
     Err,
-    IResult,
     InputTakeAtPosition,
+    Parser,
 };
 
+use crate::definitions::CellError;
+use crate::diagnostic::{check_delimiters, ParseDiagnostic};
+use crate::trivia::{attach_trivia, Trivia};
 use crate::util::*;
+use crate::visit::Visitor;
 use nom_locate::LocatedSpan;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
 
-type Span<'a> = LocatedSpan<&'a str>;
+type Span<'a> = LocatedSpan<&'a str, Option<&'a ParseContext>>;
 
-fn precedence(opr: &str) -> i32 {
-    match opr {
-        "+" | "-" => 100,
-        "*" | "/" => 200,
-        "==" | ">" | "<" | ">=" | "<=" => 20,
-        "&&" | "||" => 10,
-        _ => 0,
+type IResult<I, O> = nom::IResult<I, O, ParseFailure<I>>;
+
+/// A span that isn't tied to a `ParseContext`
+fn span(input: &str) -> Span<'_> {
+    Span::new_extra(input, None)
+}
+
+/// State shared by the parsers while parsing a formula. Every failure is
+/// recorded so that when the formula can't be parsed, the failure that
+/// got the farthest into the formula and what was expected there can be
+/// reported
+#[derive(Debug, Default)]
+pub struct ParseContext {
+    farthest: RefCell<Option<(usize, Vec<String>)>>,
+    problems: RefCell<Vec<(usize, String)>>,
+    recover: bool,
+    r1c1: bool,
+}
+
+impl ParseContext {
+    fn record(&self, offset: usize, expected: Option<String>) {
+        let mut farthest = self.farthest.borrow_mut();
+        match &mut *farthest {
+            Some((far, labels)) if *far == offset => {
+                if let Some(label) = expected {
+                    if !labels.contains(&label) {
+                        labels.push(label);
+                    }
+                }
+            }
+            Some((far, _)) if *far > offset => (),
+            _ => *farthest = Some((offset, expected.into_iter().collect())),
+        }
+    }
+
+    /// The farthest offset so far and how many things were expected there
+    fn mark(&self) -> Option<(usize, usize)> {
+        self.farthest
+            .borrow()
+            .as_ref()
+            .map(|(offset, labels)| (*offset, labels.len()))
+    }
+
+    /// Replace what was expected at `offset` since `mark` with `what`
+    fn summarize(&self, mark: Option<(usize, usize)>, offset: usize, what: &str) {
+        let keep = match mark {
+            Some((marked, len)) if marked == offset => len,
+            _ => 0,
+        };
+        if let Some((far, labels)) = &mut *self.farthest.borrow_mut() {
+            if *far == offset {
+                labels.truncate(keep);
+            }
+        }
+        self.record(offset, Some(what.to_string()));
+    }
+
+    /// Note something that was recognized but is wrong, such as a number
+    /// that's too large. It's reported instead of what was expected there
+    fn problem(&self, offset: usize, message: String) {
+        let mut problems = self.problems.borrow_mut();
+        if !problems.iter().any(|(o, _)| *o == offset) {
+            problems.push((offset, message));
+        }
+    }
+
+    /// The diagnostics for problems found while parsing
+    fn problem_diagnostics(&self, formula: &str) -> Vec<ParseDiagnostic> {
+        self.problems
+            .borrow()
+            .iter()
+            .map(|(offset, message)| ParseDiagnostic::problem(formula, *offset, message.clone()))
+            .collect()
     }
+
+    /// The diagnostics for a formula that failed to parse
+    fn diagnostics(&self, formula: &str) -> Vec<ParseDiagnostic> {
+        let mut ret = check_delimiters(formula);
+        let problems = self.problem_diagnostics(formula);
+
+        if let Some((offset, expected)) = &*self.farthest.borrow() {
+            // running off the end is explained by an unclosed delimiter
+            // and a parse that stopped at a problem by the problem
+            let explained = (!ret.is_empty() && *offset >= formula.len()) || !problems.is_empty();
+            if !explained && !ret.iter().any(|d| d.offset == *offset) {
+                ret.push(ParseDiagnostic::unexpected(
+                    formula,
+                    *offset,
+                    expected.clone(),
+                ));
+            }
+        }
+
+        ret.extend(problems);
+        ret.sort_by_key(|d| d.offset);
+        ret
+    }
+}
+
+/// Note a failure at `input` in the input's `ParseContext`
+fn record_failure(input: &Span, expected: Option<String>) {
+    if let Some(context) = input.extra {
+        context.record(input.location_offset(), expected);
+    }
+}
+
+/// Note a problem at `input` in the input's `ParseContext`
+fn record_problem(input: &Span, message: String) {
+    if let Some(context) = input.extra {
+        context.problem(input.location_offset(), message);
+    }
+}
+
+/// Is the parse recovering from errors rather than failing?
+fn recovering(input: &Span) -> bool {
+    input.extra.map(|c| c.recover).unwrap_or(false)
+}
+
+/// Are cell addresses written in R1C1 style rather than A1?
+fn in_r1c1(input: &Span) -> bool {
+    input.extra.map(|c| c.r1c1).unwrap_or(false)
+}
+
+/// The error the parsers produce. Creating one records the failure so
+/// that parse diagnostics aren't lost when `alt` backtracks
+#[derive(Debug, PartialEq)]
+pub struct ParseFailure<I> {
+    pub input: I,
+    pub kind: ErrorKind,
+}
+
+impl<'a> ParseError<Span<'a>> for ParseFailure<Span<'a>> {
+    fn from_error_kind(input: Span<'a>, kind: ErrorKind) -> Self {
+        record_failure(&input, None);
+        ParseFailure { input, kind }
+    }
+
+    fn append(_: Span<'a>, _: ErrorKind, other: Self) -> Self {
+        other
+    }
+
+    fn from_char(input: Span<'a>, c: char) -> Self {
+        record_failure(&input, Some(format!("'{}'", c)));
+        ParseFailure {
+            input,
+            kind: ErrorKind::Char,
+        }
+    }
+}
+
+/// Run `parser` and if it fails, note that `what` was expected after any
+/// whitespace or comments. `what` replaces the finer grained things the
+/// parser expected at the same place
+fn expecting<'a, O, F>(
+    what: &'static str,
+    mut parser: F,
+) -> impl FnMut(Span<'a>) -> IResult<Span<'a>, O>
+where
+    F: FnMut(Span<'a>) -> IResult<Span<'a>, O>,
+{
+    move |input: Span<'a>| {
+        let mark = input.extra.and_then(|c| c.mark());
+        parser(input).inspect_err(|_| {
+            let at = parser_comment_whitespaces(input)
+                .map(|(rest, _)| rest)
+                .unwrap_or(input);
+            if let Some(context) = at.extra {
+                context.summarize(mark, at.location_offset(), what);
+            }
+        })
+    }
+}
+
+/// The binary operators and their precedence. A higher precedence binds
+/// more tightly. Every binary operator is left associative, so `10 - 3 - 2`
+/// is `(10 - 3) - 2`. `<>` is a synonym for `!=`.
+///
+/// The operators are matched in the order listed, so an operator must come
+/// before any operator that is a prefix of it (e.g., `>=` before `>`)
+const BINARY_OPERATORS: [(&str, i32); 16] = [
+    ("&&", 10),
+    ("||", 5),
+    ("==", 20),
+    ("!=", 20),
+    ("<>", 20),
+    (">=", 20),
+    ("<=", 20),
+    (">", 20),
+    ("<", 20),
+    ("&", 50),
+    ("+", 100),
+    ("-", 100),
+    ("*", 200),
+    ("/", 200),
+    ("%", 200),
+    ("^", 300),
+];
+
+/// The precedence of a binary operator or `0` if it's not an operator
+pub(crate) fn precedence(opr: &str) -> i32 {
+    BINARY_OPERATORS
+        .iter()
+        .find(|(o, _)| *o == opr)
+        .map(|(_, p)| *p)
+        .unwrap_or(0)
 }
 
 #[test]
 fn test_precedence() {
-    assert!(precedence("&&") < precedence("+"))
+    assert!(precedence("||") < precedence("&&"));
+    assert!(precedence("&&") < precedence("=="));
+    assert!(precedence("==") < precedence("&"));
+    assert!(precedence("&") < precedence("+"));
+    assert!(precedence("+") < precedence("*"));
+    assert!(precedence("*") < precedence("^"));
+    assert_eq!(precedence("=>"), 0);
+    for (opr, _) in BINARY_OPERATORS.iter() {
+        assert!(precedence(opr) > 0, "{} has a precedence", opr);
+    }
+}
+
+/// The sheet a reference is on, as in `Sheet2!B4` or `'Q3 Sales'!A1`.
+/// A 3D reference (`Jan:Dec!B2`) is on every sheet from `first` to
+/// `last`. Names are kept as written and compared without regard to case
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Serialize, Deserialize)]
+pub struct SheetRef {
+    pub first: String,
+    pub last: Option<String>,
+}
+
+/// The style an address is written in. A formula is parsed in one style
+/// or the other, so `RC1` is column RC in A1 style and column 1 of the
+/// formula's row in R1C1 style
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, Default, Serialize, Deserialize)]
+pub enum Notation {
+    #[default]
+    A1,
+    R1C1,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Hash)]
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Serialize, Deserialize)]
 pub struct Address {
+    pub sheet: Option<SheetRef>,
     pub addr: String,
+    pub notation: Notation,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Hash)]
+/// A rectangular range of cells. The sheet qualifier is on the range
+/// rather than on its corners. A corner may be a whole column (`A`) or a
+/// whole row (`3`), so `A:C` is every cell in columns A through C and
+/// `A2:A` runs from A2 to the bottom of column A
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Serialize, Deserialize)]
 pub struct Range {
+    pub sheet: Option<SheetRef>,
     pub upper_left: Address,
     pub lower_right: Address,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Hash)]
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Serialize, Deserialize)]
 pub struct PositionInfo {
     pub start: usize,
     pub start_line: u32,
     pub end: usize,
     pub end_line: u32,
+    /// The source text of the expression. It isn't serialized: each
+    /// node's text repeats the text of everything in it
+    #[serde(skip)]
     pub text: String,
+    /// Where the expression starts and ends without the whitespace
+    /// around it
+    pub inner_start: usize,
+    pub inner_end: usize,
+    /// Comments and whitespace just before the expression
+    pub leading: Vec<Trivia>,
+    /// Comments after the expression on the same line or before
+    /// the next operator or delimiter, and the whitespace before them
+    pub trailing: Vec<Trivia>,
+}
+
+impl PositionInfo {
+    /// The byte offsets of the start and end of the expression without
+    /// the whitespace around it
+    pub fn span(&self) -> (usize, usize) {
+        (self.inner_start, self.inner_end)
+    }
 }
 
 pub type ParseInfo = Option<Box<PositionInfo>>;
 
 fn parse_info(start: &Span, end: &Span) -> ParseInfo {
+    let text = &start.fragment()[..(end.location_offset() - start.location_offset())];
+    let trimmed = text.trim_end();
+    let inner_start = start.location_offset() + (trimmed.len() - trimmed.trim_start().len());
     let inner = PositionInfo {
         start: start.location_offset(),
         start_line: start.location_line(),
         end: end.location_offset(),
         end_line: end.location_line(),
-        text: start.fragment()[..(end.location_offset() - start.location_offset())].to_string(),
+        text: text.to_string(),
+        inner_start,
+        inner_end: start.location_offset() + trimmed.len(),
+        leading: vec![],
+        trailing: vec![],
     };
     Some(Box::from(inner))
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Expression {
     Int(i128, ParseInfo),
     Float(f64, ParseInfo),
@@ -78,7 +343,104 @@ pub enum Expression {
     Range(Range, ParseInfo),
     Function(String, Vec<Expression>, Vec<Expression>, ParseInfo),
     Infix(String, Box<Expression>, Box<Expression>, ParseInfo),
+    Prefix(String, Box<Expression>, ParseInfo),
+    Postfix(String, Box<Expression>, ParseInfo),
     Let(String, Box<Expression>, Box<Expression>, ParseInfo),
+    /// An array constant, `{1, 2, 3; 4, 5, 6}`, as rows of items. Every
+    /// row has the same number of items
+    Array(Vec<Vec<Expression>>, ParseInfo),
+    /// An error written as a value, like `#REF!`
+    ErrorValue(CellError, ParseInfo),
+    /// Something required that isn't in the formula and a description
+    /// of what was expected. Only produced by `partial_expr_str`
+    Missing(String, ParseInfo),
+    /// Text that couldn't be parsed. Only produced by `partial_expr_str`
+    Error(String, ParseInfo),
+}
+
+impl Expression {
+    /// Where the expression is in the formula it was parsed from
+    pub fn parse_info(&self) -> &ParseInfo {
+        match self {
+            Expression::Int(_, info)
+            | Expression::Float(_, info)
+            | Expression::Str(_, info)
+            | Expression::DottedIdentifier(_, info)
+            | Expression::Identifier(_, info)
+            | Expression::Paren(_, info)
+            | Expression::Address(_, info)
+            | Expression::Range(_, info)
+            | Expression::Function(_, _, _, info)
+            | Expression::Infix(_, _, _, info)
+            | Expression::Prefix(_, _, info)
+            | Expression::Postfix(_, _, info)
+            | Expression::Let(_, _, _, info)
+            | Expression::Array(_, info)
+            | Expression::ErrorValue(_, info)
+            | Expression::Missing(_, info)
+            | Expression::Error(_, info) => info,
+        }
+    }
+
+    pub fn parse_info_mut(&mut self) -> &mut ParseInfo {
+        match self {
+            Expression::Int(_, info)
+            | Expression::Float(_, info)
+            | Expression::Str(_, info)
+            | Expression::DottedIdentifier(_, info)
+            | Expression::Identifier(_, info)
+            | Expression::Paren(_, info)
+            | Expression::Address(_, info)
+            | Expression::Range(_, info)
+            | Expression::Function(_, _, _, info)
+            | Expression::Infix(_, _, _, info)
+            | Expression::Prefix(_, _, info)
+            | Expression::Postfix(_, _, info)
+            | Expression::Let(_, _, _, info)
+            | Expression::Array(_, info)
+            | Expression::ErrorValue(_, info)
+            | Expression::Missing(_, info)
+            | Expression::Error(_, info) => info,
+        }
+    }
+
+    /// The expressions directly inside this one in the order they appear
+    /// in the formula
+    pub fn children(&self) -> Vec<&Expression> {
+        match self {
+            Expression::Paren(e, _)
+            | Expression::Prefix(_, e, _)
+            | Expression::Postfix(_, e, _) => {
+                vec![e]
+            }
+            Expression::Infix(_, left, right, _) | Expression::Let(_, left, right, _) => {
+                vec![left, right]
+            }
+            Expression::Function(_, type_params, params, _) => {
+                type_params.iter().chain(params.iter()).collect()
+            }
+            Expression::Array(rows, _) => rows.iter().flatten().collect(),
+            _ => vec![],
+        }
+    }
+
+    pub fn children_mut(&mut self) -> Vec<&mut Expression> {
+        match self {
+            Expression::Paren(e, _)
+            | Expression::Prefix(_, e, _)
+            | Expression::Postfix(_, e, _) => {
+                vec![e]
+            }
+            Expression::Infix(_, left, right, _) | Expression::Let(_, left, right, _) => {
+                vec![left, right]
+            }
+            Expression::Function(_, type_params, params, _) => {
+                type_params.iter_mut().chain(params.iter_mut()).collect()
+            }
+            Expression::Array(rows, _) => rows.iter_mut().flatten().collect(),
+            _ => vec![],
+        }
+    }
 }
 
 impl PartialEq for Expression {
@@ -104,11 +466,25 @@ impl PartialEq for Expression {
             {
                 true
             }
+            (Expression::Prefix(x1, x2, _), Expression::Prefix(y1, y2, _))
+                if x1 == y1 && x2 == y2 =>
+            {
+                true
+            }
+            (Expression::Postfix(x1, x2, _), Expression::Postfix(y1, y2, _))
+                if x1 == y1 && x2 == y2 =>
+            {
+                true
+            }
             (Expression::Let(x1, x2, x3, _), Expression::Let(y1, y2, y3, _))
                 if x1 == y1 && x2 == y2 && x3 == y3 =>
             {
                 true
             }
+            (Expression::Array(x, _), Expression::Array(y, _)) if x == y => true,
+            (Expression::ErrorValue(x, _), Expression::ErrorValue(y, _)) if x == y => true,
+            (Expression::Missing(x, _), Expression::Missing(y, _)) if x == y => true,
+            (Expression::Error(x, _), Expression::Error(y, _)) if x == y => true,
 
             _ => false,
         }
@@ -132,7 +508,7 @@ fn not_str_tag(to_match: &str) -> impl Fn(Span) -> IResult<Span, String> {
         let s2: &str = &the_str;
         match str_tag(s2)(s) {
             Err(_) => take(1u32)(s).map(|(x, res)| (x, res.to_string())),
-            Ok(_) => Err(Err::Error(nom::error::Error::new(s, ErrorKind::Tag))),
+            Ok(_) => Err(Err::Error(ParseFailure::from_error_kind(s, ErrorKind::Tag))),
         }
     }
 }
@@ -151,18 +527,18 @@ fn parser_comment(input: Span) -> IResult<Span, String> {
 }
 
 fn parser_comment_as_str(input: Span) -> IResult<Span, Span> {
-    parser_comment(input).map(|(x, _)| (x, Span::new("")))
+    parser_comment(input).map(|(x, _)| (x, span("")))
 }
 
 #[test]
 fn test_parser_comment() {
     assert_eq!(
-        parser_comment(Span::new("/*foo*/")).map(|(_, y)| y),
+        parser_comment(span("/*foo*/")).map(|(_, y)| y),
         Ok("/*foo*/".to_string()),
         "Looking for a valid comment"
     );
     assert_eq!(
-        parser_comment(Span::new("/* /* foo 32 */ */")).map(|(_, y)| y),
+        parser_comment(span("/* /* foo 32 */ */")).map(|(_, y)| y),
         Ok("/*/* foo 32 */*/".to_string())
     );
 }
@@ -174,7 +550,7 @@ fn parser_comment_eol(input: Span) -> IResult<Span, Span> {
         take_till(|c| c == '\r' || c == '\n'),
         &parser_whitespaces,
     ))(input)
-    .map(|(rest, _)| (rest, Span::new("")))
+    .map(|(rest, _)| (rest, span("")))
 }
 
 /// Join a `Vec` of `&str` into a `String`
@@ -203,11 +579,11 @@ fn parser_let(input: Span) -> IResult<Span, Expression> {
     tuple((
         &parser_comment_whitespaces,
         tag("let"),
-        &parser_identifier_string,
+        expecting("a name", &parser_identifier_string),
         &parser_comment_whitespaces,
-        tag("="),
+        char('='),
         &expr,
-        tag(";"),
+        parser_closing(';'),
         &expr,
     ))(input)
     .map(|(rest, (_, _, id, _, _, e1, _, e2))| {
@@ -224,16 +600,16 @@ fn parser_let(input: Span) -> IResult<Span, Expression> {
 }
 
 fn parser_raw_opr(input: Span) -> IResult<Span, Span> {
-    alt((
-        tag("&&"),
-        tag("=="),
-        tag("+"),
-        tag("-"),
-        tag("*"),
-        tag(">"),
-        tag("/"),
-        tag("^"),
-    ))(input)
+    for (opr, _) in BINARY_OPERATORS.iter() {
+        if let Ok(found) = tag::<_, _, ParseFailure<Span>>(*opr)(input) {
+            return Ok(found);
+        }
+    }
+    record_failure(&input, Some("an operator".to_string()));
+    Err(Err::Error(ParseFailure::from_error_kind(
+        input,
+        ErrorKind::Tag,
+    )))
 }
 
 fn parser_opr(input: Span) -> IResult<Span, Span> {
@@ -249,104 +625,329 @@ fn parser_sign(input: Span) -> IResult<Span, Option<Span>> {
     opt(alt((tag("+"), tag("-"))))(input)
 }
 
+/// Digits with optional `_` separators between them (`1_000_000`). The
+/// separators are dropped
+fn parser_digits(input: Span) -> IResult<Span, String> {
+    recognize(tuple((digit1, many0(tuple((char('_'), digit1))))))(input)
+        .map(|(rest, d)| (rest, d.fragment().replace('_', "")))
+}
+
+/// Hex digits after `0x` with optional `_` separators
+fn parser_hex_digits(input: Span) -> IResult<Span, String> {
+    preceded(
+        alt((tag("0x"), tag("0X"))),
+        recognize(tuple((hex_digit1, many0(tuple((char('_'), hex_digit1)))))),
+    )(input)
+    .map(|(rest, d)| (rest, d.fragment().replace('_', "")))
+}
+
+/// An integer: decimal (`1_000`) or hex (`0xFF`). An integer that doesn't
+/// fit in an `i128` is reported rather than quietly losing precision
 fn parser_int(input: Span) -> IResult<Span, Expression> {
-    match tuple((
-        opt(&parser_comment_whitespaces),
+    let (at, _) = opt(&parser_comment_whitespaces)(input)?;
+    let (rest, (sign, (radix, digits), _)) = tuple((
         &parser_sign,
-        digit1,
+        alt((
+            parser_hex_digits.map(|d| (16, d)),
+            // a float that's too large isn't an integer either
+            terminated(
+                &parser_digits,
+                not(alt((tag("."), recognize(&parser_exponent)))),
+            )
+            .map(|d| (10, d)),
+        )),
         opt(&parser_comment_whitespaces),
-    ))(input)
-    {
-        Ok((rest, (_, sign, i, _))) => {
-            let sign_mult: i128 = match sign {
-                Some(zz) if zz == Span::new("-") => -1i128,
-                _ => 1i128,
-            };
-
-            match i.fragment().parse::<i128>() {
-                Ok(i2) => Ok((
-                    rest,
-                    Expression::Int(i2 * sign_mult, parse_info(&input, &rest)),
-                )),
-                Result::Err(_) => {
-                    Result::Err(Err::Error(nom::error::Error::new(input, ErrorKind::Digit)))
-                }
-            }
+    ))(at)?;
+
+    let sign = match sign {
+        Some(s) if *s.fragment() == "-" => "-",
+        _ => "",
+    };
+
+    match i128::from_str_radix(&(sign.to_string() + &digits), radix) {
+        Ok(i) => Ok((rest, Expression::Int(i, parse_info(&input, &rest)))),
+        Result::Err(_) => {
+            let text = at.fragment()[..rest.location_offset() - at.location_offset()].trim_end();
+            record_problem(
+                &at,
+                format!(
+                    "the integer {} is too large: integers must be between {} and {}",
+                    text,
+                    i128::MIN,
+                    i128::MAX
+                ),
+            );
+            Result::Err(Err::Error(ParseFailure::from_error_kind(
+                input,
+                ErrorKind::TooLarge,
+            )))
         }
-        Err(x) => Err(x),
     }
 }
 
+/// The exponent of a float: `e9`, `E-9` or `e+9`
+fn parser_exponent(input: Span) -> IResult<Span, String> {
+    recognize(tuple((one_of("eE"), opt(one_of("+-")), digit1)))(input)
+        .map(|(rest, e)| (rest, e.fragment().to_string()))
+}
+
+/// A float: `1.5`, `.5`, `5.`, `1e-9` or `6.02E23`, with optional `_`
+/// separators in the digits. The text is converted with `lexical-core`
+/// so every literal maps to the nearest `f64`
 fn parser_float(input: Span) -> IResult<Span, Expression> {
-    tuple((
-        opt(&parser_comment_whitespaces),
+    let (at, _) = opt(&parser_comment_whitespaces)(input)?;
+    let (rest, (sign, (whole, fraction, exponent), _)) = tuple((
         &parser_sign,
-        digit1,
-        tag("."),
-        digit1,
+        alt((
+            tuple((
+                &parser_digits,
+                char('.'),
+                opt(&parser_digits),
+                opt(&parser_exponent),
+            ))
+            .map(|(w, _, f, e)| (w, f.unwrap_or_default(), e)),
+            tuple((char('.'), &parser_digits, opt(&parser_exponent)))
+                .map(|(_, f, e)| ("0".to_string(), f, e)),
+            tuple((&parser_digits, &parser_exponent)).map(|(w, e)| (w, "0".to_string(), Some(e))),
+        )),
         opt(&parser_comment_whitespaces),
-    ))(input)
-    .and_then(|(rest, (_, sign, sig, _, fr, _))| {
-        let first: String = sign.map(|c| c.to_string()).unwrap_or(String::from(""));
-        let front = first + sig.fragment() + ".";
-        let all = front + fr.fragment();
-
-        match all.parse::<f64>() {
-            Ok(i2) => Ok((rest, Expression::Float(i2, parse_info(&input, &rest)))),
-            Result::Err(_) => {
-                Result::Err(Err::Error(nom::error::Error::new(input, ErrorKind::Digit)))
-            }
+    ))(at)?;
+
+    let all = format!(
+        "{}{}.{}{}",
+        sign.map(|s| s.to_string()).unwrap_or_default(),
+        whole,
+        if fraction.is_empty() { "0" } else { &fraction },
+        exponent.unwrap_or_default()
+    );
+
+    match lexical_core::parse::<f64>(all.as_bytes()) {
+        Ok(f) if f.is_finite() => Ok((rest, Expression::Float(f, parse_info(&input, &rest)))),
+        _ => {
+            let text = at.fragment()[..rest.location_offset() - at.location_offset()].trim_end();
+            record_problem(&at, format!("the number {} is too large", text));
+            Result::Err(Err::Error(ParseFailure::from_error_kind(
+                input,
+                ErrorKind::TooLarge,
+            )))
         }
-    })
+    }
 }
 
 fn opt_char_to_string(oc: Option<char>) -> String {
     oc.map(|c| c.to_string()).unwrap_or(String::from(""))
 }
 
+/// A sheet name, either bare (`Sheet2`) or quoted (`'Q3 Sales'`) where a
+/// doubled quote is a quote in the name (`'Bob''s'`)
+fn parser_sheet_name(input: Span) -> IResult<Span, String> {
+    alt((
+        delimited(
+            char('\''),
+            many1(alt((
+                str_tag("''").map(|_| "'".to_string()),
+                is_not("'").map(|s: Span| s.to_string()),
+            ))),
+            char('\''),
+        )
+        .map(|v| vec_string_to_string(&v)),
+        tuple((
+            satisfy(|c| c.is_alphabetic() || c == '_'),
+            &alphanumeric_or_underscore0,
+        ))
+        .map(|(c, rest): (char, Span)| c.to_string() + rest.fragment()),
+    ))(input)
+}
+
+/// The sheet qualifier in front of a reference: `Sheet2!` or `Jan:Dec!`
+fn parser_sheet_ref(input: Span) -> IResult<Span, SheetRef> {
+    tuple((
+        &parser_comment_whitespaces,
+        &parser_sheet_name,
+        opt(tuple((char(':'), &parser_sheet_name))),
+        char('!'),
+    ))(input)
+    .map(|(rest, (_, first, last, _))| {
+        (
+            rest,
+            SheetRef {
+                first,
+                last: last.map(|(_, l)| l),
+            },
+        )
+    })
+}
+
+/// A cell address in the style the formula is written in
 fn parser_address_addr(input: Span) -> IResult<Span, Address> {
+    match in_r1c1(&input) {
+        true => parser_r1c1_addr(input),
+        false => parser_a1_addr(input),
+    }
+}
+
+fn parser_a1_addr(input: Span) -> IResult<Span, Address> {
     tuple((
         opt(&parser_comment_whitespaces),
         opt(char('$')),
         alpha1,
         opt(char('$')),
         digit1,
+        not(satisfy(|c| c.is_alphanumeric() || c == '_')),
         opt(parser_comment_whitespaces),
     ))(input)
-    .map(|(rest, (_, ab_col, col, ab_row, row, _))| {
+    .map(|(rest, (_, ab_col, col, ab_row, row, _, _))| {
         (
             rest,
             Address {
+                sheet: None,
                 addr: (opt_char_to_string(ab_col)
                     + col.fragment()
                     + &opt_char_to_string(ab_row)
                     + row.fragment())
                 .to_uppercase(),
+                notation: Notation::A1,
+            },
+        )
+    })
+}
+
+/// One half of an R1C1 address: `R` or `C` followed by a row or column
+/// number (`R3`), an offset from the formula's cell in brackets (`R[-1]`)
+/// or nothing for the formula's own row or column. An offset of zero is
+/// kept as nothing
+fn parser_r1c1_part<'a>(letter: char) -> impl Fn(Span<'a>) -> IResult<Span<'a>, String> {
+    move |input| {
+        preceded(
+            satisfy(|c| c.to_ascii_uppercase() == letter),
+            opt(alt((
+                map_opt(digit1, |d: Span| {
+                    d.fragment().parse::<i32>().ok().map(|n| n.to_string())
+                }),
+                map_opt(
+                    delimited(
+                        char('['),
+                        recognize(tuple((opt(one_of("+-")), digit1))),
+                        char(']'),
+                    ),
+                    |d: Span| {
+                        d.fragment().parse::<i32>().ok().map(|n| match n {
+                            0 => "".to_string(),
+                            n => format!("[{}]", n),
+                        })
+                    },
+                ),
+            ))),
+        )(input)
+        .map(|(rest, part)| (rest, format!("{}{}", letter, part.unwrap_or_default())))
+    }
+}
+
+/// An address in R1C1 style: `R1C1`, `R[-1]C[2]` or `RC[-3]`
+fn parser_r1c1_addr(input: Span) -> IResult<Span, Address> {
+    tuple((
+        opt(&parser_comment_whitespaces),
+        parser_r1c1_part('R'),
+        parser_r1c1_part('C'),
+        not(satisfy(|c| c.is_alphanumeric() || c == '_' || c == '[')),
+        opt(parser_comment_whitespaces),
+    ))(input)
+    .map(|(rest, (_, row, col, _, _))| {
+        (
+            rest,
+            Address {
+                sheet: None,
+                addr: row + &col,
+                notation: Notation::R1C1,
             },
         )
     })
 }
 
 fn parser_address(input: Span) -> IResult<Span, Expression> {
-    parser_address_addr(input)
-        .map(|(rest, a)| (rest, Expression::Address(a, parse_info(&input, &rest))))
+    tuple((opt(&parser_sheet_ref), &parser_address_addr))(input).map(|(rest, (sheet, a))| {
+        (
+            rest,
+            Expression::Address(Address { sheet, ..a }, parse_info(&input, &rest)),
+        )
+    })
+}
+
+/// A corner of a range: a cell (`B7`), a whole column (`B`) or a
+/// whole row (`7`)
+fn parser_range_corner(input: Span) -> IResult<Span, Address> {
+    let boundary = |c: char| c.is_alphanumeric() || c == '_' || c == '.';
+    alt((
+        &parser_address_addr,
+        tuple((
+            opt(&parser_comment_whitespaces),
+            opt(char('$')),
+            alt((alpha1, digit1)),
+            not(satisfy(boundary)),
+            opt(&parser_comment_whitespaces),
+        ))
+        .map(|(_, absolute, part, _, _)| Address {
+            sheet: None,
+            addr: (opt_char_to_string(absolute) + part.fragment()).to_uppercase(),
+            notation: Notation::A1,
+        }),
+    ))(input)
 }
 
+/// Whether a range corner names a column and whether it names a row
+fn corner_parts(corner: &Address) -> (bool, bool) {
+    if corner.notation == Notation::R1C1 {
+        return (true, true);
+    }
+    (
+        corner.addr.contains(|c: char| c.is_ascii_alphabetic()),
+        corner.addr.contains(|c: char| c.is_ascii_digit()),
+    )
+}
+
+/// A range is between two cells (`A1:B7`), whole columns (`A:C`), whole
+/// rows (`3:5`) or from a cell to the end of a column (`A2:A`)
 fn parser_range(input: Span) -> IResult<Span, Expression> {
-    tuple((&parser_address_addr, tag(":"), &parser_address_addr))(input).map(
-        |(rest, (ul, _, lr))| {
-            (
-                rest,
-                Expression::Range(
-                    Range {
-                        upper_left: ul,
-                        lower_right: lr,
-                    },
-                    parse_info(&input, &rest),
-                ),
+    verify(
+        tuple((
+            opt(&parser_sheet_ref),
+            &parser_range_corner,
+            tag(":"),
+            &parser_range_corner,
+        )),
+        |(_, ul, _, lr)| {
+            matches!(
+                (corner_parts(ul), corner_parts(lr)),
+                ((true, true), (true, true))
+                    | ((true, false), (true, false))
+                    | ((false, true), (false, true))
+                    | ((true, true), (true, false))
             )
         },
-    )
+    )(input)
+    .map(|(rest, (sheet, ul, _, lr))| {
+        (
+            rest,
+            Expression::Range(
+                Range {
+                    sheet,
+                    upper_left: ul,
+                    lower_right: lr,
+                },
+                parse_info(&input, &rest),
+            ),
+        )
+    })
+}
+
+/// Match the closing delimiter `c`. A recovering parse carries on as if
+/// a missing delimiter were there
+fn parser_closing<'a>(c: char) -> impl Fn(Span<'a>) -> IResult<Span<'a>, ()> {
+    move |input: Span<'a>| match char(c)(input) {
+        Ok((rest, _)) => Ok((rest, ())),
+        Err(_) if recovering(&input) => Ok((input, ())),
+        Err(e) => Err(e),
+    }
 }
 
 fn parser_paren(input: Span) -> IResult<Span, Expression> {
@@ -356,7 +957,7 @@ fn parser_paren(input: Span) -> IResult<Span, Expression> {
         &parser_comment_whitespaces,
         &expr,
         &parser_comment_whitespaces,
-        char(')'),
+        parser_closing(')'),
         &parser_comment_whitespaces,
     ))(input)
     .map(|(rest, (_, _, _, r, _, _, _))| {
@@ -367,22 +968,112 @@ fn parser_paren(input: Span) -> IResult<Span, Expression> {
     })
 }
 
+/// An array constant: items separated by `,` in rows separated by `;`
+/// as in `{1, 2, 3; 4, 5, 6}`
+fn parser_array(input: Span) -> IResult<Span, Expression> {
+    let (rest, (_, open, rows, _, _)) = tuple((
+        &parser_comment_whitespaces,
+        recognize(char('{')),
+        separated_list1(char(';'), separated_list1(char(','), &expr)),
+        parser_closing('}'),
+        &parser_comment_whitespaces,
+    ))(input)?;
+
+    if rows.iter().any(|row| row.len() != rows[0].len()) {
+        record_problem(
+            &open,
+            "every row of an array must have the same number of items".into(),
+        );
+        return Result::Err(Err::Error(ParseFailure::from_error_kind(
+            input,
+            ErrorKind::Verify,
+        )));
+    }
+
+    Ok((rest, Expression::Array(rows, parse_info(&input, &rest))))
+}
+
+/// A `\u{...}` escape: one to six hex digits naming a Unicode scalar value
+fn parser_unicode_escape(input: Span) -> IResult<Span, char> {
+    map_opt(
+        delimited(
+            tag("u{"),
+            take_while_m_n(1, 6, |c: char| c.is_ascii_hexdigit()),
+            char('}'),
+        ),
+        |hex: Span| {
+            u32::from_str_radix(hex.fragment(), 16)
+                .ok()
+                .and_then(char::from_u32)
+        },
+    )(input)
+}
+
+/// A backslash escape in a string: `\"`, `\\`, `\n`, `\t`, `\r`, `\0` or
+/// `\u{...}`
+fn parser_escape(input: Span) -> IResult<Span, char> {
+    preceded(
+        char('\\'),
+        expecting(
+            "an escape sequence",
+            alt((
+                char('"'),
+                char('\\'),
+                char('n').map(|_| '\n'),
+                char('t').map(|_| '\t'),
+                char('r').map(|_| '\r'),
+                char('0').map(|_| '\0'),
+                &parser_unicode_escape,
+            )),
+        ),
+    )(input)
+}
+
+/// A string in double quotes. A quote in the string is either doubled,
+/// as in Excel (`"say ""hi"""`), or escaped with a backslash
 fn parser_string(input: Span) -> IResult<Span, Expression> {
     delimited(
         tuple((opt(&parser_comment_whitespaces), tag("\""))),
-        many0(is_not("\"")),
-        tuple((tag("\""), opt(&parser_comment_whitespaces))),
+        many0(alt((
+            is_not("\"\\").map(|s: Span| s.fragment().to_string()),
+            tag("\"\"").map(|_| "\"".to_string()),
+            parser_escape.map(|c| c.to_string()),
+        ))),
+        tuple((parser_closing('"'), opt(&parser_comment_whitespaces))),
     )(input)
     .map(|(rest, v)| {
         (
             rest,
-            Expression::Str(vec_span_to_string(&v), parse_info(&input, &rest)),
+            Expression::Str(vec_string_to_string(&v), parse_info(&input, &rest)),
         )
     })
 }
 
+/// An error written as a value, such as `#REF!` or `#DIV/0!`, in either
+/// case. Copying a formula can leave these behind, so they have to parse
+fn parser_error(input: Span) -> IResult<Span, Expression> {
+    delimited(
+        opt(&parser_comment_whitespaces),
+        map_opt(
+            recognize(tuple((
+                char('#'),
+                many1(satisfy(|c| c.is_ascii_alphanumeric() || c == '/')),
+                opt(one_of("!?")),
+            ))),
+            |text: Span| CellError::from_text(text.fragment()),
+        ),
+        opt(&parser_comment_whitespaces),
+    )(input)
+    .map(|(rest, e)| (rest, Expression::ErrorValue(e, parse_info(&input, &rest))))
+}
+
 fn parser_comma_list(input: Span) -> IResult<Span, Vec<Expression>> {
-    separated_list0(tag(","), &expr)(input)
+    let (at, _) = parser_comment_whitespaces(input)?;
+    if at.fragment().starts_with(')') || at.fragment().starts_with(']') {
+        return Ok((at, vec![]));
+    }
+
+    separated_list0(char(','), &expr)(input)
 }
 
 fn parser_dotted_identifier(input: Span) -> IResult<Span, Expression> {
@@ -391,7 +1082,7 @@ fn parser_dotted_identifier(input: Span) -> IResult<Span, Expression> {
         many1(tuple((
             &parser_comment_whitespaces,
             nom::character::complete::char('.'),
-            &parser_identifier_string,
+            expecting("a name", &parser_identifier_string),
             &parser_comment_whitespaces,
         ))),
     ))(input)
@@ -411,7 +1102,7 @@ fn parser_dotted_identifier(input: Span) -> IResult<Span, Expression> {
 #[test]
 fn test_parser_dotted_identifier() {
     assert_eq!(
-        parser_dotted_identifier(Span::new("x.y")).map(|(_, y)| y),
+        parser_dotted_identifier(span("x.y")).map(|(_, y)| y),
         Ok(Expression::DottedIdentifier(
             vec!["X".to_string(), "Y".to_string()],
             None
@@ -420,7 +1111,7 @@ fn test_parser_dotted_identifier() {
     );
 
     assert_eq!(
-        parser_dotted_identifier(Span::new(
+        parser_dotted_identifier(span(
             "  x  .
         
         y"
@@ -434,7 +1125,7 @@ fn test_parser_dotted_identifier() {
     );
 
     assert_eq!(
-        parser_dotted_identifier(Span::new("  frog32xx. moose ")).map(|(_, y)| y),
+        parser_dotted_identifier(span("  frog32xx. moose ")).map(|(_, y)| y),
         Ok(Expression::DottedIdentifier(
             vec!["FROG32XX".to_string(), "MOOSE".to_string()],
             None
@@ -442,7 +1133,7 @@ fn test_parser_dotted_identifier() {
     );
 
     assert_eq!(
-        parser_dotted_identifier(Span::new(
+        parser_dotted_identifier(span(
             "  frog32xx /*
         
         a comment */
@@ -461,29 +1152,27 @@ fn test_parser_dotted_identifier() {
     );
 }
 
-fn concat_str(x: &str, y: &str) -> String {
-    x.to_string() + y
-}
-
-fn alphanumeric_or_underscore0<T, E: ParseError<T>>(input: T) -> IResult<T, T, E>
+fn alphanumeric_or_underscore0<T, E: ParseError<T>>(input: T) -> nom::IResult<T, T, E>
 where
     T: InputTakeAtPosition,
     <T as InputTakeAtPosition>::Item: AsChar + Clone,
 {
     input.split_at_position_complete(|item| {
-        let ci = item.clone();
-        !(item.is_alphanum() || ci.as_char() == '_')
+        let c = item.as_char();
+        !(c.is_alphanumeric() || c == '_')
     })
 }
 
+/// A name starts with a letter, which may be any Unicode letter, and
+/// carries on with letters, digits and underscores
 fn parser_identifier_string(input: Span) -> IResult<Span, String> {
     tuple((
         &parser_comment_whitespaces,
-        &alpha1,
+        satisfy(|c| c.is_alphabetic()),
         &alphanumeric_or_underscore0,
         &parser_comment_whitespaces,
     ))(input)
-    .map(|(rest, (_, x, y, _))| (rest, concat_str(x.fragment(), y.fragment())))
+    .map(|(rest, (_, x, y, _))| (rest, x.to_string() + y.fragment()))
 }
 
 fn parser_identifier(input: Span) -> IResult<Span, Expression> {
@@ -502,18 +1191,18 @@ mod tests {
     #[test]
     fn test_parser_identifier() {
         assert_eq!(
-            parser_identifier(Span::new("x")).map(|(_, y)| y),
+            parser_identifier(span("x")).map(|(_, y)| y),
             Ok(ex_id("x")),
             "single letter variable"
         );
 
         assert_eq!(
-            parser_identifier(Span::new("  frog32xx ")).map(|(_, y)| y),
+            parser_identifier(span("  frog32xx ")).map(|(_, y)| y),
             Ok(ex_id("FROG32XX"))
         );
 
         assert_eq!(
-            parser_identifier(Span::new(
+            parser_identifier(span(
                 "  frog32xx
         
         
@@ -526,80 +1215,183 @@ mod tests {
 }
 
 fn parser_function(input: Span) -> IResult<Span, Expression> {
-    tuple((
+    let (rest, (func_name, opt_type_param)) = tuple((
         &parser_identifier_string,
-        opt(delimited(tag("["), &parser_comma_list, tag("]"))), // FIXME whitespace
-        delimited(tag("("), &parser_comma_list, tag(")")),      // FIXME whitespace
+        opt(delimited(
+            char('['),
+            &parser_comma_list,
+            parser_closing(']'),
+        )), // FIXME whitespace
+    ))(input)?;
+
+    // FIXME whitespace
+    let params = delimited(char('('), &parser_comma_list, parser_closing(')'))(rest);
+    let (rest, params) = match params {
+        Ok(found) => found,
+        // an incomplete formula may not have got to the parameters yet
+        Err(_) if recovering(&rest) && opt_type_param.is_some() => (rest, vec![]),
+        Err(e) => return Err(e),
+    };
+    let (rest, _) = parser_comment_whitespaces(rest)?;
+
+    Ok((
+        rest,
+        Expression::Function(
+            func_name.to_uppercase(),
+            opt_type_param.unwrap_or_default(),
+            params,
+            parse_info(&input, &rest),
+        ),
+    ))
+}
+
+/// Parse a chain of binary operators by precedence climbing. Operators
+/// that bind less tightly than `min_prec` are left for the caller.
+fn parser_binary(input: Span, min_prec: i32) -> IResult<Span, Expression> {
+    let (mut rest, mut left) = parser_operand(input)?;
+
+    while let Ok((after_opr, opr)) = parser_opr(rest) {
+        let prec = precedence(opr.fragment());
+        if prec < min_prec {
+            break;
+        }
+        let opr = match *opr.fragment() {
+            "<>" => "!=",
+            o => o,
+        };
+
+        // everything on the right must bind more tightly so that
+        // operators of the same precedence associate to the left
+        let (after_right, right) = parser_binary(after_opr, prec + 1)?;
+        left = Expression::Infix(
+            opr.to_string(),
+            Box::from(left),
+            Box::from(right),
+            parse_info(&input, &after_right),
+        );
+        rest = after_right;
+    }
+
+    Ok((rest, left))
+}
+
+/// Parse an operand with any prefix (`-`, `+`, `!`) and postfix (`%`)
+/// operators. Unary operators bind more tightly than any binary
+/// operator, so `-A1^2` is `(-A1)^2` and `50%^2` is `(50%)^2`
+fn parser_unary(input: Span) -> IResult<Span, Expression> {
+    // a sign directly in front of digits is part of the number, so
+    // only look for a prefix operator if there's no plain operand
+    alt((&parser_postfix, &parser_prefix))(input)
+}
+
+fn parser_prefix(input: Span) -> IResult<Span, Expression> {
+    tuple((
         &parser_comment_whitespaces,
+        alt((tag("-"), tag("+"), tag("!"))),
+        &parser_operand,
     ))(input)
-    .map(|(rest, (func_name, opt_type_param, params, _))| {
+    .map(|(rest, (_, opr, operand))| {
         (
             rest,
-            Expression::Function(
-                func_name.to_uppercase(),
-                match opt_type_param {
-                    Some(x) => x,
-                    _ => vec![],
-                },
-                params,
+            Expression::Prefix(
+                opr.to_string(),
+                Box::from(operand),
                 parse_info(&input, &rest),
             ),
         )
     })
 }
 
-fn parser_opr_exp(input: Span) -> IResult<Span, Expression> {
-    tuple((&expr_mini, parser_opr, &expr))(input).map(|(rest, (left, oprs, right))| {
-        let opr = oprs.to_string();
-        let rexp = match right {
-            Expression::Infix(o2, sub_left, sub_right, _) if precedence(&o2) < precedence(&opr) => {
-                Expression::Infix(
-                    o2,
-                    Box::from(Expression::Infix(opr, Box::from(left), sub_left, None)), // FIXME -- can we get the parse info for the span?
-                    sub_right,
-                    parse_info(&input, &rest),
-                )
+/// An operand of a binary or prefix operator. In a recovering parse, an
+/// operand that can't be parsed is `Missing` if it's absent or an
+/// `Error` holding the text that isn't an operand
+fn parser_operand(input: Span) -> IResult<Span, Expression> {
+    match parser_unary(input) {
+        Err(Err::Error(_)) if recovering(&input) => {
+            let (at, _) = parser_comment_whitespaces(input)?;
+            let next = at.fragment().chars().next();
+            match next {
+                None | Some(',' | ')' | ']' | '}' | ';') => Ok((
+                    at,
+                    Expression::Missing("an expression".into(), parse_info(&at, &at)),
+                )),
+                Some(_) if parser_raw_opr(at).is_ok() => Ok((
+                    at,
+                    Expression::Missing("an expression".into(), parse_info(&at, &at)),
+                )),
+                Some(c) => {
+                    let len = if c.is_alphanumeric() {
+                        at.fragment()
+                            .find(|c: char| !c.is_alphanumeric())
+                            .unwrap_or(at.fragment().len())
+                    } else {
+                        c.len_utf8()
+                    };
+                    let (rest, bad) = take(len)(at)?;
+                    Ok((
+                        rest,
+                        Expression::Error(bad.to_string(), parse_info(&at, &rest)),
+                    ))
+                }
             }
-            _ => Expression::Infix(
-                opr,
-                Box::from(left),
-                Box::from(right),
-                parse_info(&input, &rest),
-            ),
-        };
-        (rest, rexp)
-    })
+        }
+        res => res,
+    }
 }
 
-fn expr_mini(input: Span) -> IResult<Span, Expression> {
-    alt((
-        &parser_let,
-        &parser_paren,
-        &parser_dotted_identifier,
-        &parser_function,
-        &parser_address,
-        &parser_range,
-        &parser_identifier,
-        &parser_string,
-        &parser_float,
-        &parser_int,
-    ))(input)
+fn parser_postfix(input: Span) -> IResult<Span, Expression> {
+    let (mut rest, mut operand) = expr_operand(input)?;
+
+    while let Some(after) = parser_percent(rest) {
+        operand = Expression::Postfix(
+            "%".to_string(),
+            Box::from(operand),
+            parse_info(&input, &after),
+        );
+        rest = after;
+    }
+
+    Ok((rest, operand))
+}
+
+/// A `%` followed by something that can start an operand other than a
+/// sign is modulo (`7 % 3`). Any other `%` is a percentage, so `50% + 10`
+/// adds 10 to a half. Whitespace doesn't matter: `7%-3` and `7 % -3` both
+/// take 3 from a percentage and modulo by a negative is `7 % (-3)`
+fn parser_percent(rest: Span) -> Option<Span> {
+    let (after, _) = tag::<_, _, ParseFailure<Span>>("%")(rest).ok()?;
+    let (after, _) = parser_comment_whitespaces(after).ok()?;
+    let text = after.fragment();
+    match text.chars().next() {
+        Some('!') if text.starts_with("!=") => Some(after),
+        Some(c) if c.is_alphanumeric() || "_$.\"'({!#".contains(c) => None,
+        _ => Some(after),
+    }
+}
+
+/// An operand of a binary operator
+fn expr_operand(input: Span) -> IResult<Span, Expression> {
+    expecting(
+        "an expression",
+        alt((
+            &parser_let,
+            &parser_paren,
+            &parser_array,
+            &parser_dotted_identifier,
+            &parser_function,
+            &parser_range,
+            &parser_address,
+            &parser_identifier,
+            &parser_string,
+            &parser_error,
+            &parser_float,
+            &parser_int,
+        )),
+    )(input)
 }
 
 fn expr(input: Span) -> IResult<Span, Expression> {
-    alt((
-        &parser_let,
-        &parser_opr_exp,
-        &parser_paren,
-        &parser_dotted_identifier,
-        &parser_function,
-        &parser_range,
-        &parser_identifier,
-        &parser_address,
-        &parser_string,
-        &parser_float,
-        &parser_int,
-    ))(input)
+    parser_binary(input, 1)
 }
 
 // pub fn tvs(input: Vec<&str>) -> Vec<String> {
@@ -611,12 +1403,12 @@ fn test_expr() {
     use crate::parser_util::{ex_dot, ex_fun, ex_id};
 
     assert_eq!(
-        expr(Span::new(" foo /* cat */ ")).map(|(_, y)| y),
+        expr(span(" foo /* cat */ ")).map(|(_, y)| y),
         Ok(ex_id("FOO"))
     );
 
     assert_eq!(
-        expr(Span::new(
+        expr(span(
             " cat(dog, 
             
             moose.cat, /*mo
@@ -637,17 +1429,158 @@ fn test_expr() {
     );
 }
 
-pub fn whole_expr_str<'a>(input: &'a str) -> Result<Expression, nom::Err<nom::error::Error<Span>>> {
-    whole_expr(Span::new(input))
+#[test]
+fn test_infix_parse_info() {
+    fn check(e: &Expression) {
+        if let Expression::Infix(_, left, right, info) = e {
+            assert!(info.is_some(), "Infix node {:?} has parse info", e);
+            check(left);
+            check(right);
+        }
+    }
+
+    let formula = "1 - 2 - 3 * 4 - 5";
+    let (_, e) = expr(span(formula)).unwrap();
+    check(&e);
+    let text = |info: ParseInfo| {
+        let info = info.unwrap();
+        assert_eq!(info.text, &formula[info.start..info.end]);
+        (&formula[info.start..info.end], info.span())
+    };
+    match e {
+        Expression::Infix(_, left, _, info) => {
+            assert_eq!(text(info), ("1 - 2 - 3 * 4 - 5", (0, 17)));
+            match *left {
+                Expression::Infix(_, _, _, info) => {
+                    assert_eq!(text(info), ("1 - 2 - 3 * 4 ", (0, 13)))
+                }
+                x => panic!("Expected an Infix, got {:?}", x),
+            }
+        }
+        x => panic!("Expected an Infix, got {:?}", x),
+    }
+}
+
+/// Parse a whole formula, optionally starting with `=`. If the formula
+/// can't be parsed, the result describes each problem found
+pub fn whole_expr_str(input: &str) -> Result<Expression, Vec<ParseDiagnostic>> {
+    parse_whole(input, ParseContext::default())
 }
 
-pub fn whole_expr(input: Span) -> Result<Expression, nom::Err<nom::error::Error<Span>>> {
-    match tuple((opt(tag("=")), &expr))(input) {
-        Ok((zz, (_, e))) if zz.fragment() == &"" => Ok(e),
-        Ok((rest, _)) => Result::Err(nom::Err::Error(nom::error::Error::new(
-            rest,
-            ErrorKind::Complete,
-        ))),
-        Result::Err(x) => Result::Err(x), // Err(Err(_, error)) => Err(error)
+/// Parse a whole formula with its cell addresses in R1C1 style, like
+/// `R[-1]C + R1C1`. Whole rows and columns are still written `A:C` and
+/// `3:5`
+pub fn whole_r1c1_expr_str(input: &str) -> Result<Expression, Vec<ParseDiagnostic>> {
+    parse_whole(
+        input,
+        ParseContext {
+            r1c1: true,
+            ..ParseContext::default()
+        },
+    )
+}
+
+fn parse_whole(input: &str, context: ParseContext) -> Result<Expression, Vec<ParseDiagnostic>> {
+    let parsed = tuple((opt(tag("=")), &expr))(Span::new_extra(input, Some(&context)));
+
+    match parsed {
+        Ok((rest, (_, mut e))) if rest.fragment().is_empty() => {
+            attach_trivia(input, &mut e);
+            Ok(e)
+        }
+        Ok((rest, _)) => {
+            record_failure(&rest, None);
+            Err(context.diagnostics(input))
+        }
+        Err(_) => Err(context.diagnostics(input)),
     }
 }
+
+/// The result of parsing a formula that may be incomplete or wrong
+#[derive(Debug, Clone, PartialEq)]
+pub struct PartialParse {
+    /// The best tree for the formula. Anything missing is an
+    /// `Expression::Missing` and anything that couldn't be parsed is an
+    /// `Expression::Error`
+    pub expr: Expression,
+    pub diagnostics: Vec<ParseDiagnostic>,
+}
+
+/// Parse a formula that may be incomplete, such as one that's still being
+/// typed. Rather than failing, the parse carries on past missing
+/// operands and closing delimiters so tools like autocomplete have the
+/// structure of what's there
+pub fn partial_expr_str(input: &str) -> PartialParse {
+    let context = ParseContext {
+        recover: true,
+        ..ParseContext::default()
+    };
+
+    let parsed = tuple((opt(tag("=")), &expr))(Span::new_extra(input, Some(&context)));
+
+    let (mut expr, mut diagnostics) = match parsed {
+        Ok((rest, (_, e))) if rest.fragment().is_empty() => (e, check_delimiters(input)),
+        Ok((rest, (_, e))) => {
+            let mut found = check_delimiters(input);
+            found.push(ParseDiagnostic::unexpected(
+                input,
+                rest.location_offset(),
+                vec!["an operator".into()],
+            ));
+            (e, found)
+        }
+        Err(_) => {
+            let at = span(input);
+            (
+                Expression::Missing("an expression".into(), parse_info(&at, &at)),
+                context.diagnostics(input),
+            )
+        }
+    };
+
+    // problems come before the recovered nodes at the same place so
+    // they're the ones that are kept
+    diagnostics.extend(context.problem_diagnostics(input));
+    collect_recovered(input, &expr, &mut diagnostics);
+    attach_trivia(input, &mut expr);
+    diagnostics.sort_by_key(|d| d.offset);
+    diagnostics.dedup_by_key(|d| d.offset);
+
+    PartialParse { expr, diagnostics }
+}
+
+/// Add diagnostics for the `Missing` and `Error` nodes in a tree
+fn collect_recovered(formula: &str, expr: &Expression, to: &mut Vec<ParseDiagnostic>) {
+    struct Recovered<'a> {
+        formula: &'a str,
+        to: &'a mut Vec<ParseDiagnostic>,
+    }
+
+    impl Visitor for Recovered<'_> {
+        fn visit_missing(&mut self, what: &str, info: &ParseInfo) {
+            let offset = info.as_ref().map(|i| i.start).unwrap_or(0);
+            self.to.push(ParseDiagnostic::unexpected(
+                self.formula,
+                offset,
+                vec![what.to_string()],
+            ))
+        }
+
+        fn visit_error(&mut self, _text: &str, info: &ParseInfo) {
+            let offset = info.as_ref().map(|i| i.start).unwrap_or(0);
+            self.to.push(ParseDiagnostic::unexpected(
+                self.formula,
+                offset,
+                vec!["an expression".into()],
+            ))
+        }
+    }
+
+    Recovered { formula, to }.visit_expr(expr)
+}
+
+/// Parse the text of the span as a whole formula. Positions in the
+/// result are relative to the start of the span
+pub fn whole_expr(input: Span) -> Result<Expression, Vec<ParseDiagnostic>> {
+    whole_expr_str(input.fragment())
+}
@@ -1,10 +1,16 @@
-pub use crate::parser::{Address, Expression, Range};
+pub use crate::definitions::CellError;
+pub use crate::parser::{Address, Expression, Notation, Range, SheetRef};
 
 /// Creates an Expression::Str
 pub fn ex_str(s: &str) -> Expression {
     Expression::Str(s.to_string(), None)
 }
 
+/// Creates an `Expression::ErrorValue`
+pub fn ex_err(e: CellError) -> Expression {
+    Expression::ErrorValue(e, None)
+}
+
 /// Creates an `Expression::Identifier`
 pub fn ex_id(s: &str) -> Expression {
     Expression::Identifier(s.to_uppercase(), None)
@@ -20,11 +26,18 @@ pub fn ex_fun(name: &str, p1: Vec<Expression>, p2: Vec<Expression>) -> Expressio
     Expression::Function(name.to_uppercase(), p1, p2, None)
 }
 
+/// Creates an `Expression::Array` from its rows
+pub fn ex_arr(rows: Vec<Vec<Expression>>) -> Expression {
+    Expression::Array(rows, None)
+}
+
 /// Creates an `Expression::Address`
 pub fn ex_adr(ad: &str) -> Expression {
     Expression::Address(
         Address {
+            sheet: None,
             addr: ad.to_uppercase(),
+            notation: Notation::A1,
         },
         None,
     )
@@ -40,6 +53,21 @@ pub fn ex_inf(opr: &str, left: Expression, right: Expression) -> Expression {
     Expression::Infix(opr.to_uppercase(), Box::from(left), Box::from(right), None)
 }
 
+/// Creates an `Expression::Prefix`
+pub fn ex_pre(opr: &str, ex: Expression) -> Expression {
+    Expression::Prefix(opr.to_string(), Box::from(ex), None)
+}
+
+/// Creates an `Expression::Postfix`
+pub fn ex_post(opr: &str, ex: Expression) -> Expression {
+    Expression::Postfix(opr.to_string(), Box::from(ex), None)
+}
+
+/// Creates an `Expression::Missing`
+pub fn ex_missing(what: &str) -> Expression {
+    Expression::Missing(what.to_string(), None)
+}
+
 /// Creates an `Expression::Float`
 pub fn ex_f(f: f64) -> Expression {
     Expression::Float(f, None)
@@ -58,11 +86,51 @@ pub fn ex_let(var: &str, left: Expression, right: Expression) -> Expression {
 /// Creates an `Expression::Range`
 pub fn ex_rng(ul: &str, lr: &str) -> Expression {
     Expression::Range(Range {
+        sheet: None,
         upper_left: Address {
+            sheet: None,
             addr: ul.to_uppercase(),
+            notation: Notation::A1,
         },
         lower_right: Address {
+            sheet: None,
             addr: lr.to_uppercase(),
+            notation: Notation::A1,
         },
     }, None)
 }
+
+/// Puts an `Expression::Address` or `Expression::Range` on the sheets
+/// from `first` to `last`
+pub fn ex_on(first: &str, last: Option<&str>, ex: Expression) -> Expression {
+    let sheet = Some(SheetRef {
+        first: first.to_string(),
+        last: last.map(|l| l.to_string()),
+    });
+    match ex {
+        Expression::Address(a, info) => Expression::Address(Address { sheet, ..a }, info),
+        Expression::Range(r, info) => Expression::Range(Range { sheet, ..r }, info),
+        other => other,
+    }
+}
+
+/// Marks the addresses in an `Expression::Address` or `Expression::Range`
+/// as written in R1C1 style
+pub fn ex_r1c1(ex: Expression) -> Expression {
+    let r1c1 = |a: Address| Address {
+        notation: Notation::R1C1,
+        ..a
+    };
+    match ex {
+        Expression::Address(a, info) => Expression::Address(r1c1(a), info),
+        Expression::Range(r, info) => Expression::Range(
+            Range {
+                upper_left: r1c1(r.upper_left),
+                lower_right: r1c1(r.lower_right),
+                ..r
+            },
+            info,
+        ),
+        other => other,
+    }
+}
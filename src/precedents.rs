@@ -0,0 +1,197 @@
+//! The cells, ranges and names a formula reads
+//!
+
+// Copyright 2021 David Pollak
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::parser::{Address, Expression, ParseInfo, Range, SheetRef};
+use crate::visit::Visitor;
+
+/// A cell or range that a formula reads
+#[derive(Debug, PartialEq, Eq, Clone, Hash)]
+pub enum Reference {
+    Cell(Address),
+    Range(Range),
+}
+
+impl Reference {
+    /// The sheet the reference is qualified with. `None` is the sheet the
+    /// formula is on
+    pub fn sheet(&self) -> &Option<SheetRef> {
+        match self {
+            Reference::Cell(addr) => &addr.sheet,
+            Reference::Range(range) => &range.sheet,
+        }
+    }
+}
+
+/// What a formula reads. Each item is listed once in the order it first
+/// appears in the formula
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct Precedents {
+    pub references: Vec<Reference>,
+    /// Names that aren't bound by an enclosing `let`, which come from
+    /// the workbook. A dotted name (`cats.dogs`) is kept whole
+    pub free_names: Vec<String>,
+    /// Names that are bound by an enclosing `let`
+    pub bound_names: Vec<String>,
+}
+
+impl Precedents {
+    /// The sheets referenced by name. 3D references are listed as written
+    pub fn sheets(&self) -> Vec<&SheetRef> {
+        let mut ret: Vec<&SheetRef> = vec![];
+        for sheet in self.references.iter().flat_map(|r| r.sheet()) {
+            if !ret.contains(&sheet) {
+                ret.push(sheet);
+            }
+        }
+        ret
+    }
+}
+
+/// The precedents of `expr`: the cells, ranges and names it reads.
+/// `TRUE` and `FALSE` are values rather than names
+pub fn precedents(expr: &Expression) -> Precedents {
+    let mut finder = Finder::default();
+    finder.visit_expr(expr);
+    finder.found
+}
+
+/// Each cell and range reference in `expr` with the byte offsets of its
+/// start and end in the formula, in the order they appear. A reference
+/// that's in the formula twice is listed twice, so every occurrence can
+/// be highlighted. Trees that weren't parsed have no positions and give
+/// nothing
+pub fn reference_spans(expr: &Expression) -> Vec<(Reference, usize, usize)> {
+    let mut finder = Finder::default();
+    finder.visit_expr(expr);
+    finder.spans
+}
+
+#[derive(Default)]
+struct Finder {
+    found: Precedents,
+    spans: Vec<(Reference, usize, usize)>,
+    /// The names bound by the `let`s around the current expression
+    scope: Vec<String>,
+}
+
+impl Finder {
+    fn reference(&mut self, reference: Reference, info: &ParseInfo) {
+        if let Some(info) = info {
+            let (start, end) = info.span();
+            self.spans.push((reference.clone(), start, end));
+        }
+        if !self.found.references.contains(&reference) {
+            self.found.references.push(reference);
+        }
+    }
+
+    fn name(&mut self, first: &str, whole: String) {
+        let to = match self.scope.iter().any(|n| n == first) {
+            true => &mut self.found.bound_names,
+            false => &mut self.found.free_names,
+        };
+        if !to.contains(&whole) {
+            to.push(whole);
+        }
+    }
+}
+
+impl Visitor for Finder {
+    fn visit_address(&mut self, addr: &Address, info: &ParseInfo) {
+        self.reference(Reference::Cell(addr.clone()), info)
+    }
+
+    fn visit_range(&mut self, range: &Range, info: &ParseInfo) {
+        self.reference(Reference::Range(range.clone()), info)
+    }
+
+    fn visit_identifier(&mut self, name: &str, _info: &ParseInfo) {
+        if name != "TRUE" && name != "FALSE" {
+            self.name(name, name.to_string())
+        }
+    }
+
+    fn visit_dotted_identifier(&mut self, names: &[String], _info: &ParseInfo) {
+        if let Some(first) = names.first() {
+            self.name(first, names.join("."))
+        }
+    }
+
+    fn visit_let(&mut self, name: &str, value: &Expression, body: &Expression, _info: &ParseInfo) {
+        // the name isn't bound in its own value
+        self.visit_expr(value);
+        self.scope.push(name.to_string());
+        self.visit_expr(body);
+        self.scope.pop();
+    }
+}
+
+#[test]
+fn test_precedents() {
+    use crate::parser::whole_expr_str;
+    use crate::parser_util::*;
+
+    let found = precedents(
+        &whole_expr_str(
+            "IF(A1 > Data!B2, SUM(A1:B9, 'Q3 Sales'!C:C), rate) + let rate = rate * 2; rate + tax.total + rate.high + TRUE",
+        )
+        .unwrap(),
+    );
+    assert_eq!(
+        found.references,
+        vec![
+            Reference::Cell(Address {
+                sheet: None,
+                addr: "A1".into(),
+                notation: Notation::A1,
+            }),
+            Reference::Cell(Address {
+                sheet: Some(SheetRef {
+                    first: "Data".into(),
+                    last: None
+                }),
+                addr: "B2".into(),
+                notation: Notation::A1,
+            }),
+            match ex_rng("a1", "b9") {
+                Expression::Range(r, _) => Reference::Range(r),
+                _ => unreachable!(),
+            },
+            match ex_on("Q3 Sales", None, ex_rng("c", "c")) {
+                Expression::Range(r, _) => Reference::Range(r),
+                _ => unreachable!(),
+            },
+        ]
+    );
+    assert_eq!(found.free_names, vec!["RATE", "TAX.TOTAL"]);
+    assert_eq!(found.bound_names, vec!["RATE", "RATE.HIGH"]);
+    assert_eq!(
+        found
+            .sheets()
+            .iter()
+            .map(|s| s.first.as_str())
+            .collect::<Vec<&str>>(),
+        vec!["Data", "Q3 Sales"]
+    );
+
+    let formula = "A1 + sum( B2:C3 ) * A1";
+    let spans: Vec<&str> = reference_spans(&whole_expr_str(formula).unwrap())
+        .iter()
+        .map(|(_, start, end)| &formula[*start..*end])
+        .collect();
+    assert_eq!(spans, vec!["A1", "B2:C3", "A1"]);
+}
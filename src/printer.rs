@@ -0,0 +1,863 @@
+//! Turn an `Expression` back into formula text
+//!
+
+// Copyright 2021 David Pollak
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::parser::{
+    precedence, whole_expr_str, Address, Expression, PositionInfo, Range, SheetRef,
+};
+use crate::trivia::{trivia, Trivia, TriviaKind};
+
+/// How to lay out a printed formula
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct PrintOptions {
+    /// Anything that doesn't fit in this many characters is broken over
+    /// multiple lines
+    pub max_width: usize,
+    /// The number of spaces each level of nesting is indented by
+    pub indent: usize,
+    /// Keep the comments that the parser attached to the expressions
+    pub comments: bool,
+    /// Print the formula as it was written rather than in the canonical
+    /// form. `max_width` and `indent` are ignored
+    pub as_written: bool,
+}
+
+impl Default for PrintOptions {
+    fn default() -> Self {
+        PrintOptions {
+            max_width: 80,
+            indent: 4,
+            comments: true,
+            as_written: false,
+        }
+    }
+}
+
+impl PrintOptions {
+    /// Print everything that can be on one line on one line
+    pub fn single_line() -> PrintOptions {
+        PrintOptions {
+            max_width: usize::MAX,
+            ..PrintOptions::default()
+        }
+    }
+
+    /// Print a parsed formula exactly as it was written. A part of the
+    /// tree that's been changed since is printed in the canonical form,
+    /// keeping the comments and whitespace around it
+    pub fn as_written() -> PrintOptions {
+        PrintOptions {
+            as_written: true,
+            ..PrintOptions::default()
+        }
+    }
+}
+
+/// Print `expr` in the canonical form with the default options. Parsing
+/// the result of printing a tree from the parser gives the same tree
+pub fn print(expr: &Expression) -> String {
+    print_with(expr, &PrintOptions::default())
+}
+
+/// Print `expr` in the canonical form. A part of the formula that's too
+/// wide is broken over lines in the multi-line style: function
+/// parameters go on their own lines, a long operator chain breaks after
+/// the operator and the body of a `let` starts on a new line. A `//#`
+/// comment always ends its line. With `as_written` the formula is
+/// printed as it was written instead
+pub fn print_with(expr: &Expression, options: &PrintOptions) -> String {
+    let printer = Printer { options };
+    if options.as_written {
+        let (body, after) = printer.written(expr);
+        return body + &after;
+    }
+    printer.layout(expr, 0, 0).trim_end().to_string()
+}
+
+/// The precedence used for the operand of a prefix or postfix operator.
+/// Unary operators bind more tightly than any binary operator
+const UNARY_PRECEDENCE: i32 = i32::MAX;
+
+struct Printer<'a> {
+    options: &'a PrintOptions,
+}
+
+/// An expression laid out with the comments that trail it kept apart so
+/// a `,` or operator can go between them
+struct Parts {
+    body: String,
+    trailing: String,
+    /// The last trailing comment is a `//#` comment
+    ends_line: bool,
+}
+
+impl<'a> Printer<'a> {
+    /// Print `expr` starting at `column` where continuation lines are
+    /// indented by `indent`
+    fn layout(&self, expr: &Expression, indent: usize, column: usize) -> String {
+        let parts = self.parts(expr, indent, column);
+        if parts.ends_line {
+            format!("{}{}\n{}", parts.body, parts.trailing, pad(indent))
+        } else {
+            parts.body + &parts.trailing
+        }
+    }
+
+    fn parts(&self, expr: &Expression, indent: usize, column: usize) -> Parts {
+        let (leading, trailing) = self.comments(expr);
+
+        let mut body = String::new();
+        for c in leading {
+            body.push_str(&c.text);
+            if c.kind == TriviaKind::LineComment {
+                body.push('\n');
+                body.push_str(&pad(indent));
+            } else {
+                body.push(' ');
+            }
+        }
+        let column = match body.rfind('\n') {
+            Some(_) => indent,
+            None => column + width(&body),
+        };
+        body.push_str(&self.layout_expr(expr, indent, column));
+
+        Parts {
+            body,
+            trailing: trailing.iter().map(|c| format!(" {}", c.text)).collect(),
+            ends_line: trailing
+                .last()
+                .map(|c| c.kind == TriviaKind::LineComment)
+                .unwrap_or(false),
+        }
+    }
+
+    /// Lay out `expr` without the comments around it
+    fn layout_expr(&self, expr: &Expression, indent: usize, column: usize) -> String {
+        if !self.has_line_comment(expr) {
+            let flat = self.flat_expr(expr);
+            if column + width(&flat) <= self.options.max_width {
+                return flat;
+            }
+        }
+
+        let step = indent + self.options.indent;
+        match expr {
+            Expression::Function(name, type_params, params, _) if !params.is_empty() => {
+                let mut ret = self.function_head(name, type_params, indent);
+                ret.push('(');
+                for (i, param) in params.iter().enumerate() {
+                    let parts = self.parts(param, step, step);
+                    ret.push('\n');
+                    ret.push_str(&pad(step));
+                    ret.push_str(&parts.body);
+                    if i + 1 < params.len() {
+                        ret.push(',');
+                    }
+                    ret.push_str(&parts.trailing);
+                }
+                ret.push('\n');
+                ret.push_str(&pad(indent));
+                ret.push(')');
+                ret
+            }
+            Expression::Infix(opr, left, right, _) => {
+                let prec = precedence(opr);
+                let left = self.operand_parts(left, prec, false, indent, column);
+                let right = match signed_modulo(opr, right) {
+                    true => format!("({})", self.layout(right, step, step + 1)),
+                    false => self.operand(right, prec, true, step, step),
+                };
+                if left.ends_line {
+                    format!(
+                        "{}{}\n{}{}\n{}{}",
+                        left.body,
+                        left.trailing,
+                        pad(step),
+                        opr,
+                        pad(step),
+                        right
+                    )
+                } else {
+                    format!(
+                        "{}{} {}\n{}{}",
+                        left.body,
+                        left.trailing,
+                        opr,
+                        pad(step),
+                        right
+                    )
+                }
+            }
+            Expression::Let(name, value, body, _) => {
+                let head = format!("let {} = ", name);
+                let value = self.parts(value, step, column + width(&head));
+                format!(
+                    "{}{};{}\n{}{}",
+                    head,
+                    value.body,
+                    value.trailing,
+                    pad(indent),
+                    self.layout(body, indent, indent)
+                )
+            }
+            Expression::Paren(inner, _) => {
+                format!("({})", self.layout(inner, step, column + 1))
+            }
+            Expression::Prefix(opr, operand, _) => {
+                let operand = self.operand(operand, UNARY_PRECEDENCE, false, indent, column + 1);
+                prefix(opr, &operand)
+            }
+            Expression::Postfix(opr, operand, _) => {
+                self.operand(operand, UNARY_PRECEDENCE, false, indent, column) + opr
+            }
+            Expression::Array(rows, _) => {
+                let rows: Vec<String> = rows
+                    .iter()
+                    .map(|row| {
+                        row.iter()
+                            .map(|e| self.layout(e, indent + 1, indent + 1))
+                            .collect::<Vec<String>>()
+                            .join(", ")
+                    })
+                    .collect();
+                format!("{{{}}}", rows.join(&format!(";\n{}", pad(indent + 1))))
+            }
+            _ => self.flat_expr(expr),
+        }
+    }
+
+    /// Lay out the operand of an operator in parentheses if it needs them
+    fn operand(
+        &self,
+        expr: &Expression,
+        prec: i32,
+        right: bool,
+        indent: usize,
+        column: usize,
+    ) -> String {
+        if needs_parens(expr, prec, right) {
+            format!("({})", self.layout(expr, indent, column + 1))
+        } else {
+            self.layout(expr, indent, column)
+        }
+    }
+
+    fn operand_parts(
+        &self,
+        expr: &Expression,
+        prec: i32,
+        right: bool,
+        indent: usize,
+        column: usize,
+    ) -> Parts {
+        if needs_parens(expr, prec, right) {
+            Parts {
+                body: format!("({})", self.layout(expr, indent, column + 1)),
+                trailing: "".into(),
+                ends_line: false,
+            }
+        } else {
+            self.parts(expr, indent, column)
+        }
+    }
+
+    /// The comments before and after `expr` if comments are printed
+    fn comments<'e>(&self, expr: &'e Expression) -> (Vec<&'e Trivia>, Vec<&'e Trivia>) {
+        match expr.parse_info() {
+            Some(info) if self.options.comments => (
+                info.leading.iter().filter(|t| t.is_comment()).collect(),
+                info.trailing.iter().filter(|t| t.is_comment()).collect(),
+            ),
+            _ => (vec![], vec![]),
+        }
+    }
+
+    /// A `//#` comment ends its line, so an expression with one inside
+    /// it can't be printed on one line
+    fn has_line_comment(&self, expr: &Expression) -> bool {
+        expr.children().iter().any(|e| {
+            let (leading, trailing) = self.comments(e);
+            leading
+                .iter()
+                .chain(trailing.iter())
+                .any(|c| c.kind == TriviaKind::LineComment)
+                || self.has_line_comment(e)
+        })
+    }
+
+    /// Print `expr` on one line
+    fn flat(&self, expr: &Expression) -> String {
+        let (leading, trailing) = self.comments(expr);
+        let mut ret = String::new();
+        for c in leading {
+            ret.push_str(&c.text);
+            ret.push(' ');
+        }
+        ret.push_str(&self.flat_expr(expr));
+        for c in trailing {
+            ret.push(' ');
+            ret.push_str(&c.text);
+        }
+        ret
+    }
+
+    /// Print `expr` on one line without the comments around it
+    fn flat_expr(&self, expr: &Expression) -> String {
+        match expr {
+            Expression::Int(i, _) => i.to_string(),
+            // `Debug` is the shortest text that reads back as the same
+            // `f64` and always has a `.` or exponent so it reads back as
+            // a float
+            Expression::Float(f, _) => format!("{:?}", f),
+            Expression::Str(s, _) => quote_string(s),
+            Expression::DottedIdentifier(names, _) => names.join("."),
+            Expression::Identifier(name, _) => name.clone(),
+            Expression::Paren(inner, _) => format!("({})", self.flat(inner)),
+            Expression::Address(addr, _) => print_address(addr),
+            Expression::Range(range, _) => print_range(range),
+            Expression::Function(name, type_params, params, _) => {
+                format!(
+                    "{}({})",
+                    self.function_head(name, type_params, 0),
+                    self.flat_list(params)
+                )
+            }
+            Expression::Infix(opr, left, right, _) => {
+                let prec = precedence(opr);
+                let right = match signed_modulo(opr, right) {
+                    true => format!("({})", self.flat(right)),
+                    false => self.flat_operand(right, prec, true),
+                };
+                format!("{} {} {}", self.flat_operand(left, prec, false), opr, right)
+            }
+            Expression::Prefix(opr, operand, _) => {
+                prefix(opr, &self.flat_operand(operand, UNARY_PRECEDENCE, false))
+            }
+            Expression::Postfix(opr, operand, _) => {
+                self.flat_operand(operand, UNARY_PRECEDENCE, false) + opr
+            }
+            Expression::Let(name, value, body, _) => {
+                format!("let {} = {}; {}", name, self.flat(value), self.flat(body))
+            }
+            Expression::Array(rows, _) => {
+                let rows: Vec<String> = rows.iter().map(|row| self.flat_list(row)).collect();
+                format!("{{{}}}", rows.join("; "))
+            }
+            Expression::Missing(..) => "".into(),
+            Expression::ErrorValue(e, _) => e.text().to_string(),
+            Expression::Error(text, _) => text.clone(),
+        }
+    }
+
+    fn flat_list(&self, exprs: &[Expression]) -> String {
+        exprs
+            .iter()
+            .map(|e| self.flat(e))
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+
+    fn flat_operand(&self, expr: &Expression, prec: i32, right: bool) -> String {
+        if needs_parens(expr, prec, right) {
+            format!("({})", self.flat(expr))
+        } else {
+            self.flat(expr)
+        }
+    }
+
+    /// `expr` as it was written with the trivia around it, split where a
+    /// `,` or `;` after it goes. An expression that parses the same as
+    /// its text is its text, and anything else is printed from its parts
+    fn written(&self, expr: &Expression) -> (String, String) {
+        let info = match expr.parse_info() {
+            Some(info) => info,
+            None => return (self.written_expr(expr, true), String::new()),
+        };
+        let (start, end) = core(info);
+        let text = info.text.get(start - info.start..end - info.start);
+        let unchanged = text.filter(|text| {
+            (self.options.comments || !trivia(text).iter().any(|t| t.is_comment()))
+                && whole_expr_str(text).as_ref() == Ok(expr)
+        });
+        // a comment with nowhere else to go can be inside the expression
+        let outside = |t: &&Trivia| {
+            (unchanged.is_none() || t.end <= start || t.start >= end)
+                && (self.options.comments || !t.is_comment())
+        };
+
+        let mut body: String = info
+            .leading
+            .iter()
+            .filter(outside)
+            .map(|t| t.text.as_str())
+            .collect();
+        match unchanged {
+            Some(text) => body.push_str(text),
+            None => body.push_str(&self.written_expr(expr, false)),
+        }
+
+        let (mut before, mut after) = (end, String::new());
+        for t in info.trailing.iter().filter(outside) {
+            if after.is_empty() && t.start <= before {
+                body.push_str(&t.text);
+                before = t.end;
+            } else {
+                after.push_str(&t.text);
+            }
+        }
+        (body, after)
+    }
+
+    /// Print `expr` from its parts as they were written. `spaced` puts
+    /// the canonical spaces between the parts, for an expression that
+    /// wasn't parsed and so has no whitespace around its parts
+    fn written_expr(&self, expr: &Expression, spaced: bool) -> String {
+        let whole = |e: &Expression| {
+            let (body, after) = self.written(e);
+            body + &after
+        };
+        let list = |exprs: &[Expression], sep: &str| {
+            let mut ret = String::new();
+            for (i, e) in exprs.iter().enumerate() {
+                let (body, after) = self.written(e);
+                ret.push_str(&body);
+                if i + 1 < exprs.len() {
+                    ret.push_str(sep);
+                    if spaced {
+                        ret.push(' ');
+                    }
+                }
+                ret.push_str(&after);
+            }
+            ret
+        };
+        let space = if spaced { " " } else { "" };
+
+        match expr {
+            Expression::Paren(inner, _) => format!("({})", whole(inner)),
+            Expression::Function(name, type_params, params, _) => {
+                let mut ret = name.clone();
+                if !type_params.is_empty() {
+                    ret = format!("{}[{}]", ret, list(type_params, ","));
+                }
+                format!("{}({})", ret, list(params, ","))
+            }
+            Expression::Infix(opr, left, right, _) => {
+                let prec = precedence(opr);
+                let right = if signed_modulo(opr, right) || needs_parens(right, prec, true) {
+                    format!("({})", whole(right))
+                } else {
+                    whole(right)
+                };
+                let left = match needs_parens(left, prec, false) {
+                    true => format!("({})", whole(left)),
+                    false => whole(left),
+                };
+                format!("{}{}{}{}{}", left, space, opr, space, right)
+            }
+            Expression::Prefix(opr, operand, _) => {
+                let operand = match needs_parens(operand, UNARY_PRECEDENCE, false) {
+                    true => format!("({})", whole(operand)),
+                    false => whole(operand),
+                };
+                match spaced {
+                    true => prefix(opr, &operand),
+                    false => format!("{}{}", opr, operand),
+                }
+            }
+            Expression::Postfix(opr, operand, _) => {
+                match needs_parens(operand, UNARY_PRECEDENCE, false) {
+                    true => format!("({}){}", whole(operand), opr),
+                    false => whole(operand) + opr,
+                }
+            }
+            Expression::Let(name, value, body, _) => {
+                format!(
+                    "let {} ={}{};{}{}",
+                    name,
+                    space,
+                    whole(value),
+                    space,
+                    whole(body)
+                )
+            }
+            Expression::Array(rows, _) => {
+                let rows: Vec<String> = rows.iter().map(|row| list(row, ",")).collect();
+                format!("{{{}}}", rows.join(if spaced { "; " } else { ";" }))
+            }
+            _ => self.flat_expr(expr),
+        }
+    }
+
+    fn function_head(&self, name: &str, type_params: &[Expression], indent: usize) -> String {
+        if type_params.is_empty() {
+            name.to_string()
+        } else {
+            let params: Vec<String> = type_params
+                .iter()
+                .map(|e| self.layout(e, indent, indent))
+                .collect();
+            format!("{}[{}]", name, params.join(", "))
+        }
+    }
+}
+
+/// Where `info`'s expression starts and ends without the trivia around
+/// it. Without the text this is as near as the positions give
+fn core(info: &PositionInfo) -> (usize, usize) {
+    if info.text.is_empty() {
+        return (info.inner_start, info.inner_end);
+    }
+    let (mut start, mut end) = (0, info.text.len());
+    let found = trivia(&info.text);
+    for t in &found {
+        if t.start == start {
+            start = t.end;
+        }
+    }
+    for t in found.iter().rev() {
+        if t.end == end && t.start >= start {
+            end = t.start;
+        }
+    }
+    (info.start + start, info.start + end)
+}
+
+/// Does `expr` need parentheses as an operand of an operator with
+/// precedence `prec`? Trees from the parser have a `Paren` wherever one
+/// is needed, so this only matters for trees built some other way
+fn needs_parens(expr: &Expression, prec: i32, right: bool) -> bool {
+    match expr {
+        Expression::Infix(opr, _, _, _) => {
+            let inner = precedence(opr);
+            inner < prec || (right && inner == prec)
+        }
+        // the body of a `let` runs to the end of the formula
+        Expression::Let(..) => !right,
+        _ => false,
+    }
+}
+
+/// A `%` followed by a sign is a percentage, so the right operand of
+/// modulo needs parentheses if it's printed starting with one
+fn signed_modulo(opr: &str, right: &Expression) -> bool {
+    opr == "%" && starts_with_sign(right)
+}
+
+/// Whether the first thing printed for `expr` is a `-` or `+`
+fn starts_with_sign(expr: &Expression) -> bool {
+    match expr {
+        Expression::Int(i, _) => *i < 0,
+        Expression::Float(f, _) => f.is_sign_negative(),
+        Expression::Prefix(opr, _, _) => opr == "-" || opr == "+",
+        Expression::Infix(_, left, _, _) | Expression::Postfix(_, left, _) => {
+            starts_with_sign(left)
+        }
+        _ => false,
+    }
+}
+
+/// A `-` or `+` directly in front of a number is part of the number, so
+/// there's a space between a sign operator and a numeric operand
+fn prefix(opr: &str, operand: &str) -> String {
+    let numeric = operand.starts_with(|c: char| c.is_ascii_digit() || c == '.');
+    if numeric && (opr == "-" || opr == "+") {
+        format!("{} {}", opr, operand)
+    } else {
+        format!("{}{}", opr, operand)
+    }
+}
+
+/// Quote a string. A quote is doubled and control characters and
+/// backslashes are escaped with a backslash
+fn quote_string(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len() + 2);
+    ret.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => ret.push_str("\"\""),
+            '\\' => ret.push_str("\\\\"),
+            '\n' => ret.push_str("\\n"),
+            '\t' => ret.push_str("\\t"),
+            '\r' => ret.push_str("\\r"),
+            '\0' => ret.push_str("\\0"),
+            c if c.is_control() => ret.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => ret.push(c),
+        }
+    }
+    ret.push('"');
+    ret
+}
+
+/// A sheet name that isn't a plain name is quoted with a quote in the
+/// name doubled
+fn quote_sheet_name(name: &str) -> String {
+    let mut chars = name.chars();
+    let plain = matches!(chars.next(), Some(c) if c.is_alphabetic() || c == '_')
+        && chars.all(|c| c.is_alphanumeric() || c == '_');
+    if plain {
+        name.to_string()
+    } else {
+        format!("'{}'", name.replace('\'', "''"))
+    }
+}
+
+fn print_sheet(sheet: &Option<SheetRef>) -> String {
+    match sheet {
+        None => "".into(),
+        Some(SheetRef { first, last: None }) => format!("{}!", quote_sheet_name(first)),
+        Some(SheetRef {
+            first,
+            last: Some(last),
+        }) => format!("{}:{}!", quote_sheet_name(first), quote_sheet_name(last)),
+    }
+}
+
+fn print_address(addr: &Address) -> String {
+    print_sheet(&addr.sheet) + &addr.addr
+}
+
+fn print_range(range: &Range) -> String {
+    format!(
+        "{}{}:{}",
+        print_sheet(&range.sheet),
+        range.upper_left.addr,
+        range.lower_right.addr
+    )
+}
+
+/// The width of text in characters. For multi-line text it's the width
+/// of the widest line
+fn width(text: &str) -> usize {
+    text.lines().map(|l| l.chars().count()).max().unwrap_or(0)
+}
+
+fn pad(n: usize) -> String {
+    " ".repeat(n)
+}
+
+#[test]
+fn test_round_trip() {
+    use crate::parser::whole_expr_str;
+
+    let formulas = [
+        "147",
+        "-32.822 + .5 * 5. - 1e-9 / 6.02E23",
+        "0xFF + 1_000",
+        r#""say ""hi"" \\ to\tme\n\u{7}" & "é☃""#,
+        "IF(A1 > B2 && B3 > G6 || SUM(QQ99:ZZ107) + SUM(EE7:UU9) > AVE(D4:D9), AVE(Q2:Q9) + SUM(QQ99:ZZ107) + SUM(EE7:UU9), A1 + B2 + B3 + G6 + SUM(W4:W9))",
+        "let rounding_errors = SUM(QQ99:ZZ107) + SUM(EE7:UU9); IF(rounding_errors > AVE[MEAN](D4:D9), AVE[MEDIAN, 3](Q2:Q9) + rounding_errors, 0)",
+        "SELECT[DISTINCT](ITEMS(foo, bar, baz * 3), FROM(cats, dogs), GROUP_BY(), order_by())",
+        "cats.dogs.mice + $A$1 - b$7 * $C3",
+        "Sheet2!B4 + 'Q3 Sales'!$A$1:C9 + 'Bob''s'!A:A + Jan:Dec!3:5 + A2:A",
+        "10 - (3 - 2) - 1",
+        "(1 + 2) * 3 ^ (4 ^ 5)",
+        "- 5 + -5 - +5 + + 5 - -A1 + !TRUE + --3",
+        "50% * 4% % 3 + (1 + 2)%",
+        "7%-3 + (7%) - 3 + 7% != 3 + 50%+10 + 7 % (-3) % +x",
+        "{1, 2, 3; 4, 5, 6} * {1; -2}",
+        "a <> b",
+        "1 + let x = 2; x * 3",
+        "1 /* one */ + /* two */ 2 //# three",
+        "IF(A1 > B2 //# north beats west\n || /* or */ C3, //# then\n D4, 0 /* none */)",
+        "let x = 2 //# two\n; /* body */ x * {1 /* a */, 2; 3, //# b\n 4}",
+    ];
+
+    for formula in formulas {
+        let parsed = whole_expr_str(formula).unwrap();
+        for options in [
+            PrintOptions::default(),
+            PrintOptions::single_line(),
+            PrintOptions {
+                max_width: 10,
+                indent: 2,
+                ..PrintOptions::default()
+            },
+            PrintOptions {
+                max_width: 0,
+                ..PrintOptions::default()
+            },
+        ] {
+            let printed = print_with(&parsed, &options);
+            assert_eq!(
+                whole_expr_str(&printed),
+                Ok(parsed.clone()),
+                "Printing {} as {}",
+                formula,
+                printed
+            );
+        }
+    }
+}
+
+#[test]
+fn test_print() {
+    use crate::parser::whole_expr_str;
+    use crate::parser_util::*;
+
+    let canonical = |formula: &str| print(&whole_expr_str(formula).unwrap());
+
+    assert_eq!(canonical("=  1+2 *3"), "1 + 2 * 3");
+    assert_eq!(canonical("if( a1,b$2 , 3 )"), "IF(A1, B$2, 3)");
+    assert_eq!(canonical("a <> b"), "A != B");
+    assert_eq!(canonical("'Q3 sales'!a1:B2"), "'Q3 sales'!A1:B2");
+    assert_eq!(canonical(r#""a""b""#), r#""a""b""#);
+    assert_eq!(canonical("1 /* note */ + 2"), "1 /* note */ + 2");
+    assert_eq!(
+        print_with(
+            &whole_expr_str("1 /* note */ + 2").unwrap(),
+            &PrintOptions {
+                comments: false,
+                ..PrintOptions::default()
+            }
+        ),
+        "1 + 2"
+    );
+    assert_eq!(
+        canonical("f(1, //# one\n 2)"),
+        "F(\n    1, //# one\n    2\n)"
+    );
+
+    // trees that weren't parsed get the parentheses they need
+    let built = ex_inf(
+        "*",
+        ex_inf("-", ex_i(10), ex_inf("-", ex_i(3), ex_i(2))),
+        ex_pre("-", ex_inf("+", ex_i(1), ex_i(1))),
+    );
+    assert_eq!(print(&built), "(10 - (3 - 2)) * -(1 + 1)");
+    let built = ex_inf(
+        "+",
+        ex_inf("*", ex_i(2), ex_pre("-", ex_post("%", ex_i(7)))),
+        ex_inf("-", ex_post("%", ex_i(5)), ex_i(3)),
+    );
+    assert_eq!(print(&built), "2 * - 7% + (5% - 3)");
+    let built = ex_inf(
+        "-",
+        ex_inf("%", ex_i(7), ex_i(-3)),
+        ex_inf("%", ex_i(7), ex_inf("*", ex_pre("-", ex_id("x")), ex_i(2))),
+    );
+    assert_eq!(print(&built), "7 % (-3) - 7 % (-X * 2)");
+    assert_eq!(
+        whole_expr_str(&print(&built)).map(|e| print(&e)),
+        Ok(print(&built))
+    );
+
+    let long = whole_expr_str(
+        "IF(A1 > B2 && B3 > G6 || SUM(QQ99:ZZ107) + SUM(EE7:UU9) > AVE(D4:D9), AVE(Q2:Q9) + SUM(QQ99:ZZ107), 42)",
+    )
+    .unwrap();
+    assert_eq!(
+        print_with(
+            &long,
+            &PrintOptions {
+                max_width: 40,
+                ..PrintOptions::default()
+            }
+        ),
+        "IF(
+    A1 > B2 && B3 > G6 ||
+        SUM(QQ99:ZZ107) + SUM(EE7:UU9) >
+            AVE(D4:D9),
+    AVE(Q2:Q9) + SUM(QQ99:ZZ107),
+    42
+)"
+    );
+
+    let with_let = whole_expr_str("let total = SUM(A1:A9) * 2; IF(total > 10, total, 0)").unwrap();
+    assert_eq!(
+        print_with(
+            &with_let,
+            &PrintOptions {
+                max_width: 30,
+                ..PrintOptions::default()
+            }
+        ),
+        "let TOTAL = SUM(A1:A9) * 2;
+IF(TOTAL > 10, TOTAL, 0)"
+    );
+
+    // the example from the formulas doc keeps its comments
+    let commented = whole_expr_str(
+        "=IF(A1 > B2 && B3 > G6 //# north beats west
+    ||
+    /* or rounding errors */
+    SUM(QQ99:ZZ107) > AVE(D4:D9),
+    //# then
+    AVE(Q2:Q9), 0 /* none */)",
+    )
+    .unwrap();
+    assert_eq!(
+        print(&commented),
+        "IF(
+    A1 > B2 && B3 > G6 //# north beats west
+        ||
+        /* or rounding errors */ SUM(QQ99:ZZ107) > AVE(D4:D9),
+    //# then
+    AVE(Q2:Q9),
+    0 /* none */
+)"
+    );
+}
+
+#[test]
+fn test_print_as_written() {
+    use crate::parser::whole_expr_str;
+    use crate::rewrite::copy_formula;
+    use crate::worksheet::SimpleAddress;
+
+    let written = |formula: &str| {
+        let options = PrintOptions::as_written();
+        print_with(&whole_expr_str(formula).unwrap(), &options)
+    };
+    for formula in [
+        "if( a1,b$2 , 3 )",
+        "f(1, /* one */ 2) + f( /* empty */ )",
+        "{1 ,2;\n 3, 4 } - 1.50 %",
+        "let x=1 ;x",
+        "f(1 //# one\n, 2)  ",
+        "=IF(A1 > B2 && B3 > G6 //# north beats west
+    ||
+    /* or rounding errors */
+    SUM(QQ99:ZZ107) > AVE(D4:D9),
+    //# then
+    AVE(Q2:Q9), 0 /* none */)",
+    ] {
+        assert_eq!(written(formula), formula.trim_start_matches('='));
+    }
+    assert_eq!(
+        print_with(
+            &whole_expr_str("f(1, /* one */ 2)").unwrap(),
+            &PrintOptions {
+                comments: false,
+                ..PrintOptions::as_written()
+            }
+        ),
+        "F(1,  2)"
+    );
+
+    // what's changed is printed in the canonical form and the rest
+    // stays as it was
+    let moved = copy_formula(
+        &whole_expr_str("sum( a1 ,\n  b$2 ) /* sum */ + total").unwrap(),
+        &SimpleAddress { row: 1, col: 1 },
+        &SimpleAddress { row: 1, col: 2 },
+    );
+    assert_eq!(
+        print_with(&moved, &PrintOptions::as_written()),
+        "SUM( B1 ,\n  C$2 ) /* sum */ + total"
+    );
+}
@@ -0,0 +1,263 @@
+//! R1C1 references and converting between R1C1 and A1
+//!
+
+// Copyright 2021 David Pollak
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::parser::{Address, Expression, Notation, ParseInfo, Range};
+use crate::visit::VisitorMut;
+use crate::worksheet::{A1Reference, SimpleAddress};
+
+/// The row or column half of an R1C1 address. `R3` is absolute and
+/// `R[-1]` is relative to the cell the formula is in (the anchor)
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Part {
+    Absolute(i32),
+    Relative(i32),
+}
+
+impl Part {
+    fn parse(text: &str) -> Option<Part> {
+        match text {
+            "" => Some(Part::Relative(0)),
+            t if t.starts_with('[') && t.ends_with(']') => {
+                Some(Part::Relative(t[1..t.len() - 1].parse().ok()?))
+            }
+            t if t.chars().all(|c| c.is_ascii_digit()) => Some(Part::Absolute(t.parse().ok()?)),
+            _ => None,
+        }
+    }
+
+    fn format(&self, letter: char) -> String {
+        match self {
+            Part::Absolute(n) => format!("{}{}", letter, n),
+            Part::Relative(0) => letter.to_string(),
+            Part::Relative(n) => format!("{}[{}]", letter, n),
+        }
+    }
+
+    /// The row or column this part refers to from `anchor`, which is the
+    /// anchor's row or its column counting from 1
+    fn resolve(&self, anchor: i32) -> Option<i32> {
+        match self {
+            Part::Absolute(n) => Some(*n),
+            Part::Relative(n) => anchor.checked_add(*n),
+        }
+    }
+}
+
+/// Split the text of an R1C1 address into its row and column parts
+fn parse_r1c1(addr: &str) -> Option<(Part, Part)> {
+    let rest = addr.strip_prefix('R')?;
+    let split = rest.find('C')?;
+    Some((
+        Part::parse(&rest[..split])?,
+        Part::parse(&rest[split + 1..])?,
+    ))
+}
+
+/// The R1C1 form of an A1 cell address in a formula at `anchor`. Parts
+/// marked with `$` are absolute and the others are relative, so `B$3`
+/// at `C5` is `R3C[-1]`. `None` if `addr` isn't an A1 cell address
+pub fn a1_to_r1c1(addr: &str, anchor: &SimpleAddress) -> Option<String> {
+    let a1: A1Reference = addr.parse().ok()?;
+    let cell = a1.cell()?;
+
+    let row = match a1.absolute_row {
+        true => Part::Absolute(cell.row),
+        false => Part::Relative(cell.row - anchor.row),
+    };
+    let col = match a1.absolute_col {
+        true => Part::Absolute(cell.col + 1),
+        false => Part::Relative(cell.col - anchor.col),
+    };
+    Some(row.format('R') + &col.format('C'))
+}
+
+/// The A1 form of an R1C1 address in a formula at `anchor`. Absolute
+/// parts are marked with `$`. An error if `addr` isn't an R1C1 address
+/// or it refers to a cell off the sheet
+pub fn r1c1_to_a1(addr: &str, anchor: &SimpleAddress) -> Result<String, String> {
+    let (row, col) = match parse_r1c1(addr) {
+        Some(parts) => parts,
+        None => return Err(format!("{} isn't an R1C1 address", addr)),
+    };
+
+    match (row.resolve(anchor.row), col.resolve(anchor.col + 1)) {
+        (Some(r), Some(c)) if r >= 1 && c >= 1 => Ok(A1Reference {
+            col: Some(c - 1),
+            row: Some(r),
+            absolute_col: matches!(col, Part::Absolute(_)),
+            absolute_row: matches!(row, Part::Absolute(_)),
+        }
+        .to_string()),
+        _ => Err(format!("{} from {} is off the sheet", addr, anchor)),
+    }
+}
+
+/// Rewrite every cell address and every range between two cells in
+/// `expr` in R1C1 style for a formula at `anchor`. Ranges with a whole
+/// row or column corner are left in A1 style because `R3` as a whole
+/// row is also the cell R3
+pub fn to_r1c1(expr: &Expression, anchor: &SimpleAddress) -> Expression {
+    let mut ret = expr.clone();
+    convert(&mut ret, Notation::A1, &|addr: &str| {
+        a1_to_r1c1(addr, anchor).ok_or_else(|| format!("{} isn't a cell", addr))
+    })
+    .expect("Converting to R1C1 doesn't fail");
+    ret
+}
+
+/// Rewrite every R1C1 address in `expr` in A1 style for a formula at
+/// `anchor`. An error if an address refers to a cell off the sheet
+pub fn to_a1(expr: &Expression, anchor: &SimpleAddress) -> Result<Expression, String> {
+    let mut ret = expr.clone();
+    convert(&mut ret, Notation::R1C1, &|addr: &str| {
+        r1c1_to_a1(addr, anchor)
+    })?;
+    Ok(ret)
+}
+
+/// Are the formulas at `a_at` and `b_at` the same formula, as the
+/// formulas in a column filled down from the top are
+pub fn same_formula(
+    a: &Expression,
+    a_at: &SimpleAddress,
+    b: &Expression,
+    b_at: &SimpleAddress,
+) -> bool {
+    to_r1c1(a, a_at) == to_r1c1(b, b_at)
+}
+
+fn is_cell(addr: &Address) -> bool {
+    addr.notation == Notation::R1C1 || addr.addr.parse::<SimpleAddress>().is_ok()
+}
+
+/// Rewrite the text of the addresses written in the `from` style, and of
+/// ranges between two such cells, with `f`. The first error is kept
+struct Convert<'a> {
+    from: Notation,
+    f: &'a dyn Fn(&str) -> Result<String, String>,
+    error: Option<String>,
+}
+
+impl Convert<'_> {
+    fn apply(&mut self, addr: &mut Address) {
+        if addr.notation != self.from || !is_cell(addr) {
+            return;
+        }
+        match (self.f)(&addr.addr) {
+            Ok(new) => {
+                addr.addr = new;
+                addr.notation = match self.from {
+                    Notation::A1 => Notation::R1C1,
+                    Notation::R1C1 => Notation::A1,
+                };
+            }
+            Err(e) => {
+                self.error.get_or_insert(e);
+            }
+        }
+    }
+}
+
+impl VisitorMut for Convert<'_> {
+    fn visit_address_mut(&mut self, addr: &mut Address, _info: &mut ParseInfo) {
+        self.apply(addr)
+    }
+
+    fn visit_range_mut(&mut self, range: &mut Range, _info: &mut ParseInfo) {
+        if is_cell(&range.upper_left) && is_cell(&range.lower_right) {
+            self.apply(&mut range.upper_left);
+            self.apply(&mut range.lower_right);
+        }
+    }
+}
+
+fn convert(
+    expr: &mut Expression,
+    from: Notation,
+    f: &dyn Fn(&str) -> Result<String, String>,
+) -> Result<(), String> {
+    let mut convert = Convert {
+        from,
+        f,
+        error: None,
+    };
+    convert.visit_expr_mut(expr);
+    match convert.error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+#[test]
+fn test_r1c1() {
+    use crate::parser::{whole_expr_str, whole_r1c1_expr_str};
+    use crate::printer::print;
+
+    let c5 = SimpleAddress { row: 5, col: 2 };
+    assert_eq!(a1_to_r1c1("B$3", &c5), Some("R3C[-1]".to_string()));
+    assert_eq!(a1_to_r1c1("$A$1", &c5), Some("R1C1".to_string()));
+    assert_eq!(a1_to_r1c1("C5", &c5), Some("RC".to_string()));
+    assert_eq!(a1_to_r1c1("$F9", &c5), Some("R[4]C6".to_string()));
+    assert_eq!(a1_to_r1c1("A:C", &c5), None);
+
+    assert_eq!(r1c1_to_a1("R3C[-1]", &c5), Ok("B$3".to_string()));
+    assert_eq!(r1c1_to_a1("R1C1", &c5), Ok("$A$1".to_string()));
+    assert_eq!(r1c1_to_a1("RC", &c5), Ok("C5".to_string()));
+    assert_eq!(r1c1_to_a1("R[-1]C[2]", &c5), Ok("E4".to_string()));
+    assert_eq!(
+        r1c1_to_a1("R[-5]C", &c5),
+        Err("R[-5]C from C5 is off the sheet".to_string())
+    );
+    assert_eq!(
+        r1c1_to_a1("RC[-3]", &c5),
+        Err("RC[-3] from C5 is off the sheet".to_string())
+    );
+
+    let a1 = whole_expr_str("SUM(A1:B4) + $C$2 * Sheet2!D5 + A:A").unwrap();
+    let r1c1 = to_r1c1(&a1, &c5);
+    assert_eq!(
+        r1c1,
+        whole_r1c1_expr_str("SUM(R[-4]C[-2]:R[-1]C[-1]) + R2C3 * Sheet2!RC[1] + A:A").unwrap()
+    );
+    assert_eq!(to_a1(&r1c1, &c5), Ok(a1));
+
+    // only addresses parsed in R1C1 style are R1C1, so A1 formulas that
+    // look like R1C1 are left alone
+    let a1 = whole_expr_str("RC1 + 1 + let rc = 2; rc").unwrap();
+    assert_eq!(to_a1(&a1, &c5), Ok(a1.clone()));
+    assert_eq!(
+        print(&to_r1c1(&a1, &c5)),
+        "R[-4]C[468] + 1 + let RC = 2; RC"
+    );
+    let r1c1 = whole_r1c1_expr_str("RC1 + 1").unwrap();
+    assert_eq!(print(&to_a1(&r1c1, &c5).unwrap()), "$A5 + 1");
+
+    // a column of formulas filled down is the same formula
+    let c6 = SimpleAddress { row: 6, col: 2 };
+    assert!(same_formula(
+        &whole_expr_str("C4 + $A$1").unwrap(),
+        &c5,
+        &whole_expr_str("C5 + $A$1").unwrap(),
+        &c6
+    ));
+    assert!(!same_formula(
+        &whole_expr_str("C4 + A1").unwrap(),
+        &c5,
+        &whole_expr_str("C5 + A1").unwrap(),
+        &c6
+    ));
+}
@@ -0,0 +1,336 @@
+//! Rewriting the references in a formula when it's copied, filled or
+//! moved
+//!
+
+// Copyright 2021 David Pollak
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::definitions::CellError;
+use crate::diagnostic::ParseDiagnostic;
+use crate::parser::{whole_expr_str, Address, Expression, Notation, Range, SheetRef};
+use crate::printer::{print_with, PrintOptions};
+use crate::visit::{walk_expr_mut, VisitorMut};
+use crate::worksheet::{A1Reference, SimpleAddress, SimpleRange};
+
+/// Why a formula can't be rewritten
+#[derive(Debug, PartialEq, Eq, Clone, Hash)]
+pub enum RewriteError {
+    /// Filling a range that runs to the end of the sheet
+    OpenEnded(SimpleRange),
+}
+
+impl std::fmt::Display for RewriteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RewriteError::OpenEnded(range) => write!(f, "can't fill the whole of {}", range),
+        }
+    }
+}
+
+impl std::error::Error for RewriteError {}
+
+/// The formula `expr` in the cell `from` copied to the cell `to`. The
+/// relative parts of each reference move by the distance between the
+/// cells and the `$` parts stay put, so `A1 + $B$2` copied from C1 to D5
+/// is `B5 + $B$2`. A reference that would be off the sheet becomes
+/// `#REF!`, without the sheet it was on. R1C1 addresses are already
+/// relative to their cell so they don't change
+pub fn copy_formula(expr: &Expression, from: &SimpleAddress, to: &SimpleAddress) -> Expression {
+    let rows = to.row as i64 - from.row as i64;
+    let cols = to.col as i64 - from.col as i64;
+    let mut ret = expr.clone();
+    rewrite(&mut ret, &|_, corners| {
+        corners
+            .iter()
+            .map(|a1| shift(a1, rows, cols, false))
+            .collect()
+    });
+    ret
+}
+
+/// The formula `expr` in the cell `from` copied to every cell in `to`,
+/// as filling down or across does. An error if `to` is open ended
+pub fn fill_formula(
+    expr: &Expression,
+    from: &SimpleAddress,
+    to: &SimpleRange,
+) -> Result<Vec<(SimpleAddress, Expression)>, RewriteError> {
+    let (last_row, last_col) = match (to.last_row, to.last_col) {
+        (Some(row), Some(col)) => (row, col),
+        _ => return Err(RewriteError::OpenEnded(*to)),
+    };
+
+    let mut ret = vec![];
+    for row in to.first_row..=last_row {
+        for col in to.first_col..=last_col {
+            let at = SimpleAddress { row, col };
+            ret.push((at, copy_formula(expr, from, &at)));
+        }
+    }
+    Ok(ret)
+}
+
+/// Copy the text of a formula from `from` to `to`. The result keeps the
+/// formula's layout and comments, and only the references that changed
+/// are printed in the canonical form
+pub fn copy_formula_str(
+    formula: &str,
+    from: &SimpleAddress,
+    to: &SimpleAddress,
+) -> Result<String, Vec<ParseDiagnostic>> {
+    let copied = copy_formula(&whole_expr_str(formula)?, from, to);
+    Ok(print_with(&copied, &PrintOptions::as_written()))
+}
+
+/// Follow cells that were moved. `expr` is a formula on the sheet
+/// `on_sheet` and the cells in `moved` on `moved_sheet` were moved so the
+/// top left one is at `to`. References to cells in the moved block,
+/// including the `$` parts, are rewritten to where the cells went. A
+/// range that's only partly in the block is left alone
+pub fn move_references(
+    expr: &Expression,
+    on_sheet: &str,
+    moved_sheet: &str,
+    moved: &SimpleRange,
+    to: &SimpleAddress,
+) -> Expression {
+    let rows = to.row as i64 - moved.first_row as i64;
+    let cols = to.col as i64 - moved.first_col as i64;
+    let mut ret = expr.clone();
+    rewrite(&mut ret, &|sheet, corners| {
+        let name = match sheet {
+            None => on_sheet,
+            Some(SheetRef { first, last: None }) => first,
+            Some(_) => return Some(corners.to_vec()),
+        };
+        let bounds = match corners {
+            [cell] => SimpleRange::from_corners(cell, cell),
+            [ul, lr] => SimpleRange::from_corners(ul, lr),
+            _ => return Some(corners.to_vec()),
+        };
+        match bounds {
+            Ok(bounds)
+                if name.to_lowercase() == moved_sheet.to_lowercase() && inside(&bounds, moved) =>
+            {
+                corners
+                    .iter()
+                    .map(|a1| shift(a1, rows, cols, true))
+                    .collect()
+            }
+            _ => Some(corners.to_vec()),
+        }
+    });
+    ret
+}
+
+/// Is all of `inner` in `outer`
+fn inside(inner: &SimpleRange, outer: &SimpleRange) -> bool {
+    let within = |first: i32, last: Option<i32>, o_first: i32, o_last: Option<i32>| {
+        first >= o_first
+            && match (last, o_last) {
+                (_, None) => true,
+                (Some(last), Some(o_last)) => last <= o_last,
+                (None, Some(_)) => false,
+            }
+    };
+    within(
+        inner.first_row,
+        inner.last_row,
+        outer.first_row,
+        outer.last_row,
+    ) && within(
+        inner.first_col,
+        inner.last_col,
+        outer.first_col,
+        outer.last_col,
+    )
+}
+
+/// Move an A1 reference by `rows` and `cols`. Only the relative parts
+/// move unless `absolute_too`. `None` if it ends up off the sheet
+fn shift(a1: &A1Reference, rows: i64, cols: i64, absolute_too: bool) -> Option<A1Reference> {
+    let moved = |part: Option<i32>, absolute: bool, by: i64| -> Option<Option<i32>> {
+        match part {
+            Some(p) if absolute_too || !absolute => Some(Some(i32::try_from(p as i64 + by).ok()?)),
+            other => Some(other),
+        }
+    };
+    let ret = A1Reference {
+        row: moved(a1.row, a1.absolute_row, rows)?,
+        col: moved(a1.col, a1.absolute_col, cols)?,
+        ..*a1
+    };
+    ret.check().ok().map(|_| ret)
+}
+
+/// Rewrite the corners of a reference on a sheet. `None` if the
+/// reference can't be rewritten
+type Rewriter<'a> = dyn Fn(&Option<SheetRef>, &[A1Reference]) -> Option<Vec<A1Reference>> + 'a;
+
+/// Rewrite the A1 addresses and ranges in `expr` with `f`, which is
+/// given the sheet a reference is on and its one or two corners. A
+/// reference that `f` can't rewrite becomes `#REF!`. R1C1 references
+/// aren't rewritten
+fn rewrite(expr: &mut Expression, f: &Rewriter) {
+    Rewrite { f }.visit_expr_mut(expr)
+}
+
+struct Rewrite<'a, 'b> {
+    f: &'a Rewriter<'b>,
+}
+
+impl VisitorMut for Rewrite<'_, '_> {
+    fn visit_expr_mut(&mut self, expr: &mut Expression) {
+        let rewritten = match expr {
+            Expression::Address(addr, _) => {
+                rewrite_corners(&addr.sheet.clone(), &mut [addr], self.f)
+            }
+            Expression::Range(
+                Range {
+                    sheet,
+                    upper_left,
+                    lower_right,
+                },
+                _,
+            ) => rewrite_corners(sheet, &mut [upper_left, lower_right], self.f),
+            _ => {
+                walk_expr_mut(self, expr);
+                true
+            }
+        };
+
+        if !rewritten {
+            let info = expr.parse_info_mut().take();
+            *expr = Expression::ErrorValue(CellError::Ref, info);
+        }
+    }
+}
+
+fn rewrite_corners(sheet: &Option<SheetRef>, corners: &mut [&mut Address], f: &Rewriter) -> bool {
+    if corners.iter().any(|c| c.notation == Notation::R1C1) {
+        return true;
+    }
+    let parsed: Result<Vec<A1Reference>, _> = corners.iter().map(|c| c.addr.parse()).collect();
+    let parsed = match parsed {
+        Ok(parsed) => parsed,
+        Err(_) => return true,
+    };
+    match f(sheet, &parsed) {
+        Some(new) => {
+            for (corner, a1) in corners.iter_mut().zip(new) {
+                corner.addr = a1.to_string();
+            }
+            true
+        }
+        None => false,
+    }
+}
+
+#[test]
+fn test_copy_formula() {
+    use crate::printer::print;
+
+    let at = |text: &str| text.parse::<SimpleAddress>().unwrap();
+    let copy = |formula: &str, from: &str, to: &str| {
+        copy_formula_str(formula, &at(from), &at(to)).unwrap()
+    };
+
+    assert_eq!(copy("=A1+$B$2", "C1", "D5"), "B5+$B$2");
+    assert_eq!(copy("A$1 * $A1", "B2", "D9"), "C$1 * $A8");
+    assert_eq!(
+        copy("SUM(A1:B3, Data!C:C, 2:$4, 'Q3 Sales'!$A2:A)", "C3", "D4"),
+        "SUM(B2:C4, Data!D:D, 3:$4, 'Q3 Sales'!$A3:B)"
+    );
+    let r1c1 = crate::parser::whole_r1c1_expr_str("R[-1]C + SUM(RC:RC[2])").unwrap();
+    assert_eq!(copy_formula(&r1c1, &at("C3"), &at("Z99")), r1c1);
+    assert_eq!(copy("RC1 + rc", "C3", "D3"), "RD1 + rc");
+
+    // off the sheet
+    assert_eq!(copy("A1 + $A$1", "B2", "A1"), "#REF! + $A$1");
+    assert_eq!(copy("SUM(A1:B2) + 1", "C3", "C2"), "SUM(#REF!) + 1");
+    assert_eq!(copy("A1048576", "A1", "A2"), "#REF!");
+    assert_eq!(copy("Data!A1 * 2", "B2", "A1"), "#REF! * 2");
+    for formula in ["A1 + $A$1", "SUM(A1:B2) + 1"] {
+        let copied = copy(formula, "B2", "A1");
+        let again = whole_expr_str(&copied).unwrap();
+        assert_eq!(print(&again), copied);
+        assert_eq!(copy(&copied, "A1", "B2"), copied);
+    }
+
+    // comments and layout stay as they were
+    assert_eq!(
+        copy("sum( a1,\n     b1 ) * rate", "A1", "A3"),
+        "SUM( A3,\n     B3 ) * rate"
+    );
+    assert_eq!(
+        copy("A1 /* the base */ + B1 //# and the rest", "C1", "C2"),
+        "A2 /* the base */ + B2 //# and the rest"
+    );
+
+    let filled = fill_formula(
+        &whole_expr_str("A1 + $B$1").unwrap(),
+        &at("C1"),
+        &"C2:D3".parse().unwrap(),
+    )
+    .unwrap();
+    let filled: Vec<(String, String)> = filled
+        .iter()
+        .map(|(at, e)| (at.to_string(), print(e)))
+        .collect();
+    assert_eq!(
+        filled,
+        vec![
+            ("C2".to_string(), "A2 + $B$1".to_string()),
+            ("D2".to_string(), "B2 + $B$1".to_string()),
+            ("C3".to_string(), "A3 + $B$1".to_string()),
+            ("D3".to_string(), "B3 + $B$1".to_string()),
+        ]
+    );
+    assert_eq!(
+        fill_formula(
+            &whole_expr_str("A1").unwrap(),
+            &at("A1"),
+            &"A:A".parse().unwrap()
+        )
+        .map_err(|e| e.to_string()),
+        Err("can't fill the whole of A:A".to_string())
+    );
+}
+
+#[test]
+fn test_move_references() {
+    use crate::printer::print;
+
+    let moved: SimpleRange = "A1:B2".parse().unwrap();
+    let to = "D5".parse::<SimpleAddress>().unwrap();
+    let follow = |formula: &str, on_sheet: &str| {
+        print(&move_references(
+            &whole_expr_str(formula).unwrap(),
+            on_sheet,
+            "Sheet1",
+            &moved,
+            &to,
+        ))
+    };
+
+    assert_eq!(
+        follow("A1 + $B$2 + C3 + SUM(A1:B2) + SUM(A1:C3)", "Sheet1"),
+        "D5 + $E$6 + C3 + SUM(D5:E6) + SUM(A1:C3)"
+    );
+    assert_eq!(
+        follow("A1 + sheet1!B1 + Other!A1", "Other"),
+        "A1 + sheet1!E5 + Other!A1"
+    );
+    assert_eq!(follow("Sheet1:Sheet3!A1", "Sheet1"), "Sheet1:Sheet3!A1");
+}
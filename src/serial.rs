@@ -0,0 +1,275 @@
+//! Saving formulas, compiled formulas and values as JSON or bytes
+//!
+//! Everything is wrapped in an envelope that records `SCHEMA_VERSION`,
+//! so a cache or snapshot written by a different version of the schema
+//! is rejected rather than misread
+
+// Copyright 2021 David Pollak
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// The version of the serialized form of `Expression`, `EvalStack`,
+/// `Value` and the types in them. Bump it whenever one of them changes
+/// shape: renaming or reordering variants or fields changes the schema
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// The first bytes of the binary form
+const MAGIC: &[u8; 4] = b"MESA";
+
+#[derive(Debug)]
+pub enum SerialError {
+    Json(serde_json::Error),
+    Binary(bincode::Error),
+    /// Written with a different version of the schema
+    Version {
+        found: u32,
+        expected: u32,
+    },
+    /// The bytes don't start with the binary header
+    NotMesa,
+}
+
+impl fmt::Display for SerialError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SerialError::Json(e) => write!(f, "bad JSON: {}", e),
+            SerialError::Binary(e) => write!(f, "bad binary data: {}", e),
+            SerialError::Version { found, expected } => write!(
+                f,
+                "written with schema version {} but this is version {}",
+                found, expected
+            ),
+            SerialError::NotMesa => write!(f, "not Mesa X binary data"),
+        }
+    }
+}
+
+impl std::error::Error for SerialError {}
+
+impl From<serde_json::Error> for SerialError {
+    fn from(e: serde_json::Error) -> Self {
+        SerialError::Json(e)
+    }
+}
+
+impl From<bincode::Error> for SerialError {
+    fn from(e: bincode::Error) -> Self {
+        SerialError::Binary(e)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Envelope<T> {
+    version: u32,
+    content: T,
+}
+
+fn check_version(found: u32) -> Result<(), SerialError> {
+    match found {
+        SCHEMA_VERSION => Ok(()),
+        found => Err(SerialError::Version {
+            found,
+            expected: SCHEMA_VERSION,
+        }),
+    }
+}
+
+/// `value` as JSON: `{"version": SCHEMA_VERSION, "content": ...}`
+pub fn to_json<T: Serialize>(value: &T) -> Result<String, SerialError> {
+    Ok(serde_json::to_string(&Envelope {
+        version: SCHEMA_VERSION,
+        content: value,
+    })?)
+}
+
+/// Read JSON written by `to_json`
+pub fn from_json<T: DeserializeOwned>(text: &str) -> Result<T, SerialError> {
+    #[derive(Deserialize)]
+    struct Version {
+        version: u32,
+    }
+
+    // check the version before trying to make sense of the content
+    check_version(serde_json::from_str::<Version>(text)?.version)?;
+    let envelope: Envelope<T> = serde_json::from_str(text)?;
+    Ok(envelope.content)
+}
+
+/// `value` in a compact binary form: `MESA`, the schema version as a
+/// little-endian `u32` and then the content
+pub fn to_binary<T: Serialize>(value: &T) -> Result<Vec<u8>, SerialError> {
+    let mut ret = MAGIC.to_vec();
+    ret.extend_from_slice(&SCHEMA_VERSION.to_le_bytes());
+    bincode::serialize_into(&mut ret, value)?;
+    Ok(ret)
+}
+
+/// Read bytes written by `to_binary`
+pub fn from_binary<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, SerialError> {
+    if bytes.len() < 8 || &bytes[..4] != MAGIC {
+        return Err(SerialError::NotMesa);
+    }
+    check_version(u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]))?;
+    Ok(bincode::deserialize(&bytes[8..])?)
+}
+
+#[test]
+fn test_round_trip() {
+    use crate::definitions::{JsonValue, Value};
+    use crate::eval_stack::{create_eval_stack, EvalStack};
+    use crate::parser::{whole_expr_str, Expression};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let expr = whole_expr_str(
+        "IF(Data!A1:B2 > 3, {1.5, \"two\"; -3, 170141183460469231731687303715884105727}, x) /* why */ + let x = 1; x%",
+    )
+    .unwrap();
+    let json = to_json(&expr).unwrap();
+    let from: Expression = from_json(&json).unwrap();
+    assert_eq!(from, expr);
+    // positions and comments survive too, but not the text
+    let mut info = expr.parse_info().clone().unwrap();
+    info.text = String::new();
+    assert_eq!(from.parse_info(), &Some(info.clone()));
+    let from: Expression = from_binary(&to_binary(&expr).unwrap()).unwrap();
+    assert_eq!(from.parse_info(), &Some(info));
+
+    // positions don't repeat the formula's text, so doubling the nesting
+    // doubles the size rather than quadrupling it
+    let sizes = |depth: usize| {
+        let formula = format!("{}1{}", "(1 + ".repeat(depth), ")".repeat(depth));
+        let expr = whole_expr_str(&formula).unwrap();
+        (
+            to_json(&expr).unwrap().len(),
+            to_binary(&expr).unwrap().len(),
+        )
+    };
+    let ((json_10, binary_10), (json_20, binary_20)) = (sizes(10), sizes(20));
+    assert!(json_20 < json_10 * 21 / 10, "{} then {}", json_10, json_20);
+    assert!(
+        binary_20 < binary_10 * 21 / 10,
+        "{} then {}",
+        binary_10,
+        binary_20
+    );
+
+    let stack = create_eval_stack(
+        &whole_expr_str("{1, 2} * A1 + B1:B2").unwrap(),
+        &HashMap::new(),
+    )
+    .unwrap();
+    assert_eq!(
+        from_json::<Vec<EvalStack>>(&to_json(&stack).unwrap()).unwrap(),
+        stack
+    );
+    assert_eq!(
+        from_binary::<Vec<EvalStack>>(&to_binary(&stack).unwrap()).unwrap(),
+        stack
+    );
+
+    let value = Value::Array(vec![vec![
+        Value::Int(-5),
+        Value::Float(0.25),
+        Value::Str("é".into()),
+        Value::Error(("#REF!".into(), 4)),
+        Value::Maybe(Some(Arc::new(Value::Bool(true)))),
+        Value::JSON(JsonValue::Json(
+            vec![(
+                "a".to_string(),
+                JsonValue::Array(vec![JsonValue::Null, JsonValue::IntNumber(7)]),
+            )]
+            .into_iter()
+            .collect(),
+        )),
+    ]]);
+    assert_eq!(
+        from_json::<Value>(&to_json(&value).unwrap()).unwrap(),
+        value
+    );
+    assert_eq!(
+        from_binary::<Value>(&to_binary(&value).unwrap()).unwrap(),
+        value
+    );
+
+    // JSON numbers can't be NaN or infinite
+    let floats = Value::Array(vec![vec![
+        Value::Float(f64::NAN),
+        Value::Float(f64::INFINITY),
+        Value::Float(f64::NEG_INFINITY),
+    ]]);
+    let json = to_json(&floats).unwrap();
+    assert!(json.contains(r#"{"Float":"NaN"},{"Float":"inf"},{"Float":"-inf"}"#));
+    for back in [
+        from_json::<Value>(&json).unwrap(),
+        from_binary::<Value>(&to_binary(&floats).unwrap()).unwrap(),
+    ] {
+        match back {
+            Value::Array(rows) => match rows[0][..] {
+                [Value::Float(nan), Value::Float(inf), Value::Float(neg)] => {
+                    assert!(nan.is_nan());
+                    assert_eq!((inf, neg), (f64::INFINITY, f64::NEG_INFINITY));
+                }
+                ref x => panic!("Expected three floats, got {:?}", x),
+            },
+            x => panic!("Expected an array, got {:?}", x),
+        }
+    }
+    let lots = format!(
+        r#"{{"version": {}, "content": {{"Float": "lots"}}}}"#,
+        SCHEMA_VERSION
+    );
+    assert!(from_json::<Value>(&lots).is_err());
+
+    // a date is written as the time since 1970, so the same date is
+    // always the same bytes
+    let date = Value::Date(UNIX_EPOCH + Duration::new(1_600_000_000, 123_456_789));
+    let json = to_json(&date).unwrap();
+    assert_eq!(
+        json,
+        format!(
+            r#"{{"version":{},"content":{{"Date":{{"secs_since_epoch":1600000000,"nanos_since_epoch":123456789}}}}}}"#,
+            SCHEMA_VERSION
+        )
+    );
+    assert_eq!(from_json::<Value>(&json).unwrap(), date);
+    let bytes = to_binary(&date).unwrap();
+    assert_eq!(to_binary(&date.clone()).unwrap(), bytes);
+    assert_eq!(from_binary::<Value>(&bytes).unwrap(), date);
+    assert!(to_json(&Value::Date(UNIX_EPOCH - Duration::from_secs(1))).is_err());
+
+    assert_eq!(
+        from_json::<Value>(r#"{"version": 99, "content": {"Int": 1}}"#)
+            .unwrap_err()
+            .to_string(),
+        format!(
+            "written with schema version 99 but this is version {}",
+            SCHEMA_VERSION
+        )
+    );
+    let mut bytes = to_binary(&value).unwrap();
+    bytes[4] = 7;
+    assert!(matches!(
+        from_binary::<Value>(&bytes),
+        Err(SerialError::Version { found: 7, .. })
+    ));
+    assert!(matches!(
+        from_binary::<Value>(b"{}"),
+        Err(SerialError::NotMesa)
+    ));
+}
@@ -0,0 +1,405 @@
+//! The comments and whitespace in a formula
+//!
+//! Comments and whitespace are attached to the expressions they go
+//! with, so they're kept when a formula is printed, reformatted or
+//! rewritten. The canonical printer keeps the comments and lays the
+//! formula out in its own style; `PrintOptions::as_written` keeps the
+//! author's layout too
+//!
+
+// Copyright 2021 David Pollak
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::parser::Expression;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, Serialize, Deserialize)]
+pub enum TriviaKind {
+    Whitespace,
+    /// A `/* */` comment, which may have comments nested in it
+    BlockComment,
+    /// A `//#` comment that runs to the end of the line
+    LineComment,
+}
+
+/// A part of a formula that doesn't change what the formula means.
+/// `start` and `end` are byte offsets and `text` is the trivia as
+/// written, including the comment markers
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Serialize, Deserialize)]
+pub struct Trivia {
+    pub kind: TriviaKind,
+    pub text: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Trivia {
+    pub fn is_comment(&self) -> bool {
+        self.kind != TriviaKind::Whitespace
+    }
+}
+
+/// All the comments and whitespace in a formula in the order they appear.
+/// Together with the text between them this is the whole formula, so
+/// the layout of a formula can be recovered exactly
+pub fn trivia(formula: &str) -> Vec<Trivia> {
+    let mut ret = vec![];
+    let mut chars = formula.char_indices().peekable();
+    let is_space = |c: char| c == ' ' || c == '\t' || c == '\n' || c == '\r';
+
+    let mut push = |kind: TriviaKind, start: usize, end: usize| {
+        ret.push(Trivia {
+            kind,
+            text: formula[start..end].to_string(),
+            start,
+            end,
+        })
+    };
+
+    while let Some((pos, c)) = chars.next() {
+        match c {
+            // the text of strings and quoted sheet names isn't trivia
+            '"' => {
+                while let Some((_, c2)) = chars.next() {
+                    if c2 == '\\' {
+                        chars.next();
+                    } else if c2 == '"' {
+                        break;
+                    }
+                }
+            }
+            '\'' => {
+                while let Some((p2, c2)) = chars.next() {
+                    if c2 == '\'' && !formula[p2 + 1..].starts_with('\'') {
+                        break;
+                    }
+                    if c2 == '\'' {
+                        chars.next();
+                    }
+                }
+            }
+            '/' if formula[pos..].starts_with("/*") => {
+                chars.next();
+                let mut depth = 1;
+                let mut end = formula.len();
+                while let Some((p2, c2)) = chars.next() {
+                    if c2 == '/' && formula[p2..].starts_with("/*") {
+                        chars.next();
+                        depth += 1;
+                    } else if c2 == '*' && formula[p2..].starts_with("*/") {
+                        chars.next();
+                        depth -= 1;
+                        if depth == 0 {
+                            end = p2 + 2;
+                            break;
+                        }
+                    }
+                }
+                push(TriviaKind::BlockComment, pos, end);
+            }
+            '/' if formula[pos..].starts_with("//#") => {
+                let mut end = formula.len();
+                while let Some((p2, c2)) = chars.peek() {
+                    if *c2 == '\n' || *c2 == '\r' {
+                        end = *p2;
+                        break;
+                    }
+                    chars.next();
+                }
+                push(TriviaKind::LineComment, pos, end);
+            }
+            c if is_space(c) => {
+                let mut end = formula.len();
+                while let Some((p2, c2)) = chars.peek() {
+                    if !is_space(*c2) {
+                        end = *p2;
+                        break;
+                    }
+                    chars.next();
+                }
+                push(TriviaKind::Whitespace, pos, end);
+            }
+            _ => (),
+        }
+    }
+
+    ret
+}
+
+/// Attach each comment and run of whitespace in `formula` to an
+/// expression in the tree parsed from it. A comment on the same line
+/// after an expression (or after the `,` or `;` following it), or
+/// followed by an operator or delimiter rather than an expression,
+/// trails the outermost expression that ends just before it. Any other
+/// comment leads the outermost expression that starts just after it.
+/// Whitespace goes with a comment after it that trails an expression,
+/// and otherwise leads the expression after it or trails the one before
+/// it. Whitespace with no expression on either side, like the space in
+/// `f( )`, isn't kept
+pub(crate) fn attach_trivia(formula: &str, expr: &mut Expression) {
+    let all = trivia(formula);
+    if all.is_empty() {
+        return;
+    }
+
+    let mut in_trivia = vec![false; formula.len()];
+    for t in &all {
+        in_trivia[t.start..t.end].iter_mut().for_each(|b| *b = true);
+    }
+
+    let mut spans = vec![];
+    core_spans(expr, &in_trivia, &mut spans);
+
+    let mut targets: Vec<(usize, bool, Trivia)> = vec![];
+    for comment in all.iter().filter(|t| t.is_comment()) {
+        let next = (comment.end..formula.len())
+            .find(|i| !in_trivia[*i])
+            .unwrap_or(formula.len());
+        let prev = (0..comment.start)
+            .rev()
+            .find(|i| !in_trivia[*i])
+            .map(|i| i + 1)
+            .unwrap_or(0);
+        let same_line = !formula[prev..comment.start].contains('\n');
+
+        // a comment just after a `,` or `;` goes with what's before it
+        let before_separator = if same_line && formula[..prev].ends_with([',', ';']) {
+            (0..prev - 1)
+                .rev()
+                .find(|i| !in_trivia[*i])
+                .map(|i| i + 1)
+                .unwrap_or(0)
+        } else {
+            prev
+        };
+
+        let ends_before = spans
+            .iter()
+            .position(|s| matches!(s, Some((_, end)) if *end == before_separator));
+        let starts_after = spans
+            .iter()
+            .position(|s| matches!(s, Some((start, _)) if *start == next));
+
+        let target = match (ends_before, starts_after) {
+            (Some(node), _) if same_line || comment.kind == TriviaKind::LineComment => {
+                (node, false)
+            }
+            (_, Some(node)) => (node, true),
+            (Some(node), None) => (node, false),
+            (None, None) => (0, false),
+        };
+        targets.push((target.0, target.1, comment.clone()));
+    }
+
+    for (i, space) in all.iter().enumerate().filter(|(_, t)| !t.is_comment()) {
+        // the comments in the same run of trivia after the whitespace
+        let mut end = space.end;
+        let run_after = all[i + 1..].iter().take_while(|t| {
+            let in_run = t.start == end;
+            end = t.end;
+            in_run
+        });
+        let trails_comment = run_after
+            .filter_map(|t| targets.iter().find(|(_, _, c)| c.start == t.start))
+            .find(|(_, leading, _)| !leading)
+            .map(|(node, _, _)| *node);
+
+        let next = (space.end..formula.len()).find(|i| !in_trivia[*i]);
+        let prev = (0..space.start)
+            .rev()
+            .find(|i| !in_trivia[*i])
+            .map(|i| i + 1);
+        let target = match trails_comment {
+            Some(node) => Some((node, false)),
+            None => spans
+                .iter()
+                .position(|s| matches!((s, next), (Some((start, _)), Some(next)) if *start == next))
+                .map(|node| (node, true))
+                .or_else(|| {
+                    spans
+                        .iter()
+                        .position(
+                            |s| matches!((s, prev), (Some((_, end)), Some(prev)) if *end == prev),
+                        )
+                        .map(|node| (node, false))
+                }),
+        };
+        if let Some((node, leading)) = target {
+            targets.push((node, leading, space.clone()));
+        }
+    }
+    targets.sort_by_key(|(_, _, t)| t.start);
+
+    let mut index = 0;
+    attach(expr, &mut index, &mut targets);
+}
+
+/// The spans of the expressions in the tree, outermost first, without
+/// any comments or whitespace at either end
+fn core_spans(expr: &Expression, in_trivia: &[bool], to: &mut Vec<Option<(usize, usize)>>) {
+    to.push(expr.parse_info().as_ref().map(|info| {
+        let end = info.end.min(in_trivia.len());
+        let start = (info.start..end).find(|i| !in_trivia[*i]);
+        let last = (info.start..end).rev().find(|i| !in_trivia[*i]);
+        match (start, last) {
+            (Some(start), Some(last)) => (start, last + 1),
+            _ => (info.start, info.start),
+        }
+    }));
+    for e in expr.children() {
+        core_spans(e, in_trivia, to);
+    }
+}
+
+/// Put the trivia targeted at each expression into its `ParseInfo`.
+/// Expressions are numbered in the same order as `core_spans`
+fn attach(expr: &mut Expression, index: &mut usize, targets: &mut Vec<(usize, bool, Trivia)>) {
+    let me = *index;
+    *index += 1;
+
+    if let Some(info) = expr.parse_info_mut() {
+        for (_, leading, trivia) in targets.iter().filter(|(node, _, _)| *node == me) {
+            if *leading {
+                info.leading.push(trivia.clone());
+            } else {
+                info.trailing.push(trivia.clone());
+            }
+        }
+    }
+    targets.retain(|(node, _, _)| *node != me);
+
+    for e in expr.children_mut() {
+        attach(e, index, targets);
+    }
+}
+
+#[test]
+fn test_trivia() {
+    let found = trivia("1 /* a /* b */ */+\n \"/* no */\" //# end\r\n'x /*'!A1 //#");
+    let kinds: Vec<(TriviaKind, &str)> = found.iter().map(|t| (t.kind, t.text.as_str())).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            (TriviaKind::Whitespace, " "),
+            (TriviaKind::BlockComment, "/* a /* b */ */"),
+            (TriviaKind::Whitespace, "\n "),
+            (TriviaKind::Whitespace, " "),
+            (TriviaKind::LineComment, "//# end"),
+            (TriviaKind::Whitespace, "\r\n"),
+            (TriviaKind::Whitespace, " "),
+            (TriviaKind::LineComment, "//#"),
+        ]
+    );
+    assert_eq!((found[1].start, found[1].end), (2, 17));
+}
+
+#[test]
+fn test_attach_trivia() {
+    use crate::parser::whole_expr_str;
+
+    let comments = |e: &Expression| -> (Vec<String>, Vec<String>) {
+        match e.parse_info() {
+            Some(info) => (
+                info.leading
+                    .iter()
+                    .filter(|t| t.is_comment())
+                    .map(|t| t.text.clone())
+                    .collect(),
+                info.trailing
+                    .iter()
+                    .filter(|t| t.is_comment())
+                    .map(|t| t.text.clone())
+                    .collect(),
+            ),
+            None => (vec![], vec![]),
+        }
+    };
+
+    let e = whole_expr_str(
+        "=IF(A1 > B2 && B3 > G6 //# north beats west
+    ||
+    /* or rounding errors */
+    SUM(QQ99:ZZ107) > AVE(D4:D9),
+    //# then
+    AVE(Q2:Q9), 0 /* none */)",
+    )
+    .unwrap();
+
+    let params = match &e {
+        Expression::Function(_, _, params, _) => params,
+        other => panic!("Expected a function, got {:?}", other),
+    };
+    let (or_left, or_right) = match &params[0] {
+        Expression::Infix(opr, left, right, _) if opr == "||" => (left, right),
+        other => panic!("Expected ||, got {:?}", other),
+    };
+    assert_eq!(
+        comments(or_left),
+        (vec![], vec!["//# north beats west".to_string()])
+    );
+    assert_eq!(
+        comments(or_right),
+        (vec!["/* or rounding errors */".to_string()], vec![])
+    );
+    assert_eq!(comments(&params[1]), (vec!["//# then".to_string()], vec![]));
+    assert_eq!(
+        comments(&params[2]),
+        (vec![], vec!["/* none */".to_string()])
+    );
+
+    // a comment before an operator trails what's before it
+    match whole_expr_str("1 /* one */ + /* two */ 2").unwrap() {
+        Expression::Infix(_, left, right, _) => {
+            assert_eq!(comments(&left), (vec![], vec!["/* one */".to_string()]));
+            assert_eq!(comments(&right), (vec!["/* two */".to_string()], vec![]));
+        }
+        other => panic!("Expected an Infix, got {:?}", other),
+    }
+
+    // a comment after a separator goes with what's before the separator
+    match whole_expr_str("f(1, /* one */ 2)").unwrap() {
+        Expression::Function(_, _, params, _) => {
+            assert_eq!(
+                comments(&params[0]),
+                (vec![], vec!["/* one */".to_string()])
+            );
+        }
+        other => panic!("Expected a function, got {:?}", other),
+    }
+
+    // a comment with nothing to go with ends up on the whole formula
+    let e = whole_expr_str("f( /* empty */ )").unwrap();
+    assert_eq!(comments(&e), (vec![], vec!["/* empty */".to_string()]));
+
+    // whitespace goes with a comment that trails, and otherwise leads
+    // what's after it
+    let all = |e: &Expression| {
+        let info = e.parse_info().as_ref().unwrap();
+        let texts =
+            |found: &[Trivia]| -> Vec<String> { found.iter().map(|t| t.text.clone()).collect() };
+        (texts(&info.leading), texts(&info.trailing))
+    };
+    match whole_expr_str(" f(1 , /* one */\n  2 )").unwrap() {
+        Expression::Function(_, _, params, info) => {
+            let info = info.unwrap();
+            assert_eq!((info.leading.len(), info.trailing.len()), (1, 0));
+            assert_eq!(
+                all(&params[0]),
+                (vec![], vec![" ".into(), " ".into(), "/* one */".into()])
+            );
+            assert_eq!(all(&params[1]), (vec!["\n  ".into()], vec![" ".into()]));
+        }
+        other => panic!("Expected a function, got {:?}", other),
+    }
+}
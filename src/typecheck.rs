@@ -0,0 +1,392 @@
+//! Inferring the types in a formula before it's evaluated
+//!
+
+// Copyright 2021 David Pollak
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::diagnostic::ParseDiagnostic;
+use crate::eval_stack::{OperatorInfo, ResultType, Types};
+use crate::functions::{builtins, has_decorators, Registry};
+use crate::parser::{whole_expr_str, Expression, ParseInfo};
+use crate::printer::print;
+
+use Types::*;
+
+const NUMERIC: &[Types] = Types::NUMERIC;
+const LOGICAL: &[Types] = Types::LOGICAL;
+const SCALAR: &[Types] = Types::SCALAR;
+
+/// The signature of a binary operator
+pub fn binary_operator(opr: &str) -> Option<OperatorInfo> {
+    let (params, result) = match opr {
+        "+" | "-" | "*" | "%" => (NUMERIC, ResultType::Arithmetic),
+        "/" | "^" => (NUMERIC, ResultType::Division),
+        "==" | "!=" | "<>" | "<" | "<=" | ">" | ">=" => (SCALAR, ResultType::Is(Bool)),
+        "&&" | "||" => (LOGICAL, ResultType::Is(Bool)),
+        "&" => (SCALAR, ResultType::Is(Str)),
+        _ => return None,
+    };
+    Some(OperatorInfo::new(opr, 2, Some(2), &[params], result))
+}
+
+/// The signature of a prefix operator
+pub fn prefix_operator(opr: &str) -> Option<OperatorInfo> {
+    let (params, result) = match opr {
+        "-" => (NUMERIC, ResultType::Arithmetic),
+        "+" => (SCALAR, ResultType::Common(0)),
+        "!" => (LOGICAL, ResultType::Is(Bool)),
+        _ => return None,
+    };
+    Some(OperatorInfo::new(opr, 1, Some(1), &[params], result))
+}
+
+/// The signature of a postfix operator
+pub fn postfix_operator(opr: &str) -> Option<OperatorInfo> {
+    match opr {
+        "%" => Some(OperatorInfo::new(
+            opr,
+            1,
+            Some(1),
+            &[NUMERIC],
+            ResultType::Is(Float),
+        )),
+        _ => None,
+    }
+}
+
+/// The signature of a built-in function. Names are upper case
+pub fn function(name: &str) -> Option<OperatorInfo> {
+    builtins().get(name).map(|f| f.signature())
+}
+
+/// A name for a type in messages
+fn describe(t: Types) -> &'static str {
+    match t {
+        Int => "an integer",
+        Float => "a float",
+        Str => "text",
+        Bool => "a boolean",
+        Number => "a number",
+        Array => "an array",
+        Error => "an error",
+        Any => "any value",
+    }
+}
+
+/// A parameter of an operator or function that has the wrong type, or a
+/// function given the wrong number of parameters. `start` and `end` are
+/// the byte offsets of the expression with the problem
+#[derive(Debug, PartialEq, Eq, Clone, Hash)]
+pub struct TypeError {
+    pub start: usize,
+    pub end: usize,
+    pub message: String,
+}
+
+impl TypeError {
+    /// The error as a diagnostic with a line and column in `formula`
+    pub fn diagnostic(&self, formula: &str) -> ParseDiagnostic {
+        ParseDiagnostic::problem(formula, self.start, self.message.clone())
+    }
+}
+
+/// The types inferred for a formula
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct TypeCheck {
+    /// The type of the whole formula
+    pub result: Types,
+    /// The type of every expression in the order a `Visitor` visits
+    /// them, so code generation can pick instructions for the types
+    pub types: Vec<Types>,
+    pub errors: Vec<TypeError>,
+}
+
+/// Infer the types in `expr` and find the operators and functions given
+/// parameters they can't take. Functions that aren't built in and names
+/// that aren't bound by a `let` could be anything
+pub fn check(expr: &Expression) -> TypeCheck {
+    check_with(expr, &builtins())
+}
+
+/// Check `expr` against the functions in `registry` rather than just
+/// the built-in ones
+pub fn check_with(expr: &Expression, registry: &Registry) -> TypeCheck {
+    let mut checker = Checker {
+        registry,
+        types: vec![],
+        errors: vec![],
+        scope: vec![],
+    };
+    let result = checker.infer(expr);
+    TypeCheck {
+        result,
+        types: checker
+            .types
+            .into_iter()
+            .map(|t| t.unwrap_or(Any))
+            .collect(),
+        errors: checker.errors,
+    }
+}
+
+/// Parse and check a formula, giving the type errors as diagnostics
+pub fn check_str(formula: &str) -> Result<Types, Vec<ParseDiagnostic>> {
+    let expr = whole_expr_str(formula)?;
+    let checked = check(&expr);
+    match checked.errors.is_empty() {
+        true => Ok(checked.result),
+        false => Err(checked
+            .errors
+            .iter()
+            .map(|e| e.diagnostic(formula))
+            .collect()),
+    }
+}
+
+struct Checker<'a> {
+    registry: &'a Registry,
+    types: Vec<Option<Types>>,
+    errors: Vec<TypeError>,
+    /// The types of the names bound by the `let`s around the current
+    /// expression
+    scope: Vec<(String, Types)>,
+}
+
+impl Checker<'_> {
+    fn infer(&mut self, expr: &Expression) -> Types {
+        // parents are numbered before their children
+        let me = self.types.len();
+        self.types.push(None);
+
+        let ret = match expr {
+            Expression::Int(..) => Int,
+            Expression::Float(..) => Float,
+            Expression::Str(..) => Str,
+            Expression::Identifier(name, _) if name == "TRUE" || name == "FALSE" => Bool,
+            Expression::Identifier(name, _) => self
+                .scope
+                .iter()
+                .rev()
+                .find(|(n, _)| n == name)
+                .map(|(_, t)| *t)
+                .unwrap_or(Any),
+            Expression::DottedIdentifier(..) | Expression::Address(..) => Any,
+            Expression::Range(..) | Expression::Array(..) => {
+                for e in expr.children() {
+                    self.infer(e);
+                }
+                Array
+            }
+            Expression::Paren(inner, _) => self.infer(inner),
+            Expression::Let(name, value, body, _) => {
+                let value = self.infer(value);
+                self.scope.push((name.clone(), value));
+                let ret = self.infer(body);
+                self.scope.pop();
+                ret
+            }
+            Expression::Infix(opr, left, right, _) => {
+                let params = [(self.infer(left), &**left), (self.infer(right), &**right)];
+                self.apply(binary_operator(opr), &format!("'{}'", opr), expr, &params)
+            }
+            Expression::Prefix(opr, operand, _) => {
+                let params = [(self.infer(operand), &**operand)];
+                self.apply(prefix_operator(opr), &format!("'{}'", opr), expr, &params)
+            }
+            Expression::Postfix(opr, operand, _) => {
+                let params = [(self.infer(operand), &**operand)];
+                self.apply(postfix_operator(opr), &format!("'{}'", opr), expr, &params)
+            }
+            Expression::Function(name, type_params, params, _) => {
+                for e in type_params {
+                    self.infer(e);
+                }
+                let params: Vec<(Types, &Expression)> =
+                    params.iter().map(|e| (self.infer(e), e)).collect();
+                let found = self.registry.get(name);
+                if let Some(f) = &found {
+                    for decorator in type_params {
+                        if !has_decorators(f.as_ref(), &[print(decorator)]) {
+                            self.error(
+                                decorator.parse_info(),
+                                format!("{} doesn't have the decorator {}", name, print(decorator)),
+                            );
+                        }
+                    }
+                }
+                self.apply(found.map(|f| f.signature()), name, expr, &params)
+            }
+            Expression::ErrorValue(..) => Error,
+            Expression::Missing(..) | Expression::Error(..) => Any,
+        };
+
+        self.types[me] = Some(ret);
+        ret
+    }
+
+    fn error(&mut self, info: &ParseInfo, message: String) {
+        let (start, end) = info.as_ref().map(|info| info.span()).unwrap_or((0, 0));
+        self.errors.push(TypeError {
+            start,
+            end,
+            message,
+        })
+    }
+
+    /// Check the parameters against a signature and work out the type of
+    /// the result. Without a signature the result could be anything
+    fn apply(
+        &mut self,
+        sig: Option<OperatorInfo>,
+        what: &str,
+        expr: &Expression,
+        params: &[(Types, &Expression)],
+    ) -> Types {
+        let sig = match sig {
+            Some(sig) => sig,
+            None => return Any,
+        };
+        let is_operator = !matches!(expr, Expression::Function(..));
+
+        let count = params.len() as u16;
+        if !sig.takes(params.len()) {
+            let expected = match sig.max_params {
+                Some(max) if max == sig.num_params => max.to_string(),
+                Some(max) => format!("{} to {}", sig.num_params, max),
+                None => format!("at least {}", sig.num_params),
+            };
+            let plural = match sig.max_params {
+                Some(max) if max != sig.num_params => "s",
+                _ if sig.num_params == 1 => "",
+                _ => "s",
+            };
+            self.error(
+                expr.parse_info(),
+                format!(
+                    "{} takes {} parameter{} but was given {}",
+                    what, expected, plural, count
+                ),
+            );
+        }
+
+        let mut broadcast = false;
+        for (pos, (t, e)) in params.iter().enumerate() {
+            let allowed = sig.param_types(pos as u16);
+            match t {
+                Any | Error => (),
+                // an operator is applied to each item of an array
+                Array if is_operator => broadcast = true,
+                t if allowed.contains(t) => (),
+                t => {
+                    let message = match is_operator {
+                        true => format!("{} can't be used with {}", what, describe(*t)),
+                        false => format!(
+                            "{} can't take {} as parameter {}",
+                            what,
+                            describe(*t),
+                            pos + 1
+                        ),
+                    };
+                    self.error(e.parse_info(), message);
+                }
+            }
+        }
+        if is_operator && params.iter().any(|(t, _)| *t == Error) {
+            return Error;
+        }
+        if broadcast {
+            return Array;
+        }
+
+        let types: Vec<Types> = params.iter().map(|(t, _)| *t).collect();
+        match sig.result {
+            ResultType::Is(t) => t,
+            ResultType::Arithmetic | ResultType::Division if types.contains(&Float) => Float,
+            ResultType::Arithmetic if types.iter().all(|t| *t == Int || *t == Bool) => Int,
+            ResultType::Arithmetic | ResultType::Division => Number,
+            ResultType::Common(from) => {
+                let rest = &types[(from as usize).min(types.len())..];
+                match rest.split_first() {
+                    Some((first, rest)) if rest.iter().all(|t| t == first) => *first,
+                    _ => Any,
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_check() {
+    let result = |formula: &str| {
+        check_str(formula)
+            .map_err(|errs| errs.iter().map(|e| e.to_string()).collect::<Vec<String>>())
+    };
+
+    assert_eq!(result("1 + 2 * 3"), Ok(Int));
+    assert_eq!(result("1 + 2.5"), Ok(Float));
+    assert_eq!(result("7 / 2"), Ok(Number));
+    assert_eq!(result("-TRUE"), Ok(Int));
+    assert_eq!(result("50%"), Ok(Float));
+    assert_eq!(result("1 & \"a\""), Ok(Str));
+    assert_eq!(result("A1 * 2"), Ok(Number));
+    assert_eq!(result("A1 > 2 && B2"), Ok(Bool));
+    assert_eq!(result("{1, 2} * 3"), Ok(Array));
+    assert_eq!(result("A1:A9 + 1"), Ok(Array));
+    assert_eq!(result("let x = 2; x * 3"), Ok(Int));
+    assert_eq!(result("let x = \"a\"; x & x"), Ok(Str));
+    assert_eq!(result("SUM(A1:A9, 2)"), Ok(Number));
+    assert_eq!(result("IF(A1, 1, 2)"), Ok(Int));
+    assert_eq!(result("IF(A1, 1, \"no\")"), Ok(Any));
+    assert_eq!(result("COUNT(A1:B2) + ROUND(2.5)"), Ok(Float));
+    assert_eq!(result("MYSTERY(\"a\") * 2"), Ok(Number));
+    assert_eq!(result("#REF! * 2 & \"x\""), Ok(Error));
+    assert_eq!(result("IF(A1, #N/A, 2)"), Ok(Any));
+
+    assert_eq!(
+        result("\"abc\" * 3"),
+        Err(vec!["1:1: '*' can't be used with text".to_string()])
+    );
+    assert_eq!(
+        result("1 +\n  -\"x\" || !\"y\""),
+        Err(vec![
+            "2:4: '-' can't be used with text".to_string(),
+            "2:12: '!' can't be used with text".to_string(),
+        ])
+    );
+    assert_eq!(
+        result("let x = \"a\"; x * 3"),
+        Err(vec!["1:14: '*' can't be used with text".to_string()])
+    );
+    assert_eq!(
+        result("SUM(1, \"two\")"),
+        Err(vec!["1:8: SUM can't take text as parameter 2".to_string()])
+    );
+    assert_eq!(
+        result("ROUND(1, 2, 3) + NOT() + AND() + IF(1)"),
+        Err(vec![
+            "1:1: ROUND takes 1 to 2 parameters but was given 3".to_string(),
+            "1:18: NOT takes 1 parameter but was given 0".to_string(),
+            "1:26: AND takes at least 1 parameter but was given 0".to_string(),
+            "1:34: IF takes 2 to 3 parameters but was given 1".to_string(),
+        ])
+    );
+    assert_eq!(
+        result("ABS[FAST](1)"),
+        Err(vec!["1:5: ABS doesn't have the decorator FAST".to_string()])
+    );
+
+    // every expression gets a type, parents first
+    let checked = check(&whole_expr_str("1 + (2.5 * \"a\")").unwrap());
+    assert_eq!(checked.types, vec![Float, Int, Float, Float, Float, Str]);
+}
@@ -13,7 +13,7 @@ pub fn to_vec_uc_string(vstr: &Vec<&str>) -> Vec<String> {
 }
 
 /// Join a `Vec` of `String` into a `String`
-pub fn vec_string_to_string(v: &Vec<String>) -> String {
+pub fn vec_string_to_string(v: &[String]) -> String {
     let r2 = v.iter().fold(String::from(""), |mut sum, the_str| {
         sum.push_str(the_str);
         sum
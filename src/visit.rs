@@ -0,0 +1,484 @@
+//! Walking and rewriting `Expression` trees
+//!
+//! `Visitor` looks at a tree, `VisitorMut` changes it in place and `Fold`
+//! turns it into a new tree. Each has a method for every variant of
+//! `Expression` that by default goes on to the expressions inside it, so
+//! an analysis only overrides the variants it cares about
+
+// Copyright 2021 David Pollak
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::definitions::CellError;
+use crate::parser::{Address, Expression, ParseInfo, Range};
+
+/// Look at every expression in a tree, parents before children and
+/// children in the order they appear in the formula
+pub trait Visitor {
+    /// Visit any expression. By default this calls the method for the
+    /// expression's variant
+    fn visit_expr(&mut self, expr: &Expression) {
+        walk_expr(self, expr)
+    }
+
+    fn visit_int(&mut self, _value: i128, _info: &ParseInfo) {}
+
+    fn visit_float(&mut self, _value: f64, _info: &ParseInfo) {}
+
+    fn visit_str(&mut self, _value: &str, _info: &ParseInfo) {}
+
+    fn visit_dotted_identifier(&mut self, _names: &[String], _info: &ParseInfo) {}
+
+    fn visit_identifier(&mut self, _name: &str, _info: &ParseInfo) {}
+
+    fn visit_paren(&mut self, inner: &Expression, _info: &ParseInfo) {
+        self.visit_expr(inner)
+    }
+
+    fn visit_address(&mut self, _addr: &Address, _info: &ParseInfo) {}
+
+    fn visit_range(&mut self, _range: &Range, _info: &ParseInfo) {}
+
+    fn visit_function(
+        &mut self,
+        _name: &str,
+        type_params: &[Expression],
+        params: &[Expression],
+        _info: &ParseInfo,
+    ) {
+        for e in type_params.iter().chain(params) {
+            self.visit_expr(e)
+        }
+    }
+
+    fn visit_infix(
+        &mut self,
+        _opr: &str,
+        left: &Expression,
+        right: &Expression,
+        _info: &ParseInfo,
+    ) {
+        self.visit_expr(left);
+        self.visit_expr(right)
+    }
+
+    fn visit_prefix(&mut self, _opr: &str, operand: &Expression, _info: &ParseInfo) {
+        self.visit_expr(operand)
+    }
+
+    fn visit_postfix(&mut self, _opr: &str, operand: &Expression, _info: &ParseInfo) {
+        self.visit_expr(operand)
+    }
+
+    fn visit_let(&mut self, _name: &str, value: &Expression, body: &Expression, _info: &ParseInfo) {
+        self.visit_expr(value);
+        self.visit_expr(body)
+    }
+
+    fn visit_array(&mut self, rows: &[Vec<Expression>], _info: &ParseInfo) {
+        for e in rows.iter().flatten() {
+            self.visit_expr(e)
+        }
+    }
+
+    fn visit_error_value(&mut self, _error: CellError, _info: &ParseInfo) {}
+
+    fn visit_missing(&mut self, _what: &str, _info: &ParseInfo) {}
+
+    fn visit_error(&mut self, _text: &str, _info: &ParseInfo) {}
+}
+
+/// Call the `Visitor` method for the variant of `expr`
+pub fn walk_expr<V: Visitor + ?Sized>(visitor: &mut V, expr: &Expression) {
+    match expr {
+        Expression::Int(i, info) => visitor.visit_int(*i, info),
+        Expression::Float(f, info) => visitor.visit_float(*f, info),
+        Expression::Str(s, info) => visitor.visit_str(s, info),
+        Expression::DottedIdentifier(names, info) => visitor.visit_dotted_identifier(names, info),
+        Expression::Identifier(name, info) => visitor.visit_identifier(name, info),
+        Expression::Paren(inner, info) => visitor.visit_paren(inner, info),
+        Expression::Address(addr, info) => visitor.visit_address(addr, info),
+        Expression::Range(range, info) => visitor.visit_range(range, info),
+        Expression::Function(name, type_params, params, info) => {
+            visitor.visit_function(name, type_params, params, info)
+        }
+        Expression::Infix(opr, left, right, info) => visitor.visit_infix(opr, left, right, info),
+        Expression::Prefix(opr, operand, info) => visitor.visit_prefix(opr, operand, info),
+        Expression::Postfix(opr, operand, info) => visitor.visit_postfix(opr, operand, info),
+        Expression::Let(name, value, body, info) => visitor.visit_let(name, value, body, info),
+        Expression::Array(rows, info) => visitor.visit_array(rows, info),
+        Expression::ErrorValue(error, info) => visitor.visit_error_value(*error, info),
+        Expression::Missing(what, info) => visitor.visit_missing(what, info),
+        Expression::Error(text, info) => visitor.visit_error(text, info),
+    }
+}
+
+/// Change the expressions in a tree in place, visiting them in the same
+/// order as `Visitor`. Overriding `visit_expr_mut` allows replacing a
+/// whole expression
+pub trait VisitorMut {
+    /// Visit any expression. By default this calls the method for the
+    /// expression's variant
+    fn visit_expr_mut(&mut self, expr: &mut Expression) {
+        walk_expr_mut(self, expr)
+    }
+
+    fn visit_int_mut(&mut self, _value: &mut i128, _info: &mut ParseInfo) {}
+
+    fn visit_float_mut(&mut self, _value: &mut f64, _info: &mut ParseInfo) {}
+
+    fn visit_str_mut(&mut self, _value: &mut String, _info: &mut ParseInfo) {}
+
+    fn visit_dotted_identifier_mut(&mut self, _names: &mut Vec<String>, _info: &mut ParseInfo) {}
+
+    fn visit_identifier_mut(&mut self, _name: &mut String, _info: &mut ParseInfo) {}
+
+    fn visit_paren_mut(&mut self, inner: &mut Expression, _info: &mut ParseInfo) {
+        self.visit_expr_mut(inner)
+    }
+
+    fn visit_address_mut(&mut self, _addr: &mut Address, _info: &mut ParseInfo) {}
+
+    fn visit_range_mut(&mut self, _range: &mut Range, _info: &mut ParseInfo) {}
+
+    fn visit_function_mut(
+        &mut self,
+        _name: &mut String,
+        type_params: &mut Vec<Expression>,
+        params: &mut Vec<Expression>,
+        _info: &mut ParseInfo,
+    ) {
+        for e in type_params.iter_mut().chain(params.iter_mut()) {
+            self.visit_expr_mut(e)
+        }
+    }
+
+    fn visit_infix_mut(
+        &mut self,
+        _opr: &mut String,
+        left: &mut Expression,
+        right: &mut Expression,
+        _info: &mut ParseInfo,
+    ) {
+        self.visit_expr_mut(left);
+        self.visit_expr_mut(right)
+    }
+
+    fn visit_prefix_mut(
+        &mut self,
+        _opr: &mut String,
+        operand: &mut Expression,
+        _info: &mut ParseInfo,
+    ) {
+        self.visit_expr_mut(operand)
+    }
+
+    fn visit_postfix_mut(
+        &mut self,
+        _opr: &mut String,
+        operand: &mut Expression,
+        _info: &mut ParseInfo,
+    ) {
+        self.visit_expr_mut(operand)
+    }
+
+    fn visit_let_mut(
+        &mut self,
+        _name: &mut String,
+        value: &mut Expression,
+        body: &mut Expression,
+        _info: &mut ParseInfo,
+    ) {
+        self.visit_expr_mut(value);
+        self.visit_expr_mut(body)
+    }
+
+    fn visit_array_mut(&mut self, rows: &mut Vec<Vec<Expression>>, _info: &mut ParseInfo) {
+        for e in rows.iter_mut().flatten() {
+            self.visit_expr_mut(e)
+        }
+    }
+
+    fn visit_error_value_mut(&mut self, _error: &mut CellError, _info: &mut ParseInfo) {}
+
+    fn visit_missing_mut(&mut self, _what: &mut String, _info: &mut ParseInfo) {}
+
+    fn visit_error_mut(&mut self, _text: &mut String, _info: &mut ParseInfo) {}
+}
+
+/// Call the `VisitorMut` method for the variant of `expr`
+pub fn walk_expr_mut<V: VisitorMut + ?Sized>(visitor: &mut V, expr: &mut Expression) {
+    match expr {
+        Expression::Int(i, info) => visitor.visit_int_mut(i, info),
+        Expression::Float(f, info) => visitor.visit_float_mut(f, info),
+        Expression::Str(s, info) => visitor.visit_str_mut(s, info),
+        Expression::DottedIdentifier(names, info) => {
+            visitor.visit_dotted_identifier_mut(names, info)
+        }
+        Expression::Identifier(name, info) => visitor.visit_identifier_mut(name, info),
+        Expression::Paren(inner, info) => visitor.visit_paren_mut(inner, info),
+        Expression::Address(addr, info) => visitor.visit_address_mut(addr, info),
+        Expression::Range(range, info) => visitor.visit_range_mut(range, info),
+        Expression::Function(name, type_params, params, info) => {
+            visitor.visit_function_mut(name, type_params, params, info)
+        }
+        Expression::Infix(opr, left, right, info) => {
+            visitor.visit_infix_mut(opr, left, right, info)
+        }
+        Expression::Prefix(opr, operand, info) => visitor.visit_prefix_mut(opr, operand, info),
+        Expression::Postfix(opr, operand, info) => visitor.visit_postfix_mut(opr, operand, info),
+        Expression::Let(name, value, body, info) => visitor.visit_let_mut(name, value, body, info),
+        Expression::Array(rows, info) => visitor.visit_array_mut(rows, info),
+        Expression::ErrorValue(error, info) => visitor.visit_error_value_mut(error, info),
+        Expression::Missing(what, info) => visitor.visit_missing_mut(what, info),
+        Expression::Error(text, info) => visitor.visit_error_mut(text, info),
+    }
+}
+
+/// Build a new tree from an old one. Children are folded before their
+/// parent, so each method is given parts that have already been folded
+/// and by default puts them back together as the same variant
+pub trait Fold {
+    /// Fold any expression. By default this folds the children and then
+    /// calls the method for the expression's variant
+    fn fold_expr(&mut self, expr: Expression) -> Expression {
+        walk_fold(self, expr)
+    }
+
+    fn fold_int(&mut self, value: i128, info: ParseInfo) -> Expression {
+        Expression::Int(value, info)
+    }
+
+    fn fold_float(&mut self, value: f64, info: ParseInfo) -> Expression {
+        Expression::Float(value, info)
+    }
+
+    fn fold_str(&mut self, value: String, info: ParseInfo) -> Expression {
+        Expression::Str(value, info)
+    }
+
+    fn fold_dotted_identifier(&mut self, names: Vec<String>, info: ParseInfo) -> Expression {
+        Expression::DottedIdentifier(names, info)
+    }
+
+    fn fold_identifier(&mut self, name: String, info: ParseInfo) -> Expression {
+        Expression::Identifier(name, info)
+    }
+
+    fn fold_paren(&mut self, inner: Expression, info: ParseInfo) -> Expression {
+        Expression::Paren(Box::new(inner), info)
+    }
+
+    fn fold_address(&mut self, addr: Address, info: ParseInfo) -> Expression {
+        Expression::Address(addr, info)
+    }
+
+    fn fold_range(&mut self, range: Range, info: ParseInfo) -> Expression {
+        Expression::Range(range, info)
+    }
+
+    fn fold_function(
+        &mut self,
+        name: String,
+        type_params: Vec<Expression>,
+        params: Vec<Expression>,
+        info: ParseInfo,
+    ) -> Expression {
+        Expression::Function(name, type_params, params, info)
+    }
+
+    fn fold_infix(
+        &mut self,
+        opr: String,
+        left: Expression,
+        right: Expression,
+        info: ParseInfo,
+    ) -> Expression {
+        Expression::Infix(opr, Box::new(left), Box::new(right), info)
+    }
+
+    fn fold_prefix(&mut self, opr: String, operand: Expression, info: ParseInfo) -> Expression {
+        Expression::Prefix(opr, Box::new(operand), info)
+    }
+
+    fn fold_postfix(&mut self, opr: String, operand: Expression, info: ParseInfo) -> Expression {
+        Expression::Postfix(opr, Box::new(operand), info)
+    }
+
+    fn fold_let(
+        &mut self,
+        name: String,
+        value: Expression,
+        body: Expression,
+        info: ParseInfo,
+    ) -> Expression {
+        Expression::Let(name, Box::new(value), Box::new(body), info)
+    }
+
+    fn fold_array(&mut self, rows: Vec<Vec<Expression>>, info: ParseInfo) -> Expression {
+        Expression::Array(rows, info)
+    }
+
+    fn fold_error_value(&mut self, error: CellError, info: ParseInfo) -> Expression {
+        Expression::ErrorValue(error, info)
+    }
+
+    fn fold_missing(&mut self, what: String, info: ParseInfo) -> Expression {
+        Expression::Missing(what, info)
+    }
+
+    fn fold_error(&mut self, text: String, info: ParseInfo) -> Expression {
+        Expression::Error(text, info)
+    }
+}
+
+/// Fold the children of `expr` and then call the `Fold` method for its
+/// variant
+pub fn walk_fold<F: Fold + ?Sized>(folder: &mut F, expr: Expression) -> Expression {
+    match expr {
+        Expression::Int(i, info) => folder.fold_int(i, info),
+        Expression::Float(f, info) => folder.fold_float(f, info),
+        Expression::Str(s, info) => folder.fold_str(s, info),
+        Expression::DottedIdentifier(names, info) => folder.fold_dotted_identifier(names, info),
+        Expression::Identifier(name, info) => folder.fold_identifier(name, info),
+        Expression::Paren(inner, info) => {
+            let inner = folder.fold_expr(*inner);
+            folder.fold_paren(inner, info)
+        }
+        Expression::Address(addr, info) => folder.fold_address(addr, info),
+        Expression::Range(range, info) => folder.fold_range(range, info),
+        Expression::Function(name, type_params, params, info) => {
+            let type_params = type_params
+                .into_iter()
+                .map(|e| folder.fold_expr(e))
+                .collect();
+            let params = params.into_iter().map(|e| folder.fold_expr(e)).collect();
+            folder.fold_function(name, type_params, params, info)
+        }
+        Expression::Infix(opr, left, right, info) => {
+            let left = folder.fold_expr(*left);
+            let right = folder.fold_expr(*right);
+            folder.fold_infix(opr, left, right, info)
+        }
+        Expression::Prefix(opr, operand, info) => {
+            let operand = folder.fold_expr(*operand);
+            folder.fold_prefix(opr, operand, info)
+        }
+        Expression::Postfix(opr, operand, info) => {
+            let operand = folder.fold_expr(*operand);
+            folder.fold_postfix(opr, operand, info)
+        }
+        Expression::Let(name, value, body, info) => {
+            let value = folder.fold_expr(*value);
+            let body = folder.fold_expr(*body);
+            folder.fold_let(name, value, body, info)
+        }
+        Expression::Array(rows, info) => {
+            let rows = rows
+                .into_iter()
+                .map(|row| row.into_iter().map(|e| folder.fold_expr(e)).collect())
+                .collect();
+            folder.fold_array(rows, info)
+        }
+        Expression::ErrorValue(error, info) => folder.fold_error_value(error, info),
+        Expression::Missing(what, info) => folder.fold_missing(what, info),
+        Expression::Error(text, info) => folder.fold_error(text, info),
+    }
+}
+
+#[test]
+fn test_visitors() {
+    use crate::parser::whole_expr_str;
+    use crate::printer::print;
+
+    let e = whole_expr_str("SUM(A1, {1, x; 2, 3}) + let x = -B2; x * C3:D4 + f.g%").unwrap();
+
+    struct Names(Vec<String>);
+    impl Visitor for Names {
+        fn visit_identifier(&mut self, name: &str, _info: &ParseInfo) {
+            self.0.push(name.to_string())
+        }
+        fn visit_address(&mut self, addr: &Address, _info: &ParseInfo) {
+            self.0.push(addr.addr.clone())
+        }
+        fn visit_range(&mut self, range: &Range, _info: &ParseInfo) {
+            self.0.push(format!(
+                "{}:{}",
+                range.upper_left.addr, range.lower_right.addr
+            ))
+        }
+        fn visit_dotted_identifier(&mut self, names: &[String], _info: &ParseInfo) {
+            self.0.push(names.join("."))
+        }
+    }
+    let mut names = Names(vec![]);
+    names.visit_expr(&e);
+    assert_eq!(names.0, vec!["A1", "X", "B2", "X", "C3:D4", "F.G"]);
+
+    struct Lower;
+    impl VisitorMut for Lower {
+        fn visit_identifier_mut(&mut self, name: &mut String, _info: &mut ParseInfo) {
+            *name = name.to_lowercase()
+        }
+        fn visit_let_mut(
+            &mut self,
+            name: &mut String,
+            value: &mut Expression,
+            body: &mut Expression,
+            _info: &mut ParseInfo,
+        ) {
+            *name = name.to_lowercase();
+            self.visit_expr_mut(value);
+            self.visit_expr_mut(body)
+        }
+    }
+    let mut lowered = e.clone();
+    Lower.visit_expr_mut(&mut lowered);
+    assert_eq!(
+        print(&lowered),
+        "SUM(A1, {1, x; 2, 3}) + let x = -B2; x * C3:D4 + F.G%"
+    );
+
+    // double every number, folding 1 + 1 into 2 on the way up
+    struct Double;
+    impl Fold for Double {
+        fn fold_int(&mut self, value: i128, info: ParseInfo) -> Expression {
+            Expression::Int(value * 2, info)
+        }
+        fn fold_infix(
+            &mut self,
+            opr: String,
+            left: Expression,
+            right: Expression,
+            info: ParseInfo,
+        ) -> Expression {
+            match (opr.as_str(), &left, &right) {
+                ("+", Expression::Int(l, _), Expression::Int(r, _)) => Expression::Int(l + r, info),
+                _ => Expression::Infix(opr, Box::new(left), Box::new(right), info),
+            }
+        }
+    }
+    let folded = Double.fold_expr(whole_expr_str("{1 + 1, 3} & 4 + 5").unwrap());
+    assert_eq!(print(&folded), "{4, 6} & 18");
+    assert_eq!(Double.fold_expr(e.clone()), {
+        let mut doubled = e;
+        struct DoubleMut;
+        impl VisitorMut for DoubleMut {
+            fn visit_int_mut(&mut self, value: &mut i128, _info: &mut ParseInfo) {
+                *value *= 2
+            }
+        }
+        DoubleMut.visit_expr_mut(&mut doubled);
+        doubled
+    });
+}
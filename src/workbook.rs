@@ -32,6 +32,7 @@ pub enum CommandResponse {
 
 #[derive(Debug, Clone)]
 pub struct CommandWrapper {
+    #[allow(dead_code)] // FIXME read when commands are dispatched
     command: WorksheetCommand,
     reply_channel: Option<Sender<CommandResponse>>,
 }
@@ -107,23 +108,19 @@ fn listen_for_commands(workbook: &ArcWorkbook) {
     let book = workbook.clone();
 
     spawn(move || {
+        // only this thread ever takes the lock, so holding it across the
+        // awaits can't block anything
+        #[allow(clippy::await_holding_lock)]
         let f1 = async {
-            match book.receiver_chan.lock() {
-                Ok(mut rx) => {
-                    while let Some(msg) = rx.recv().await {
-                        // FIXME dispatch message
-                        book.command_cnt.inc();
-                        match msg.reply_channel {
-                            Some(rc) => {
-                                let _ = rc.send(CommandResponse::OkResp).await;
-                                ()
-                            }
-                            None => (),
-                        }
+            if let Ok(mut rx) = book.receiver_chan.lock() {
+                while let Some(msg) = rx.recv().await {
+                    // FIXME dispatch message
+                    book.command_cnt.inc();
+                    if let Some(rc) = msg.reply_channel {
+                        let _ = rc.send(CommandResponse::OkResp).await;
                     }
                 }
-                _ => (),
-            };
+            }
         };
 
         let runtime = tokio::runtime::Runtime::new().expect("Unable to create a runtime");
@@ -44,64 +44,448 @@ pub struct SimpleAddress {
     pub col: i32,
 }
 
-impl SimpleAddress {
-    pub fn to_string(&self) -> String {
-        format!("{}{}", self.format_column(), self.row)
+impl std::fmt::Display for SimpleAddress {
+    /// The address in A1 style. An address off the sheet is `#REF!`
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match (self.format_column(), check_row(self.row as i64)) {
+            (Ok(col), Ok(row)) => write!(f, "{}{}", col, row),
+            _ => write!(f, "#REF!"),
+        }
     }
+}
 
-    pub fn format_column(&self) -> String {
+impl SimpleAddress {
+    pub fn format_column(&self) -> Result<String, AddressError> {
         format_column(self.col)
     }
 }
 
-pub fn format_column(mut c: i32) -> String {
-    let mut buf = [0 as u8; 20];
-    if c < 0 {
-        return "A".into();
+/// A rectangle of cells. A `None` last row or column means the range
+/// runs to the end of the sheet, so whole columns and rows are cheap
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SimpleRange {
+    pub first_row: i32,
+    pub last_row: Option<i32>,
+    pub first_col: i32,
+    pub last_col: Option<i32>,
+}
+
+impl SimpleRange {
+    pub fn contains(&self, addr: &SimpleAddress) -> bool {
+        addr.row >= self.first_row
+            && addr.col >= self.first_col
+            && self.last_row.is_none_or(|r| addr.row <= r)
+            && self.last_col.is_none_or(|c| addr.col <= c)
+    }
+
+    /// The number of cells in the range or `None` if it's unbounded
+    pub fn size(&self) -> Option<u64> {
+        let rows = self.last_row? as i64 - self.first_row as i64 + 1;
+        let cols = self.last_col? as i64 - self.first_col as i64 + 1;
+        Some(rows.max(0) as u64 * cols.max(0) as u64)
+    }
+}
+
+/// The last row a `SimpleAddress` can be on. Rows count from 1
+pub const MAX_ROW: i32 = 1_048_576;
+
+/// The last column a `SimpleAddress` can be in (`XFD`). Columns count from 0
+pub const MAX_COLUMN: i32 = 16_383;
+
+/// Why a column, address or range can't be read or written in A1 style
+#[derive(Debug, PartialEq, Eq, Clone, Hash)]
+pub enum AddressError {
+    /// The text isn't in A1 style at all
+    Invalid(String),
+    /// The row is before the first row or after `MAX_ROW`
+    RowOutOfBounds(i64),
+    /// The column is before the first column or after `MAX_COLUMN`
+    ColumnOutOfBounds(i64),
+}
+
+impl std::fmt::Display for AddressError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AddressError::Invalid(text) => write!(f, "'{}' isn't an A1 address", text),
+            AddressError::RowOutOfBounds(row) => write!(
+                f,
+                "row {} is off the sheet: rows are from 1 to {}",
+                row, MAX_ROW
+            ),
+            AddressError::ColumnOutOfBounds(col) => write!(
+                f,
+                "column {} is off the sheet: columns are from 0 to {}",
+                col, MAX_COLUMN
+            ),
+        }
+    }
+}
+
+impl std::error::Error for AddressError {}
+
+const MAX_ROW_I64: i64 = MAX_ROW as i64;
+const MAX_COLUMN_I64: i64 = MAX_COLUMN as i64;
+
+fn check_row(row: i64) -> Result<i32, AddressError> {
+    match row {
+        1..=MAX_ROW_I64 => Ok(row as i32),
+        _ => Err(AddressError::RowOutOfBounds(row)),
+    }
+}
+
+fn check_column(col: i64) -> Result<i32, AddressError> {
+    match col {
+        0..=MAX_COLUMN_I64 => Ok(col as i32),
+        _ => Err(AddressError::ColumnOutOfBounds(col)),
     }
+}
 
-    let mut pos = 0;
+/// The letters for a 0-based column: `A` through `Z`, then `AA` through
+/// `ZZ`, then `AAA` and so on
+pub fn format_column(c: i32) -> Result<String, AddressError> {
+    let mut n = check_column(c as i64)? as i64 + 1;
+    let mut letters = vec![];
+    while n > 0 {
+        n -= 1;
+        letters.push(b'A' + (n % 26) as u8);
+        n /= 26;
+    }
+    letters.reverse();
+    Ok(letters.into_iter().map(|b| b as char).collect())
+}
 
-    loop {
-        let ch: u8 = (c % 26) as u8;
-        let mut to_append: u8 = 'A' as u8;
-        if pos > 0 && c < 26 {
-            to_append -= 1;
+/// The 0-based column for its letters, in either case
+pub fn parse_column(letters: &str) -> Result<i32, AddressError> {
+    if letters.is_empty() || !letters.chars().all(|c| c.is_ascii_alphabetic()) {
+        return Err(AddressError::Invalid(letters.to_string()));
+    }
+    let mut col: i64 = 0;
+    for c in letters.to_ascii_uppercase().bytes() {
+        col = col * 26 + (c - b'A') as i64 + 1;
+        if col > MAX_COLUMN as i64 + 1 {
+            return Err(AddressError::ColumnOutOfBounds(col - 1));
         }
-        to_append += ch;
-        buf[pos] = to_append;
-        if c < 26 {
-            break;
+    }
+    check_column(col - 1)
+}
+
+/// An A1 reference as written in a formula, with its `$` absolute
+/// markers. A range corner may be just a column (`$C`) or just a row
+/// (`7`), so either part may be missing but not both
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct A1Reference {
+    pub col: Option<i32>,
+    pub row: Option<i32>,
+    pub absolute_col: bool,
+    pub absolute_row: bool,
+}
+
+impl A1Reference {
+    /// An error if the reference is off the sheet or names neither a
+    /// column nor a row
+    pub fn check(&self) -> Result<(), AddressError> {
+        if let Some(col) = self.col {
+            check_column(col as i64)?;
+        }
+        match self.row {
+            Some(row) => check_row(row as i64).map(|_| ()),
+            None if self.col.is_none() => Err(AddressError::Invalid("".into())),
+            None => Ok(()),
         }
-        c = c / 26;
-        pos += 1;
     }
 
-    let mut ret = String::with_capacity(pos);
+    /// The cell this refers to if it has both a column and a row
+    pub fn cell(&self) -> Option<SimpleAddress> {
+        Some(SimpleAddress {
+            row: self.row?,
+            col: self.col?,
+        })
+    }
+}
+
+impl std::str::FromStr for A1Reference {
+    type Err = AddressError;
 
-    loop {
-        ret.push(buf[pos as usize] as char);
-        if pos == 0 {
-            break;
+    fn from_str(text: &str) -> Result<A1Reference, AddressError> {
+        let invalid = || AddressError::Invalid(text.to_string());
+        let (absolute_col, rest) = match text.strip_prefix('$') {
+            Some(rest) => (true, rest),
+            None => (false, text),
+        };
+        let split = rest
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(rest.len());
+        let (letters, rest) = rest.split_at(split);
+        let (absolute_row, digits) = match rest.strip_prefix('$') {
+            Some(digits) => (true, digits),
+            None => (false, rest),
+        };
+        if !digits.chars().all(|c| c.is_ascii_digit())
+            || (letters.is_empty() && (absolute_row || digits.is_empty()))
+            || (digits.is_empty() && absolute_row)
+        {
+            return Err(invalid());
         }
-        pos -= 1;
+
+        let col = match letters {
+            "" => None,
+            letters => Some(parse_column(letters)?),
+        };
+        let row = match digits {
+            "" => None,
+            digits => Some(check_row(digits.parse::<i64>().unwrap_or(i64::MAX))?),
+        };
+        Ok(A1Reference {
+            col,
+            row,
+            // a lone row is written `$7` with the marker first
+            absolute_col: absolute_col && col.is_some(),
+            absolute_row: absolute_row || (absolute_col && col.is_none()),
+        })
     }
+}
 
-    ret
+impl std::fmt::Display for A1Reference {
+    /// The reference in A1 style. A reference off the sheet is `#REF!`
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.check().is_err() {
+            return write!(f, "#REF!");
+        }
+        let dollar = |absolute: bool| if absolute { "$" } else { "" };
+        if let Some(col) = self.col {
+            let letters = format_column(col).unwrap_or_default();
+            write!(f, "{}{}", dollar(self.absolute_col), letters)?;
+        }
+        if let Some(row) = self.row {
+            write!(f, "{}{}", dollar(self.absolute_row), row)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for SimpleAddress {
+    type Err = AddressError;
+
+    /// A cell in A1 style (`B4`, `$AA$10`)
+    fn from_str(text: &str) -> Result<SimpleAddress, AddressError> {
+        text.parse::<A1Reference>()?
+            .cell()
+            .ok_or_else(|| AddressError::Invalid(text.to_string()))
+    }
+}
+
+impl SimpleRange {
+    /// The rectangle between two corners. A corner may be a cell, a
+    /// whole column or a whole row: `A1:B7`, `A:C`, `3:5` or `A2:A`. A
+    /// missing row or column leaves that side of the range open
+    pub fn from_corners(
+        upper_left: &A1Reference,
+        lower_right: &A1Reference,
+    ) -> Result<SimpleRange, AddressError> {
+        let invalid = || AddressError::Invalid(format!("{}:{}", upper_left, lower_right));
+        match (
+            (upper_left.col.is_some(), upper_left.row.is_some()),
+            (lower_right.col.is_some(), lower_right.row.is_some()),
+        ) {
+            ((true, true), (true, _))
+            | ((true, false), (true, false))
+            | ((false, true), (false, true)) => (),
+            _ => return Err(invalid()),
+        }
+
+        let span = |a: Option<i32>, b: Option<i32>, start: i32| match (a, b) {
+            (Some(a), Some(b)) => (a.min(b), Some(a.max(b))),
+            (Some(a), None) => (a, None),
+            _ => (start, None),
+        };
+        let (first_col, last_col) = span(upper_left.col, lower_right.col, 0);
+        let (first_row, last_row) = span(upper_left.row, lower_right.row, 1);
+
+        Ok(SimpleRange {
+            first_row,
+            last_row,
+            first_col,
+            last_col,
+        })
+    }
+}
+
+impl std::str::FromStr for SimpleRange {
+    type Err = AddressError;
+
+    /// A range in A1 style. A single cell is a range of one cell
+    fn from_str(text: &str) -> Result<SimpleRange, AddressError> {
+        match text.split_once(':') {
+            Some((ul, lr)) => SimpleRange::from_corners(&ul.parse()?, &lr.parse()?),
+            None => {
+                let cell: SimpleAddress = text.parse()?;
+                Ok(SimpleRange {
+                    first_row: cell.row,
+                    last_row: Some(cell.row),
+                    first_col: cell.col,
+                    last_col: Some(cell.col),
+                })
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for SimpleRange {
+    /// The range in A1 style. A range that's open to the right of a
+    /// bounded set of rows can't be written in A1 style, so it's
+    /// written as running to the last column. A range that's partly off
+    /// the sheet is `#REF!`
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let on_sheet = [Some(self.first_row), self.last_row]
+            .iter()
+            .flatten()
+            .all(|r| check_row(*r as i64).is_ok())
+            && [Some(self.first_col), self.last_col]
+                .iter()
+                .flatten()
+                .all(|c| check_column(*c as i64).is_ok());
+        if !on_sheet {
+            return write!(f, "#REF!");
+        }
+        let column = |c: i32| -> Result<String, std::fmt::Error> {
+            Ok(format_column(c).unwrap_or_default())
+        };
+        match (self.last_row, self.last_col) {
+            (Some(last_row), Some(last_col)) => write!(
+                f,
+                "{}{}:{}{}",
+                column(self.first_col)?,
+                self.first_row,
+                column(last_col)?,
+                last_row
+            ),
+            (None, Some(last_col)) if self.first_row == 1 => {
+                write!(f, "{}:{}", column(self.first_col)?, column(last_col)?)
+            }
+            (Some(last_row), None) if self.first_col == 0 => {
+                write!(f, "{}:{}", self.first_row, last_row)
+            }
+            (last_row, last_col) => {
+                write!(
+                    f,
+                    "{}{}:{}",
+                    column(self.first_col)?,
+                    self.first_row,
+                    column(last_col.unwrap_or(MAX_COLUMN))?
+                )?;
+                match last_row {
+                    Some(row) => write!(f, "{}", row),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
 }
 
 #[test]
 fn test_column_format() {
-    assert_eq!(format_column(0), "A".to_string());
-    assert_eq!(format_column(1), "B".to_string());
-    assert_eq!(format_column(25), "Z".to_string());
-    assert_eq!(format_column(26 * 2), "BA".to_string());
-    assert_eq!(format_column(26), "AA".to_string());
-    assert_eq!(format_column(27), "AB".to_string());
-    assert_eq!(format_column(26 * 26 + 1), "AAB".to_string());
-    assert_eq!(format_column(26 * 26 + 25), "AAZ".to_string());
-    assert_eq!(format_column(26 * 26 + (26 * 2) + 25), "ACZ".to_string());
-    assert_eq!(format_column(i32::MAX), "FYTISYX".to_string());
+    assert_eq!(format_column(0), Ok("A".to_string()));
+    assert_eq!(format_column(1), Ok("B".to_string()));
+    assert_eq!(format_column(25), Ok("Z".to_string()));
+    assert_eq!(format_column(26 * 2), Ok("BA".to_string()));
+    assert_eq!(format_column(26), Ok("AA".to_string()));
+    assert_eq!(format_column(27), Ok("AB".to_string()));
+    assert_eq!(format_column(26 * 26 + 25), Ok("ZZ".to_string()));
+    assert_eq!(format_column(26 * 26 + 26), Ok("AAA".to_string()));
+    assert_eq!(format_column(26 * 26 + 27), Ok("AAB".to_string()));
+    assert_eq!(format_column(16_383), Ok("XFD".to_string()));
+    assert_eq!(
+        format_column(16_384),
+        Err(AddressError::ColumnOutOfBounds(16_384))
+    );
+    assert_eq!(format_column(-1), Err(AddressError::ColumnOutOfBounds(-1)));
+
+    for c in [0, 25, 26, 701, 702, 16_383] {
+        assert_eq!(parse_column(&format_column(c).unwrap()), Ok(c));
+    }
+    assert_eq!(parse_column("xfd"), Ok(16_383));
+    assert_eq!(
+        parse_column("XFE"),
+        Err(AddressError::ColumnOutOfBounds(16_384))
+    );
+    assert_eq!(
+        parse_column("A1"),
+        Err(AddressError::Invalid("A1".to_string()))
+    );
+}
+
+#[test]
+fn test_address_codec() {
+    let cell = |text: &str| text.parse::<SimpleAddress>();
+    let range = |text: &str| text.parse::<SimpleRange>();
+
+    assert_eq!(cell("B4"), Ok(SimpleAddress { row: 4, col: 1 }));
+    assert_eq!(cell("$aa$10"), Ok(SimpleAddress { row: 10, col: 26 }));
+    assert_eq!(cell("C0"), Err(AddressError::RowOutOfBounds(0)));
+    assert_eq!(
+        cell("XFD1048576"),
+        Ok(SimpleAddress {
+            row: 1_048_576,
+            col: 16_383
+        })
+    );
+    assert_eq!(
+        cell("C1048577"),
+        Err(AddressError::RowOutOfBounds(1_048_577))
+    );
+    assert_eq!(
+        cell("C99999999999999999999"),
+        Err(AddressError::RowOutOfBounds(i64::MAX))
+    );
+    for bad in ["", "$", "C", "7", "4B", "B$", "B4$", "B-4", "B 4", "$$B4"] {
+        assert_eq!(cell(bad), Err(AddressError::Invalid(bad.to_string())));
+    }
+    assert_eq!(
+        cell("C0").unwrap_err().to_string(),
+        "row 0 is off the sheet: rows are from 1 to 1048576"
+    );
+
+    let r: A1Reference = "B$4".parse().unwrap();
+    assert_eq!((r.absolute_col, r.absolute_row), (false, true));
+    for text in ["B4", "$B4", "B$4", "$B$4", "$C", "D", "7", "$7"] {
+        assert_eq!(text.parse::<A1Reference>().unwrap().to_string(), text);
+    }
+
+    assert_eq!(
+        range("B7:A1"),
+        Ok(SimpleRange {
+            first_row: 1,
+            last_row: Some(7),
+            first_col: 0,
+            last_col: Some(1),
+        })
+    );
+    assert_eq!(
+        range("A2:A").map(|r| (r.first_row, r.last_row)),
+        Ok((2, None))
+    );
+    assert_eq!(range("B4").map(|r| r.size()), Ok(Some(1)));
+    assert_eq!(range("A:1"), Err(AddressError::Invalid("A:1".to_string())));
+    assert_eq!(
+        range("3:A3"),
+        Err(AddressError::Invalid("3:A3".to_string()))
+    );
+    for text in ["A1:B7", "A:C", "$A:$C", "3:5", "A2:A", "B2:XFD9"] {
+        let bounds = range(text).unwrap();
+        assert_eq!(range(&bounds.to_string()), Ok(bounds));
+    }
+    assert_eq!(range("A2:C").unwrap().to_string(), "A2:C");
+    assert_eq!(SimpleAddress { row: 0, col: 0 }.to_string(), "#REF!");
+    assert_eq!(
+        A1Reference {
+            col: Some(-1),
+            row: None,
+            absolute_col: false,
+            absolute_row: false
+        }
+        .to_string(),
+        "#REF!"
+    );
 }
 
 impl Worksheet for SimpleWorksheet {
@@ -113,10 +497,7 @@ impl Worksheet for SimpleWorksheet {
     }
 
     fn get_cell_value(&self, addr: &Self::Address) -> Option<Arc<Self::Value>> {
-        match self.cells.load().get(addr) {
-            Some(v) => Some(v.clone()),
-            None => None,
-        }
+        self.cells.load().get(addr).cloned()
     }
 
     fn clear_cell(&self, addr: &Self::Address) -> Option<Arc<Self::Value>> {
@@ -130,31 +511,27 @@ impl Worksheet for SimpleWorksheet {
         // FIXME -- this is not doing the generational history thing the right way... sigh
         self.cells.rcu(|t| {
             last_gen = Some(t.clone());
-            t.update(addr.clone(), value.clone())
+            t.update(*addr, value.clone())
         });
 
-        match last_gen {
-            Some(old_cells) => {
-                self.history.rcu(|h| {
-                    // deref from the Arc
-                    let q: &Vector<Arc<CellHolder>> = h;
+        if let Some(old_cells) = last_gen {
+            self.history.rcu(|h| {
+                // deref from the Arc
+                let q: &Vector<Arc<CellHolder>> = h;
 
-                    // an O(1) clone of the vector
-                    let mut h2 = q.clone();
+                // an O(1) clone of the vector
+                let mut h2 = q.clone();
 
-                    // do operations
-                    h2.push_back(old_cells.clone());
-                    // only keep the last 100 changes FIXME -- make variable?
-                    if h2.len() > 100 {
-                        h2.pop_front();
-                    }
+                // do operations
+                h2.push_back(old_cells.clone());
+                // only keep the last 100 changes FIXME -- make variable?
+                if h2.len() > 100 {
+                    h2.pop_front();
+                }
 
-                    // return the new Arc of the vector
-                    Arc::new(h2)
-                });
-                ()
-            }
-            None => (),
+                // return the new Arc of the vector
+                Arc::new(h2)
+            });
         }
     }
 }
@@ -166,4 +543,84 @@ impl SimpleWorksheet {
             history: ArcSwap::new(Arc::new(Vector::new())),
         })
     }
+
+    /// The cells in `range` that have a value, in row then column order.
+    /// Only populated cells are visited: a small range is looked up cell
+    /// by cell and anything bigger than the sheet's contents is found by
+    /// walking the populated cells
+    pub fn populated_cells(&self, range: &SimpleRange) -> Vec<(SimpleAddress, Arc<DValue>)> {
+        let cells = self.cells.load();
+        let mut ret: Vec<(SimpleAddress, Arc<DValue>)> = match range.size() {
+            Some(size) if size <= cells.len() as u64 => {
+                let mut found = vec![];
+                for row in range.first_row..=range.last_row.unwrap_or(range.first_row) {
+                    for col in range.first_col..=range.last_col.unwrap_or(range.first_col) {
+                        let addr = SimpleAddress { row, col };
+                        if let Some(v) = cells.get(&addr) {
+                            found.push((addr, v.clone()));
+                        }
+                    }
+                }
+                found
+            }
+            _ => cells
+                .iter()
+                .filter(|(addr, _)| range.contains(addr))
+                .map(|(addr, v)| (*addr, v.clone()))
+                .collect(),
+        };
+        ret.sort_by_key(|(addr, _)| (addr.row, addr.col));
+        ret
+    }
+}
+
+#[test]
+fn test_populated_cells() {
+    let sheet = SimpleWorksheet::new();
+    for (row, col) in [(1, 0), (3, 0), (1_000_000, 0), (2, 1), (5, 3)] {
+        sheet.set_cell(
+            &SimpleAddress { row, col },
+            &Arc::new(DValue::Int(row as i128)),
+        );
+    }
+    let rows = |range: SimpleRange| -> Vec<i32> {
+        sheet
+            .populated_cells(&range)
+            .iter()
+            .map(|(a, _)| a.row)
+            .collect()
+    };
+
+    let whole_a = SimpleRange {
+        first_row: 1,
+        last_row: None,
+        first_col: 0,
+        last_col: Some(0),
+    };
+    assert_eq!(rows(whole_a), vec![1, 3, 1_000_000]);
+    assert_eq!(
+        rows(SimpleRange {
+            first_row: 2,
+            ..whole_a
+        }),
+        vec![3, 1_000_000]
+    );
+    assert_eq!(
+        rows(SimpleRange {
+            first_row: 2,
+            last_row: Some(5),
+            first_col: 0,
+            last_col: None,
+        }),
+        vec![2, 3, 5]
+    );
+    assert_eq!(
+        rows(SimpleRange {
+            first_row: 1,
+            last_row: Some(2),
+            first_col: 0,
+            last_col: Some(1),
+        }),
+        vec![1, 2]
+    );
 }
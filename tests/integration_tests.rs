@@ -1,5 +1,10 @@
+use mesax::definitions::Value;
+use mesax::eval::{eval_in, EvalContext};
+use mesax::eval_stack::create_eval_stack;
 use mesax::parser::*;
 use mesax::parser_util::*;
+use mesax::printer::{print, print_with, PrintOptions};
+use std::collections::HashMap;
 
 #[test]
 fn test_parsing() {
@@ -41,11 +46,58 @@ fn test_parsing() {
             )),
         ),
         (r#"  "Hello World""#, Ok(ex_str("Hello World"))),
+        (r#""say ""hi""""#, Ok(ex_str(r#"say "hi""#))),
+        (r#""""""#, Ok(ex_str(r#"""#))),
+        (r#""a\"b\\c""#, Ok(ex_str(r#"a"b\c"#))),
+        (r#""tab\there\nnew\r\0""#, Ok(ex_str("tab\there\nnew\r\0"))),
+        (r#""\u{48}\u{e9}\u{1F600}""#, Ok(ex_str("Hé😀"))),
+        (r#""snow ☃ man""#, Ok(ex_str("snow ☃ man"))),
+        (r#""\q""#, Err(0)),
+        (r#""\u{110000}""#, Err(0)),
+        (r#""\u{}""#, Err(0)),
+        (r#".5"#, Ok(ex_f(0.5))),
+        (r#"5."#, Ok(ex_f(5.0))),
+        (r#"-.25"#, Ok(ex_f(-0.25))),
+        (r#"1e-9"#, Ok(ex_f(1e-9))),
+        (r#"6.02E23"#, Ok(ex_f(6.02e23))),
+        (r#"1E+3"#, Ok(ex_f(1000.0))),
+        (
+            r#"0.1 + 5. * .5e1"#,
+            Ok(ex_inf("+", ex_f(0.1), ex_inf("*", ex_f(5.0), ex_f(5.0)))),
+        ),
+        (r#"1_000.000_5"#, Ok(ex_f(1000.0005))),
+        (
+            r#"2.2250738585072014e-308"#,
+            Ok(ex_f(2.2250738585072014e-308)),
+        ),
+        (r#"0xFF"#, Ok(ex_i(255))),
+        (r#"-0x1_0"#, Ok(ex_i(-16))),
+        (r#"1_000_000"#, Ok(ex_i(1_000_000))),
+        (
+            r#"-170141183460469231731687303715884105728"#,
+            Ok(ex_i(i128::MIN)),
+        ),
+        (r#"1__0"#, Err(0)),
+        (r#"1_"#, Err(0)),
+        (r#"0xG"#, Err(0)),
+        (r#"1e"#, Err(0)),
+        (r#"größe"#, Ok(ex_id("GRÖSSE"))),
+        (
+            r#"prix_unitaire2 * 数量"#,
+            Ok(ex_inf("*", ex_id("PRIX_UNITAIRE2"), ex_id("数量"))),
+        ),
+        (r#"Σ(x)"#, Ok(ex_fun("Σ", vec![], vec![ex_id("X")]))),
+        (
+            r#"'Ventes été'!A1"#,
+            Ok(ex_on("Ventes été", None, ex_adr("a1"))),
+        ),
+        (r#"Données!A1"#, Ok(ex_on("Données", None, ex_adr("a1")))),
         (r#"-32"#, Ok(ex_i(-32))),
         (r#"+32"#, Ok(ex_i(32))),
         (r#"32.99"#, Ok(ex_f(32.99))),
         (r#"-32.822"#, Ok(ex_f(-32.822))),
-        (r#"A1"#, Ok(ex_id("a1"))),
+        (r#"A1"#, Ok(ex_adr("a1"))),
+        (r#"A1B"#, Ok(ex_id("a1b"))),
         (r#"$A3"#, Ok(ex_adr("$A3"))),
         (r#"$A3:b77"#, Ok(ex_rng("$a3", "b77"))),
         (r#"$ABE3328282"#, Ok(ex_adr("$ABE3328282"))),
@@ -54,6 +106,86 @@ fn test_parsing() {
             Ok(ex_fun("sum", vec![], vec![ex_rng("a1", "$b7")])),
         ),
         (r#"(a1:$B7)"#, Ok(ex_paren(ex_rng("a1", "$B7")))),
+        (
+            r#"{1, 2, 3; 4, 5, 6}"#,
+            Ok(ex_arr(vec![
+                vec![ex_i(1), ex_i(2), ex_i(3)],
+                vec![ex_i(4), ex_i(5), ex_i(6)],
+            ])),
+        ),
+        (
+            r#"SUM({1,2,3} * A1:A3)"#,
+            Ok(ex_fun(
+                "sum",
+                vec![],
+                vec![ex_inf(
+                    "*",
+                    ex_arr(vec![vec![ex_i(1), ex_i(2), ex_i(3)]]),
+                    ex_rng("a1", "a3"),
+                )],
+            )),
+        ),
+        (
+            r#"INDEX({"a","b"}, 2)"#,
+            Ok(ex_fun(
+                "index",
+                vec![],
+                vec![ex_arr(vec![vec![ex_str("a"), ex_str("b")]]), ex_i(2)],
+            )),
+        ),
+        (
+            r#"let x = {1; -2}; x"#,
+            Ok(ex_let(
+                "x",
+                ex_arr(vec![vec![ex_i(1)], vec![ex_i(-2)]]),
+                ex_id("x"),
+            )),
+        ),
+        (r#"{}"#, Err(0)),
+        (r#"{1,}"#, Err(0)),
+        (r#"A:A"#, Ok(ex_rng("a", "a"))),
+        (r#"b:$D"#, Ok(ex_rng("b", "$d"))),
+        (r#"3:3"#, Ok(ex_rng("3", "3"))),
+        (r#"$5:10"#, Ok(ex_rng("$5", "10"))),
+        (r#"A2:A"#, Ok(ex_rng("a2", "a"))),
+        (
+            r#"sum(Data!C:C)"#,
+            Ok(ex_fun(
+                "sum",
+                vec![],
+                vec![ex_on("Data", None, ex_rng("c", "c"))],
+            )),
+        ),
+        (r#"A:1"#, Err(0)),
+        (r#"3:A3"#, Err(0)),
+        (r#"Sheet2!B4"#, Ok(ex_on("Sheet2", None, ex_adr("b4")))),
+        (
+            r#"'Q3 Sales'!$a$1:c9"#,
+            Ok(ex_on("Q3 Sales", None, ex_rng("$a$1", "c9"))),
+        ),
+        (r#"'Bob''s'!A1"#, Ok(ex_on("Bob's", None, ex_adr("a1")))),
+        (
+            r#"Jan:Dec!B2 + 1"#,
+            Ok(ex_inf(
+                "+",
+                ex_on("Jan", Some("Dec"), ex_adr("b2")),
+                ex_i(1),
+            )),
+        ),
+        (
+            r#"sum(Data!A1:A9)"#,
+            Ok(ex_fun(
+                "sum",
+                vec![],
+                vec![ex_on("Data", None, ex_rng("a1", "a9"))],
+            )),
+        ),
+        // R1C1 addresses are only addresses in R1C1 style
+        (r#"R1C1"#, Ok(ex_id("R1C1"))),
+        (r#"RC1"#, Ok(ex_adr("RC1"))),
+        (r#"RCX"#, Ok(ex_id("RCX"))),
+        (r#"let rc = 1; rc"#, Ok(ex_let("rc", ex_i(1), ex_id("rc")))),
+        (r#"R[1"#, Err(0)),
         (r#"( 44 )"#, Ok(ex_paren(ex_i(44)))),
         (r#"( -73.4)"#, Ok(ex_paren(ex_f(-73.4)))),
         (
@@ -155,7 +287,7 @@ fn test_parsing() {
                 "if",
                 vec![],
                 vec![
-                    ex_id("a1"),
+                    ex_adr("a1"),
                     ex_fun("sum", vec![], vec![ex_rng("a1", "$b$7")]),
                     ex_inf("+", ex_i(3), ex_i(39)),
                 ],
@@ -176,12 +308,8 @@ fn test_parsing() {
             "Woof" "#,
             Ok(ex_inf(
                 "&&",
-                ex_id("false"),
-                ex_inf(
-                    "&&",
-                    ex_id("true"),
-                    ex_inf("==", ex_dot(vec!["cat", "FOOD"]), ex_str("Woof")),
-                ),
+                ex_inf("&&", ex_id("false"), ex_id("true")),
+                ex_inf("==", ex_dot(vec!["cat", "FOOD"]), ex_str("Woof")),
             )),
         ),
         (
@@ -192,22 +320,1019 @@ fn test_parsing() {
                 ex_inf("==", ex_dot(vec!["cat", "food"]), ex_str("hello")),
             )),
         ),
+        (
+            r#"10 - 3 - 2"#,
+            Ok(ex_inf("-", ex_inf("-", ex_i(10), ex_i(3)), ex_i(2))),
+        ),
+        (
+            r#"8 / 4 / 2"#,
+            Ok(ex_inf("/", ex_inf("/", ex_i(8), ex_i(4)), ex_i(2))),
+        ),
+        (
+            r#"1 - 2 * 3 + 4"#,
+            Ok(ex_inf(
+                "+",
+                ex_inf("-", ex_i(1), ex_inf("*", ex_i(2), ex_i(3))),
+                ex_i(4),
+            )),
+        ),
+        (
+            r#"2 * 3 ^ 2 / 4"#,
+            Ok(ex_inf(
+                "/",
+                ex_inf("*", ex_i(2), ex_inf("^", ex_i(3), ex_i(2))),
+                ex_i(4),
+            )),
+        ),
+        (
+            r#"a > 1 && b <= 2 || c"#,
+            Ok(ex_inf(
+                "||",
+                ex_inf(
+                    "&&",
+                    ex_inf(">", ex_id("a"), ex_i(1)),
+                    ex_inf("<=", ex_id("b"), ex_i(2)),
+                ),
+                ex_id("c"),
+            )),
+        ),
+        (r#"3 - -2"#, Ok(ex_inf("-", ex_i(3), ex_i(-2)))),
+        (
+            r#"a <> b || c != d"#,
+            Ok(ex_inf(
+                "||",
+                ex_inf("!=", ex_id("a"), ex_id("b")),
+                ex_inf("!=", ex_id("c"), ex_id("d")),
+            )),
+        ),
+        (
+            r#""a" & 1 + 2 >= 3 % 2 ^ 2"#,
+            Ok(ex_inf(
+                ">=",
+                ex_inf("&", ex_str("a"), ex_inf("+", ex_i(1), ex_i(2))),
+                ex_inf("%", ex_i(3), ex_inf("^", ex_i(2), ex_i(2))),
+            )),
+        ),
+        (r#"-A1"#, Ok(ex_pre("-", ex_adr("a1")))),
+        (
+            r#"-(B2+3)"#,
+            Ok(ex_pre("-", ex_paren(ex_inf("+", ex_adr("b2"), ex_i(3))))),
+        ),
+        (
+            r#"-SUM(A1:A3)"#,
+            Ok(ex_pre("-", ex_fun("sum", vec![], vec![ex_rng("a1", "a3")]))),
+        ),
+        (r#"!flag"#, Ok(ex_pre("!", ex_id("flag")))),
+        (r#"- -x"#, Ok(ex_pre("-", ex_pre("-", ex_id("x"))))),
+        (r#"50%"#, Ok(ex_post("%", ex_i(50)))),
+        (
+            r#"-A1^2"#,
+            Ok(ex_inf("^", ex_pre("-", ex_adr("a1")), ex_i(2))),
+        ),
+        (
+            r#"2 * -x"#,
+            Ok(ex_inf("*", ex_i(2), ex_pre("-", ex_id("x")))),
+        ),
+        (
+            r#"A1%*2"#,
+            Ok(ex_inf("*", ex_post("%", ex_adr("a1")), ex_i(2))),
+        ),
+        (r#"7 % 3"#, Ok(ex_inf("%", ex_i(7), ex_i(3)))),
+        (r#"7%x"#, Ok(ex_inf("%", ex_i(7), ex_id("x")))),
+        (r#"7%-3"#, Ok(ex_inf("-", ex_post("%", ex_i(7)), ex_i(3)))),
+        (r#"7 % -3"#, Ok(ex_inf("-", ex_post("%", ex_i(7)), ex_i(3)))),
+        (
+            r#"50%+10"#,
+            Ok(ex_inf("+", ex_post("%", ex_i(50)), ex_i(10))),
+        ),
+        (
+            r#"A1% - B1"#,
+            Ok(ex_inf("-", ex_post("%", ex_adr("A1")), ex_adr("B1"))),
+        ),
+        (r#"7 % (-3)"#, Ok(ex_inf("%", ex_i(7), ex_paren(ex_i(-3))))),
+        (
+            r#"7% - x"#,
+            Ok(ex_inf("-", ex_post("%", ex_i(7)), ex_id("x"))),
+        ),
+        (
+            r#"(7%) - 3"#,
+            Ok(ex_inf("-", ex_paren(ex_post("%", ex_i(7))), ex_i(3))),
+        ),
+        (r#"7%!=3"#, Ok(ex_inf("!=", ex_post("%", ex_i(7)), ex_i(3)))),
+        (
+            r#"#REF! + 1"#,
+            Ok(ex_inf("+", ex_err(CellError::Ref), ex_i(1))),
+        ),
+        (
+            r#"IF(x, #div/0!, #N/A)"#,
+            Ok(ex_fun(
+                "IF",
+                vec![],
+                vec![
+                    ex_id("x"),
+                    ex_err(CellError::DivZero),
+                    ex_err(CellError::NA),
+                ],
+            )),
+        ),
+        (
+            r#"7%#NUM!"#,
+            Ok(ex_inf("%", ex_i(7), ex_err(CellError::Num))),
+        ),
+        (r#"#WHAT!"#, Err(1)),
+        (r#"-"#, Err(1)),
+        (r#"1 +"#, Err(1)),
         (r#"$5221343%%%"#, Err(44)),
     ];
 
     for item in test_exprs {
         match (whole_expr_str(item.0), item.1) {
-            (Ok(x), Ok(y)) if x == y => {
-                assert!(true)
-            }
-            (Ok(x), Ok(y)) => assert!(
-                false,
+            (Ok(x), Ok(y)) if x == y => (),
+            (Ok(x), Ok(y)) => panic!(
                 "For '{}'. Did not successfully compare:\n{:?}\nand\n{:?}\n",
                 item.0, x, y
             ),
-            (Ok(x), Err(_)) => assert!(false, "Expecting error, but got {:?}\n", x),
-            (Err(_), Err(_)) => assert!(true),
-            (Err(x), _) => assert!(false, "Trying '{}', got Error {:#?}\n", item.0, x),
+            (Ok(x), Err(_)) => panic!("Expecting error, but got {:?}\n", x),
+            (Err(_), Err(_)) => (),
+            (Err(x), _) => panic!("Trying '{}', got Error {:#?}\n", item.0, x),
+        }
+    }
+}
+
+#[test]
+fn test_r1c1_parsing() {
+    let tests = vec![
+        (r#"R1C1"#, ex_r1c1(ex_adr("R1C1"))),
+        (r#"r[-1]c[2]"#, ex_r1c1(ex_adr("R[-1]C[2]"))),
+        (
+            r#"RC[-3] + R[+2]C[0]"#,
+            ex_inf("+", ex_r1c1(ex_adr("RC[-3]")), ex_r1c1(ex_adr("R[2]C"))),
+        ),
+        (r#"RC1"#, ex_r1c1(ex_adr("RC1"))),
+        (r#"RCX"#, ex_id("RCX")),
+        (
+            r#"Data!R1C1:R[3]C + A:B"#,
+            ex_inf(
+                "+",
+                ex_on("Data", None, ex_r1c1(ex_rng("R1C1", "R[3]C"))),
+                ex_rng("A", "B"),
+            ),
+        ),
+    ];
+
+    for (formula, expected) in tests {
+        assert_eq!(
+            whole_r1c1_expr_str(formula),
+            Ok(expected),
+            "Parsing {}",
+            formula
+        );
+    }
+    assert!(whole_r1c1_expr_str("R[1").is_err());
+}
+
+#[test]
+fn test_print_round_trip() {
+    let formulas = vec![
+        r#"=  147 /* comment */"#,
+        r#"if(32, "yes", "no")"#,
+        r#"IF(a1, sum(a1:$b$7), "no")"#,
+        r#"1 + 2 * 3 - 4 / 5 % 6 ^ 7 & "x" == 1 && 2 != 3 || 4 <> 5 < 6"#,
+        r#"let x = 5; let y = {1, 2; 3, 4}; x * y"#,
+        r#"AVE[MEAN, x.y](D4:D9, -A1%, !B2, - 3)"#,
+        r#"'Q3 Sales'!$a$1:c9 + Jan:Dec!B2 + Data!C:C + 3:3"#,
+        r#""tab\there ""quoted"" \u{1F600}" & größe"#,
+        r#"6.02E23 + .5 + 0x1F + 1_000 - 1e-300"#,
+        "SUM(A1:A9 /* the inputs */, //# and\n B2) * /* two */ 2",
+        r#"SUM(#REF!) + #name? - {#N/A, 1}"#,
+    ];
+
+    for formula in formulas {
+        let parsed = whole_expr_str(formula).unwrap();
+        for printed in [
+            print(&parsed),
+            print_with(&parsed, &PrintOptions::single_line()),
+            print_with(
+                &parsed,
+                &PrintOptions {
+                    max_width: 8,
+                    indent: 3,
+                    ..PrintOptions::default()
+                },
+            ),
+        ] {
+            assert_eq!(
+                whole_expr_str(&printed),
+                Ok(parsed.clone()),
+                "For '{}' printed as '{}'",
+                formula,
+                printed
+            );
+        }
+    }
+}
+
+#[test]
+fn test_parse_diagnostics() {
+    let tests = vec![
+        ("1 2", vec![(1, 3, "expected an operator but found '2'")]),
+        (
+            "1 +",
+            vec![(1, 4, "expected an expression but found end of formula")],
+        ),
+        (
+            "SUM(1,,2)",
+            vec![(1, 7, "expected an expression but found ','")],
+        ),
+        (
+            "SUM(1,,2",
+            vec![
+                (1, 4, "unclosed parenthesis opened at 1:4"),
+                (1, 7, "expected an expression but found ','"),
+            ],
+        ),
+        (
+            "=\n  1 +\n  (2 * ",
+            vec![(3, 3, "unclosed parenthesis opened at 3:3")],
+        ),
+        (
+            "foo(1, 2]",
+            vec![(
+                1,
+                9,
+                "expected ')' to close the parenthesis opened at 1:4 but found ']'",
+            )],
+        ),
+        (
+            r#"1 & "abc"#,
+            vec![(1, 5, "unterminated string started at 1:5")],
+        ),
+        (
+            "let x = 3 x + 1",
+            vec![(1, 11, "expected an operator or ';' but found 'x'")],
+        ),
+        ("let x 3; x", vec![(1, 7, "expected '=' but found '3'")]),
+        (
+            "1 + 170141183460469231731687303715884105728",
+            vec![(
+                1,
+                5,
+                "the integer 170141183460469231731687303715884105728 is too large: integers must be between -170141183460469231731687303715884105728 and 170141183460469231731687303715884105727",
+            )],
+        ),
+        (
+            "0x1_0000_0000_0000_0000_0000_0000_0000_0000 * 2",
+            vec![(
+                1,
+                1,
+                "the integer 0x1_0000_0000_0000_0000_0000_0000_0000_0000 is too large: integers must be between -170141183460469231731687303715884105728 and 170141183460469231731687303715884105727",
+            )],
+        ),
+        ("2 * 1e400", vec![(1, 5, "the number 1e400 is too large")]),
+        (
+            "{1, 2; 3}",
+            vec![(
+                1,
+                1,
+                "every row of an array must have the same number of items",
+            )],
+        ),
+        (
+            r#""a\qb""#,
+            vec![(1, 4, "expected an escape sequence but found 'qb'")],
+        ),
+        (
+            "cat.",
+            vec![(1, 5, "expected a name but found end of formula")],
+        ),
+    ];
+
+    for (formula, expected) in tests {
+        match whole_expr_str(formula) {
+            Ok(x) => panic!("Expecting errors for '{}', but got {:?}", formula, x),
+            Err(diagnostics) => assert_eq!(
+                diagnostics
+                    .iter()
+                    .map(|d| (d.line, d.column, d.message.as_str()))
+                    .collect::<Vec<_>>(),
+                expected,
+                "Diagnostics for '{}'",
+                formula
+            ),
         }
     }
+
+    let diagnostics = whole_expr_str("IF(a1 > , 2, 3)").unwrap_err();
+    assert_eq!(diagnostics[0].offset, 8);
+    assert_eq!(diagnostics[0].expected, vec!["an expression".to_string()]);
+    assert_eq!(diagnostics[0].found, "','");
+}
+
+#[test]
+fn test_partial_parse() {
+    let tests = vec![
+        (
+            "=SUM(A1:A3, IF(B2 >",
+            ex_fun(
+                "sum",
+                vec![],
+                vec![
+                    ex_rng("a1", "a3"),
+                    ex_fun(
+                        "if",
+                        vec![],
+                        vec![ex_inf(">", ex_adr("b2"), ex_missing("an expression"))],
+                    ),
+                ],
+            ),
+            vec![
+                "1:5: unclosed parenthesis opened at 1:5",
+                "1:15: unclosed parenthesis opened at 1:15",
+                "1:20: expected an expression but found end of formula",
+            ],
+        ),
+        (
+            "1 + * 2",
+            ex_inf(
+                "+",
+                ex_i(1),
+                ex_inf("*", ex_missing("an expression"), ex_i(2)),
+            ),
+            vec!["1:5: expected an expression but found '*'"],
+        ),
+        (
+            "sum(1e999,",
+            ex_fun(
+                "sum",
+                vec![],
+                vec![
+                    Expression::Error("1e999".into(), None),
+                    ex_missing("an expression"),
+                ],
+            ),
+            vec![
+                "1:4: unclosed parenthesis opened at 1:4",
+                "1:5: the number 1e999 is too large",
+                "1:11: expected an expression but found end of formula",
+            ],
+        ),
+        (
+            "sum(1,",
+            ex_fun("sum", vec![], vec![ex_i(1), ex_missing("an expression")]),
+            vec![
+                "1:4: unclosed parenthesis opened at 1:4",
+                "1:7: expected an expression but found end of formula",
+            ],
+        ),
+        (
+            "let x = 1;",
+            ex_let("x", ex_i(1), ex_missing("an expression")),
+            vec!["1:11: expected an expression but found end of formula"],
+        ),
+        (
+            r#"-("abc"#,
+            ex_pre("-", ex_paren(ex_str("abc"))),
+            vec![
+                "1:2: unclosed parenthesis opened at 1:2",
+                "1:3: unterminated string started at 1:3",
+            ],
+        ),
+        (
+            "select[distinct",
+            ex_fun("select", vec![ex_id("distinct")], vec![]),
+            vec!["1:7: unclosed bracket opened at 1:7"],
+        ),
+        (
+            "1 2",
+            ex_i(1),
+            vec!["1:3: expected an operator but found '2'"],
+        ),
+        ("group_by()", ex_fun("group_by", vec![], vec![]), vec![]),
+        (
+            "#REF! + 1",
+            ex_inf("+", ex_err(CellError::Ref), ex_i(1)),
+            vec![],
+        ),
+    ];
+
+    for (formula, expected, diagnostics) in tests {
+        let parsed = partial_expr_str(formula);
+        assert_eq!(parsed.expr, expected, "Parsing '{}'", formula);
+        assert_eq!(
+            parsed
+                .diagnostics
+                .iter()
+                .map(|d| d.to_string())
+                .collect::<Vec<_>>(),
+            diagnostics,
+            "Diagnostics for '{}'",
+            formula
+        );
+    }
+
+    match partial_expr_str("1 + #").expr {
+        Expression::Infix(_, _, right, _) => match *right {
+            Expression::Error(text, Some(info)) => {
+                assert_eq!(text, "#");
+                assert_eq!((info.start, info.end), (4, 5));
+            }
+            x => panic!("Expected an Error, got {:?}", x),
+        },
+        x => panic!("Expected an Infix, got {:?}", x),
+    }
+}
+
+/// Sheet1 has 1, 2 and 3 in A1:A3 and 10 in B1, and Data has 100 in A1
+fn workbook() -> EvalContext {
+    EvalContext::with_sheets(&[
+        (
+            "Sheet1",
+            &[
+                ("A1", Value::Int(1)),
+                ("A2", Value::Int(2)),
+                ("A3", Value::Int(3)),
+                ("B1", Value::Int(10)),
+            ],
+        ),
+        ("Data", &[("A1", Value::Int(100))]),
+    ])
+    .unwrap()
+}
+
+/// Build and evaluate a formula in `context`
+fn evaluate(expr: &Expression, context: &EvalContext) -> Value {
+    let stack = create_eval_stack(expr, &HashMap::new()).unwrap();
+    eval_in(&stack, context).unwrap()
+}
+
+#[test]
+fn test_rewrite_and_evaluate() {
+    use mesax::definitions::CellError;
+    use mesax::rewrite::{copy_formula, fill_formula, move_references, RewriteError};
+    use mesax::serial::{from_json, to_json};
+
+    let context = workbook();
+    let running = whole_expr_str("SUM($A$1:A1) * 2 /* running */").unwrap();
+    let filled = fill_formula(&running, &"C1".parse().unwrap(), &"C1:C3".parse().unwrap()).unwrap();
+    let results: Vec<(String, String, Value)> = filled
+        .iter()
+        .map(|(at, e)| {
+            (
+                at.to_string(),
+                print_with(e, &PrintOptions::as_written()),
+                evaluate(e, &context),
+            )
+        })
+        .collect();
+    assert_eq!(
+        results,
+        vec![
+            (
+                "C1".into(),
+                "SUM($A$1:A1) * 2 /* running */".into(),
+                Value::Int(2)
+            ),
+            (
+                "C2".into(),
+                "SUM($A$1:A2) * 2 /* running */".into(),
+                Value::Int(6)
+            ),
+            (
+                "C3".into(),
+                "SUM($A$1:A3) * 2 /* running */".into(),
+                Value::Int(12)
+            ),
+        ]
+    );
+    let (_, last) = &filled[2];
+    assert_eq!(
+        &from_json::<Expression>(&to_json(last).unwrap()).unwrap(),
+        last
+    );
+
+    // off the top of the sheet
+    let off = copy_formula(
+        &whole_expr_str("Data!A1 + A2").unwrap(),
+        &"C2".parse().unwrap(),
+        &"C1".parse().unwrap(),
+    );
+    assert_eq!(print(&off), "#REF! + A1");
+    assert_eq!(evaluate(&off, &context), CellError::Ref.into());
+
+    // A1:A3 moved to D1:D3
+    let moved = move_references(
+        &whole_expr_str("SUM(A1:A3) + B1").unwrap(),
+        "Sheet1",
+        "Sheet1",
+        &"A1:A3".parse().unwrap(),
+        &"D1".parse().unwrap(),
+    );
+    assert_eq!(print(&moved), "SUM(D1:D3) + B1");
+    assert_eq!(evaluate(&moved, &context), Value::Int(10));
+
+    let whole_column = fill_formula(&running, &"C1".parse().unwrap(), &"C:C".parse().unwrap());
+    assert_eq!(
+        whole_column,
+        Err(RewriteError::OpenEnded("C:C".parse().unwrap()))
+    );
+}
+
+#[test]
+fn test_fold_and_evaluate() {
+    use mesax::definitions::CellError;
+    use mesax::visit::{Fold, Visitor};
+
+    /// Replace named constants with their values
+    struct Constants(Vec<(&'static str, f64)>);
+
+    impl Fold for Constants {
+        fn fold_identifier(&mut self, name: String, info: ParseInfo) -> Expression {
+            match self.0.iter().find(|(n, _)| *n == name) {
+                Some((_, value)) => Expression::Float(*value, info),
+                None => Expression::Identifier(name, info),
+            }
+        }
+    }
+
+    /// The cells a formula names one at a time
+    struct Cells(Vec<String>);
+
+    impl Visitor for Cells {
+        fn visit_address(&mut self, addr: &Address, _info: &ParseInfo) {
+            self.0.push(addr.addr.clone())
+        }
+    }
+
+    let context = workbook();
+    let expr = whole_expr_str("(A1 + A3) * rate - IF(TRUE, B1, fee)").unwrap();
+    let folded = Constants(vec![("RATE", 0.5), ("FEE", 1.0)]).fold_expr(expr.clone());
+    assert_eq!(print(&folded), "(A1 + A3) * 0.5 - IF(TRUE, B1, 1.0)");
+    assert_eq!(evaluate(&folded, &context), Value::Float(-8.0));
+    assert_eq!(evaluate(&expr, &context), CellError::Name.into());
+
+    let mut cells = Cells(vec![]);
+    cells.visit_expr(&folded);
+    assert_eq!(cells.0, vec!["A1", "A3", "B1"]);
+}
+
+#[test]
+fn test_precedents_and_evaluate() {
+    use mesax::precedents::{precedents, Reference};
+
+    let expr = whole_expr_str("SUM(A1:A2) + Data!A1 / B1 + A3 * A3").unwrap();
+    let found = precedents(&expr);
+    let references: Vec<String> = found
+        .references
+        .iter()
+        .map(|r| match r {
+            Reference::Cell(addr) => print(&Expression::Address(addr.clone(), None)),
+            Reference::Range(range) => print(&Expression::Range(range.clone(), None)),
+        })
+        .collect();
+    assert_eq!(references, vec!["A1:A2", "Data!A1", "B1", "A3"]);
+    assert_eq!(found.sheets().len(), 1);
+
+    // the value only changes when a precedent does
+    let with = |a3: i128, c1: i128| {
+        let context = EvalContext::with_sheets(&[
+            (
+                "Sheet1",
+                &[
+                    ("A1", Value::Int(1)),
+                    ("A2", Value::Int(2)),
+                    ("A3", Value::Int(a3)),
+                    ("B1", Value::Int(10)),
+                    ("C1", Value::Int(c1)),
+                ],
+            ),
+            ("Data", &[("A1", Value::Int(100))]),
+        ])
+        .unwrap();
+        evaluate(&expr, &context)
+    };
+    assert_eq!(with(3, 0), evaluate(&expr, &workbook()));
+    assert_eq!(with(3, 99), with(3, 0));
+    assert_ne!(with(4, 0), with(3, 0));
+
+    let names = precedents(&whole_expr_str("let x = A3; x * rate + cats.dogs").unwrap());
+    assert_eq!(names.free_names, vec!["RATE", "CATS.DOGS"]);
+    assert_eq!(names.bound_names, vec!["X"]);
+}
+
+#[test]
+fn test_serialize_and_evaluate() {
+    use mesax::definitions::CellError;
+    use mesax::eval_stack::EvalStack;
+    use mesax::serial::{from_binary, from_json, to_binary, to_json};
+
+    let context = workbook();
+    let formula = "SUM(A1:A3) / B1 + {1, 2} * 0.5 /* half */";
+    let expr = whole_expr_str(formula).unwrap();
+
+    // a parsed formula is cached as JSON and read back later
+    let cached: Expression = from_json(&to_json(&expr).unwrap()).unwrap();
+    assert_eq!(cached, expr);
+    assert_eq!(print(&cached), "SUM(A1:A3) / B1 + {1, 2} * 0.5 /* half */");
+
+    // so are its instructions, in the binary form
+    let stack = create_eval_stack(&cached, &HashMap::new()).unwrap();
+    let stack: Vec<EvalStack> = from_binary(&to_binary(&stack).unwrap()).unwrap();
+    let value = eval_in(&stack, &context).unwrap();
+    assert_eq!(
+        value,
+        Value::Array(vec![vec![Value::Float(1.1), Value::Float(1.6)]])
+    );
+
+    // and the value, errors and all
+    for value in [value, CellError::DivZero.into()] {
+        let json: Value = from_json(&to_json(&value).unwrap()).unwrap();
+        let binary: Value = from_binary(&to_binary(&value).unwrap()).unwrap();
+        assert_eq!((&json, &binary), (&value, &value));
+    }
+}
+
+#[test]
+fn test_type_check_and_evaluate() {
+    use mesax::definitions::CellError;
+    use mesax::eval_stack::Types;
+    use mesax::typecheck::{check, check_str};
+
+    // what the checker infers is the type of the value
+    let context = workbook();
+    for (formula, expected) in [
+        ("1 + 2 * 3", Types::Int),
+        ("2.5 * 2", Types::Float),
+        ("\"total: \" & SUM(A1:A3)", Types::Str),
+        ("A1 < B1 && TRUE", Types::Bool),
+        ("A1:A3 + 1", Types::Array),
+        ("#REF! * 2", Types::Error),
+    ] {
+        let expr = whole_expr_str(formula).unwrap();
+        let checked = check(&expr);
+        assert_eq!(checked.result, expected, "Checking '{}'", formula);
+        assert!(checked.errors.is_empty());
+        assert_eq!(
+            Types::of(&evaluate(&expr, &context)),
+            expected,
+            "Evaluating '{}'",
+            formula
+        );
+    }
+
+    // a formula that doesn't check is an error value when it's evaluated
+    let formula = "1 + \"abc\" * 3";
+    assert_eq!(
+        check_str(formula).map_err(|e| e[0].to_string()),
+        Err("1:5: '*' can't be used with text".to_string())
+    );
+    let expr = whole_expr_str(formula).unwrap();
+    assert_eq!(evaluate(&expr, &context), CellError::Value.into());
+}
+
+#[test]
+fn test_lint_and_evaluate() {
+    use mesax::definitions::CellError;
+    use mesax::lint::{lint, lint_str, LintKind};
+    use mesax::rewrite::copy_formula;
+
+    let context = workbook();
+    let formula = "IF(1 > 0, A1 / 0, SUM($A1:A$3))";
+    let expr = whole_expr_str(formula).unwrap();
+    let kinds: Vec<(LintKind, &str)> = lint(&expr)
+        .iter()
+        .map(|w| (w.kind, &formula[w.start..w.end]))
+        .collect();
+    assert_eq!(
+        kinds,
+        vec![
+            (LintKind::ConstantCondition, "1 > 0"),
+            (LintKind::DivisionByZero, "A1 / 0"),
+            (LintKind::MixedAnchors, "$A1:A$3"),
+        ]
+    );
+    // the division fails, and copying the formula down shrinks the range
+    assert_eq!(evaluate(&expr, &context), CellError::DivZero.into());
+    let copied = copy_formula(&expr, &"C1".parse().unwrap(), &"C3".parse().unwrap());
+    assert_eq!(print(&copied), "IF(1 > 0, A3 / 0, SUM($A3:A$3))");
+
+    assert_eq!(
+        lint_str("NOSUCH(A1)")
+            .map(|found| found.iter().map(|d| d.to_string()).collect::<Vec<String>>()),
+        Ok(vec!["1:1: unknown function NOSUCH".to_string()])
+    );
+    assert_eq!(
+        evaluate(&whole_expr_str("NOSUCH(A1)").unwrap(), &context),
+        CellError::Name.into()
+    );
+}
+
+#[test]
+fn test_operand_order_and_promotion() {
+    use mesax::eval_stack::{BuilderParams, EvalStack};
+    use mesax::serial::{from_json, to_json};
+
+    let context = workbook();
+    let general: HashMap<String, BuilderParams> =
+        vec![("specialize".to_string(), BuilderParams::Choice(false))]
+            .into_iter()
+            .collect();
+    for (formula, expected) in [
+        ("10 - 4 - 3", Value::Int(3)),
+        ("A3 - A1 - B1", Value::Int(-8)),
+        ("B1 / A2", Value::Int(5)),
+        ("B1 / A2 / A3", Value::Float(5.0 / 3.0)),
+        ("2 ^ A3", Value::Int(8)),
+        ("A1 + 0.5", Value::Float(1.5)),
+        ("A2 * TRUE", Value::Int(2)),
+        (
+            "170141183460469231731687303715884105727 + A1",
+            Value::Float(1.7014118346046923e38),
+        ),
+    ] {
+        let expr = whole_expr_str(formula).unwrap();
+        let specialized = create_eval_stack(&expr, &HashMap::new()).unwrap();
+        let plain = create_eval_stack(&expr, &general).unwrap();
+        let cached: Vec<EvalStack> = from_json(&to_json(&specialized).unwrap()).unwrap();
+        for stack in [specialized, plain, cached] {
+            assert_eq!(
+                eval_in(&stack, &context),
+                Ok(expected.clone()),
+                "Evaluating '{}'",
+                formula
+            );
+        }
+    }
+
+    // integer arithmetic on integer literals is picked when building
+    let stack = create_eval_stack(&whole_expr_str("10 - 4 - 3").unwrap(), &HashMap::new()).unwrap();
+    assert!(stack.contains(&EvalStack::PerformIntOpr("-".into())));
+}
+
+#[test]
+fn test_error_values() {
+    use mesax::definitions::CellError;
+    use mesax::serial::{from_json, to_json};
+
+    // B2 holds #N/A
+    let context = EvalContext::with_sheets(&[(
+        "Sheet1",
+        &[("A1", Value::Int(1)), ("B2", CellError::NA.into())],
+    )])
+    .unwrap();
+    let run = |formula: &str| evaluate(&whole_expr_str(formula).unwrap(), &context);
+
+    for (formula, expected) in [
+        ("B2 + 1", CellError::NA),
+        ("A1 / 0", CellError::DivZero),
+        ("A1 / 0 + B2", CellError::DivZero),
+        ("B2 + A1 / 0", CellError::NA),
+        ("SUM(A1:B2)", CellError::NA),
+        ("-#NUM! & \"x\"", CellError::Num),
+        ("Nowhere!A1", CellError::Ref),
+        ("nothing", CellError::Name),
+    ] {
+        let value = run(formula);
+        assert_eq!(
+            value.cell_error(),
+            Some(expected),
+            "Evaluating '{}'",
+            formula
+        );
+        // an error keeps its code when it's saved
+        let saved: Value = from_json(&to_json(&value).unwrap()).unwrap();
+        assert_eq!(
+            saved,
+            Value::Error((expected.text().into(), expected.code()))
+        );
+    }
+
+    // an error written in a formula prints and parses as itself
+    for e in CellError::ALL {
+        let expr = whole_expr_str(e.text()).unwrap();
+        assert_eq!(print(&expr), e.text());
+        assert_eq!(run(e.text()), e.into());
+    }
+}
+
+#[test]
+fn test_build_and_eval_errors() {
+    use mesax::eval::EvalErrorKind;
+    use mesax::eval_stack::{create_eval_stack_mapped, BuildError, EvalStack};
+
+    let build = |expr: &Expression| create_eval_stack(expr, &HashMap::new());
+
+    // what can't be built says where the problem is
+    let formula = "SUM(A1, 2 +";
+    match build(&partial_expr_str(formula).expr) {
+        Err(e @ BuildError::Incomplete { .. }) => {
+            assert_eq!(
+                e.to_string(),
+                "the formula is incomplete: expected an expression"
+            );
+            assert_eq!(e.position().as_ref().map(|p| p.start), Some(formula.len()));
+        }
+        other => panic!("Expected an incomplete formula, got {:?}", other),
+    }
+    match build(&partial_expr_str("1 + ?").expr) {
+        Err(e @ BuildError::Unparsed { .. }) => assert_eq!(e.to_string(), "'?' couldn't be parsed"),
+        other => panic!("Expected text that couldn't be parsed, got {:?}", other),
+    }
+    assert!(matches!(
+        build(&whole_expr_str("let x = 1; x").unwrap()),
+        Err(BuildError::Unsupported { .. })
+    ));
+
+    // instructions that were damaged fail with where they came from
+    let formula = "A1 + 2 * 3";
+    let (mut stack, map) =
+        create_eval_stack_mapped(&whole_expr_str(formula).unwrap(), &HashMap::new()).unwrap();
+    let times = stack
+        .iter()
+        .position(|i| matches!(i, EvalStack::PerformOpr(opr) | EvalStack::PerformIntOpr(opr) if opr == "*"))
+        .unwrap();
+    // the 3 is replaced by an addition, which leaves `*` one value short
+    stack[times - 1] = EvalStack::PerformOpr("+".into());
+    let err = eval_in(&stack, &workbook()).unwrap_err();
+    assert!(matches!(
+        err.kind,
+        EvalErrorKind::StackUnderflow { needed: 2, .. }
+    ));
+    let (start, end) = err.position(&map).unwrap().span();
+    assert_eq!(&formula[start..end], "2 * 3");
+}
+
+#[test]
+fn test_builtin_functions() {
+    use mesax::definitions::CellError;
+    use mesax::eval_stack::{EvalStack, Types};
+    use mesax::functions::builtins;
+    use mesax::typecheck::check;
+
+    let context = workbook();
+    let formula = "ROUND(AVERAGE(A1:A3, B1) * 1.25, 1)";
+    let expr = whole_expr_str(formula).unwrap();
+    assert_eq!(check(&expr).result, Types::Float);
+    let stack = create_eval_stack(&expr, &HashMap::new()).unwrap();
+    let calls: Vec<(&str, usize)> = stack
+        .iter()
+        .filter_map(|i| match i {
+            EvalStack::CallFunction(name, arity, _) => Some((name.as_str(), *arity)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(calls, vec![("AVERAGE", 2), ("ROUND", 2)]);
+    assert_eq!(eval_in(&stack, &context).unwrap(), Value::Float(5.0));
+
+    // names don't depend on case and optional and variadic parameters work
+    for (formula, expected) in [
+        ("sum(A1:A3, Data!A1)", Value::Int(106)),
+        ("Round(2.5)", Value::Float(3.0)),
+        ("IF(MAX(A1:A3) > 2, ABS(-B1), 0)", Value::Int(10)),
+        ("AND(A1, NOT(FALSE), OR(FALSE, B1 > 5))", Value::Bool(true)),
+        ("COUNT(A1:A5)", Value::Int(3)),
+    ] {
+        let expr = whole_expr_str(formula).unwrap();
+        assert_eq!(
+            evaluate(&expr, &context),
+            expected,
+            "Evaluating '{}'",
+            formula
+        );
+        assert_eq!(print(&whole_expr_str(&print(&expr)).unwrap()), print(&expr));
+    }
+
+    // the wrong number of parameters is a type error and `#VALUE!`
+    let expr = whole_expr_str("ABS(1, 2)").unwrap();
+    assert_eq!(check(&expr).errors.len(), 1);
+    assert_eq!(evaluate(&expr, &context), CellError::Value.into());
+
+    // an editor can offer the functions as a formula is typed
+    let registry = builtins();
+    let offered: Vec<String> = registry
+        .complete("a")
+        .into_iter()
+        .map(|f| f.usage)
+        .collect();
+    assert_eq!(
+        offered,
+        vec!["ABS(number)", "AND(logical, ...)", "AVERAGE(number, ...)"]
+    );
+    assert!(!registry.is_volatile(&expr));
+}
+
+/// A unit conversion an application adds, named so a mismatched
+/// signature can be tried
+struct ToKm(&'static str);
+
+impl mesax::functions::FormulaFunction for ToKm {
+    fn name(&self) -> &str {
+        self.0
+    }
+
+    fn signature(&self) -> mesax::eval_stack::OperatorInfo {
+        use mesax::eval_stack::{OperatorInfo, ResultType, Types};
+        OperatorInfo::new(
+            "TO_KM",
+            1,
+            Some(1),
+            &[Types::NUMBERS],
+            ResultType::Is(Types::Float),
+        )
+    }
+
+    fn call(&self, params: &[Value], decorators: &[String]) -> Value {
+        let distance = match params[0] {
+            Value::Int(i) => i as f64,
+            Value::Float(f) => f,
+            _ => panic!("a distance has to be a number"),
+        };
+        match decorators.iter().any(|d| d.eq_ignore_ascii_case("MILES")) {
+            true => Value::Float(distance * 1.609344),
+            false => Value::Float(distance / 1000.0),
+        }
+    }
+
+    fn volatile(&self) -> bool {
+        true
+    }
+
+    fn decorators(&self) -> Vec<String> {
+        vec!["MILES".into()]
+    }
+
+    fn doc(&self) -> String {
+        "Converts metres, or miles, to kilometres".into()
+    }
+}
+
+#[test]
+fn test_custom_function_end_to_end() {
+    use mesax::definitions::CellError;
+    use mesax::eval_stack::Types;
+    use mesax::functions::RegistryError;
+    use mesax::lint::{lint_with, LintKind};
+    use mesax::typecheck::check_with;
+
+    let mut context = workbook();
+    context.register(ToKm("to_km")).unwrap();
+    assert_eq!(
+        context.register(ToKm("TO_MILES")),
+        Err(RegistryError::NameMismatch {
+            name: "TO_MILES".into(),
+            operator: "TO_KM".into()
+        })
+    );
+    assert_eq!(
+        context.register(ToKm("TO_MILES")).unwrap_err().to_string(),
+        "the function TO_MILES has the signature of TO_KM"
+    );
+
+    // it's type checked, linted and evaluated like a built-in function
+    let formula = "ROUND(TO_KM[MILES](B1), 2) + To_Km(A1 * 500)";
+    let expr = whole_expr_str(formula).unwrap();
+    let checked = check_with(&expr, &context.functions);
+    assert_eq!(checked.result, Types::Float);
+    assert!(checked.errors.is_empty());
+    assert!(lint_with(&expr, &context.functions).is_empty());
+    assert!(context.functions.is_volatile(&expr));
+    assert_eq!(evaluate(&expr, &context), Value::Float(16.59));
+    let printed = print(&expr);
+    assert_eq!(printed, "ROUND(TO_KM[MILES](B1), 2) + TO_KM(A1 * 500)");
+    assert_eq!(
+        evaluate(&whole_expr_str(&printed).unwrap(), &context),
+        Value::Float(16.59)
+    );
+
+    // the parameter types come from its signature
+    let expr = whole_expr_str("TO_KM(\"far\")").unwrap();
+    assert_eq!(check_with(&expr, &context.functions).errors.len(), 1);
+    // a panic in the function is `#VALUE!`
+    assert_eq!(evaluate(&expr, &context), CellError::Value.into());
+
+    // a decorator it doesn't have is a warning and `#NAME?`
+    let formula = "TO_KM[FEET](3)";
+    let expr = whole_expr_str(formula).unwrap();
+    let warnings = lint_with(&expr, &context.functions);
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(warnings[0].kind, LintKind::UnknownDecorator);
+    assert_eq!(evaluate(&expr, &context), CellError::Name.into());
+
+    // an editor offers it along with the built-in functions
+    let info = context.functions.info("to_km").unwrap();
+    assert_eq!(info.usage, "TO_KM(number)");
+    assert_eq!(info.decorators, vec!["MILES".to_string()]);
+    assert!(info.volatile);
+    assert_eq!(
+        context
+            .functions
+            .complete("t")
+            .iter()
+            .map(|f| f.doc.as_str())
+            .collect::<Vec<&str>>(),
+        vec!["Converts metres, or miles, to kilometres"]
+    );
+
+    // other contexts only have the built-in functions
+    let expr = whole_expr_str("TO_KM(1)").unwrap();
+    assert_eq!(evaluate(&expr, &workbook()), CellError::Name.into());
 }